@@ -0,0 +1,541 @@
+// 整数算术求值：$(( ))、(( )) 和 let 共用
+// 运算符优先级与 bash 一致，变量名可以不带 $
+
+use crate::state::{ReadonlyError, ShellState};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArithError {
+    #[error("division by 0")]
+    DivisionByZero,
+    #[error("exponent less than 0")]
+    NegativeExponent,
+    #[error("attempted assignment to non-variable")]
+    NotAVariable,
+    #[error("value too great for base (error token is \"{0}\")")]
+    InvalidNumber(String),
+    #[error("syntax error: {0}")]
+    Syntax(String),
+    #[error("expression recursion level exceeded")]
+    Recursion,
+    #[error("{0}")]
+    Readonly(#[from] ReadonlyError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ArithToken {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// 按长度从长到短排列，保证最长匹配
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "=", "!", "~", "&", "^",
+    "|", "?", ":", ",", "(", ")",
+];
+
+const ASSIGN_OPS: &[&str] = &[
+    "=", "+=", "-=", "*=", "/=", "%=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+#[derive(Debug)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    // ++x / --x，第二个字段是增量
+    PreIncrement(String, i64),
+    // x++ / x--
+    PostIncrement(String, i64),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+    // x = e / x += e，None 表示普通赋值
+    Assign(String, Option<&'static str>, Box<Expr>),
+    Comma(Box<Expr>, Box<Expr>),
+}
+
+fn tokenize(input: &str) -> Result<Vec<ArithToken>, ArithError> {
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() {
+            // 数字里可以出现 0x1f、16#ff 这样的写法
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '#' || c == '@' || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(ArithToken::Num(parse_number(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(ArithToken::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(ArithToken::Op(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(ArithError::Syntax(format!(
+                "invalid arithmetic operator (error token is \"{}\")",
+                rest
+            )));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// 十进制、0 开头的八进制、0x 开头的十六进制，以及 base#digits
+fn parse_number(s: &str) -> Result<i64, ArithError> {
+    let invalid = || ArithError::InvalidNumber(s.to_string());
+    let (base, digits) = if let Some((base, digits)) = s.split_once('#') {
+        let base: u32 = base.parse().map_err(|_| invalid())?;
+        if !(2..=64).contains(&base) || digits.is_empty() {
+            return Err(invalid());
+        }
+        (base, digits)
+    } else if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        (16, hex)
+    } else if s.len() > 1 && s.starts_with('0') {
+        (8, &s[1..])
+    } else {
+        (10, s)
+    };
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            // 进制不超过 36 时大小写不敏感
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+struct Parser {
+    tokens: Vec<ArithToken>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&ArithToken> {
+        self.tokens.get(self.pos)
+    }
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.peek() {
+            Some(ArithToken::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+    fn next(&mut self) -> Option<ArithToken> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+    fn expect(&mut self, op: &str) -> Result<(), ArithError> {
+        match self.next() {
+            Some(ArithToken::Op(o)) if o == op => Ok(()),
+            _ => Err(ArithError::Syntax(format!("`{}' expected", op))),
+        }
+    }
+
+    fn parse_comma(&mut self) -> Result<Expr, ArithError> {
+        let mut left = self.parse_assign()?;
+        while self.peek_op() == Some(",") {
+            self.next();
+            let right = self.parse_assign()?;
+            left = Expr::Comma(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_assign(&mut self) -> Result<Expr, ArithError> {
+        if let Some(ArithToken::Ident(name)) = self.peek().cloned()
+            && let Some(ArithToken::Op(op)) = self.tokens.get(self.pos + 1)
+            && ASSIGN_OPS.contains(op)
+        {
+            let op = *op;
+            self.pos += 2;
+            let value = self.parse_assign()?;
+            let compound = if op == "=" {
+                None
+            } else {
+                Some(&op[..op.len() - 1])
+            };
+            return Ok(Expr::Assign(name, compound, Box::new(value)));
+        }
+        let expr = self.parse_ternary()?;
+        // 左边不是变量名却出现了赋值运算符，比如 (a) = 1
+        if let Some(op) = self.peek_op()
+            && ASSIGN_OPS.contains(&op)
+        {
+            return Err(ArithError::NotAVariable);
+        }
+        Ok(expr)
+    }
+
+    fn parse_ternary(&mut self) -> Result<Expr, ArithError> {
+        let cond = self.parse_binary(0)?;
+        if self.peek_op() == Some("?") {
+            self.next();
+            let then = self.parse_assign()?;
+            self.expect(":")?;
+            let otherwise = self.parse_assign()?;
+            return Ok(Expr::Ternary(
+                Box::new(cond),
+                Box::new(then),
+                Box::new(otherwise),
+            ));
+        }
+        Ok(cond)
+    }
+
+    // 二元运算符的优先级，数字越大结合越紧
+    fn precedence(op: &str) -> Option<u8> {
+        match op {
+            "||" => Some(1),
+            "&&" => Some(2),
+            "|" => Some(3),
+            "^" => Some(4),
+            "&" => Some(5),
+            "==" | "!=" => Some(6),
+            "<" | ">" | "<=" | ">=" => Some(7),
+            "<<" | ">>" => Some(8),
+            "+" | "-" => Some(9),
+            "*" | "/" | "%" => Some(10),
+            "**" => Some(11),
+            _ => None,
+        }
+    }
+
+    fn parse_binary(&mut self, min_prec: u8) -> Result<Expr, ArithError> {
+        let mut left = self.parse_unary()?;
+        while let Some(op) = self.peek_op() {
+            let Some(prec) = Self::precedence(op) else {
+                break;
+            };
+            if prec < min_prec {
+                break;
+            }
+            self.next();
+            // ** 是右结合，其余都是左结合
+            let next_min = if op == "**" { prec } else { prec + 1 };
+            let right = self.parse_binary(next_min)?;
+            left = match op {
+                "&&" => Expr::And(Box::new(left), Box::new(right)),
+                "||" => Expr::Or(Box::new(left), Box::new(right)),
+                _ => Expr::Binary(op, Box::new(left), Box::new(right)),
+            };
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ArithError> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.next();
+                let delta = if op == "++" { 1 } else { -1 };
+                match self.peek().cloned() {
+                    Some(ArithToken::Ident(name)) => {
+                        self.next();
+                        Ok(Expr::PreIncrement(name, delta))
+                    }
+                    // --5 这种写法当成两次取负
+                    _ => {
+                        let sign = &op[..1];
+                        let inner = self.parse_unary()?;
+                        Ok(Expr::Unary(
+                            sign,
+                            Box::new(Expr::Unary(sign, Box::new(inner))),
+                        ))
+                    }
+                }
+            }
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.next();
+                let inner = self.parse_unary()?;
+                Ok(Expr::Unary(op, Box::new(inner)))
+            }
+            _ => self.parse_postfix(),
+        }
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr, ArithError> {
+        match self.next() {
+            Some(ArithToken::Num(n)) => Ok(Expr::Num(n)),
+            Some(ArithToken::Ident(name)) => match self.peek_op() {
+                Some("++") => {
+                    self.next();
+                    Ok(Expr::PostIncrement(name, 1))
+                }
+                Some("--") => {
+                    self.next();
+                    Ok(Expr::PostIncrement(name, -1))
+                }
+                _ => Ok(Expr::Var(name)),
+            },
+            Some(ArithToken::Op("(")) => {
+                let inner = self.parse_comma()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Some(ArithToken::Op(op)) => Err(ArithError::Syntax(format!(
+                "operand expected (error token is \"{}\")",
+                op
+            ))),
+            None => Err(ArithError::Syntax("operand expected".to_string())),
+        }
+    }
+}
+
+// 变量的值本身也可以是表达式，这里限制递归深度
+const MAX_DEPTH: usize = 64;
+
+struct Evaluator<'a> {
+    state: &'a mut ShellState,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn var_value(&mut self, name: &str) -> Result<i64, ArithError> {
+        let value = self.state.get_var(name).unwrap_or_default();
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(n) = value.parse::<i64>() {
+            return Ok(n);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(ArithError::Recursion);
+        }
+        let tokens = tokenize(value)?;
+        let expr = parse_tokens(tokens)?;
+        self.depth += 1;
+        let result = self.eval(&expr);
+        self.depth -= 1;
+        result
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> Result<i64, ArithError> {
+        self.state.set_var(name, &value.to_string())?;
+        Ok(value)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<i64, ArithError> {
+        match expr {
+            Expr::Num(n) => Ok(*n),
+            Expr::Var(name) => self.var_value(name),
+            Expr::Unary(op, inner) => {
+                let v = self.eval(inner)?;
+                Ok(match *op {
+                    "-" => v.wrapping_neg(),
+                    "!" => (v == 0) as i64,
+                    "~" => !v,
+                    _ => v,
+                })
+            }
+            Expr::PreIncrement(name, delta) => {
+                let v = self.var_value(name)?.wrapping_add(*delta);
+                self.assign(name, v)
+            }
+            Expr::PostIncrement(name, delta) => {
+                let v = self.var_value(name)?;
+                self.assign(name, v.wrapping_add(*delta))?;
+                Ok(v)
+            }
+            Expr::Binary(op, left, right) => {
+                let l = self.eval(left)?;
+                let r = self.eval(right)?;
+                apply_binary(op, l, r)
+            }
+            // && || 和 ?: 都要短路，没走到的分支不能产生副作用
+            Expr::And(left, right) => Ok((self.eval(left)? != 0 && self.eval(right)? != 0) as i64),
+            Expr::Or(left, right) => Ok((self.eval(left)? != 0 || self.eval(right)? != 0) as i64),
+            Expr::Ternary(cond, then, otherwise) => {
+                if self.eval(cond)? != 0 {
+                    self.eval(then)
+                } else {
+                    self.eval(otherwise)
+                }
+            }
+            Expr::Assign(name, op, value) => {
+                let r = self.eval(value)?;
+                let v = match op {
+                    Some(op) => apply_binary(op, self.var_value(name)?, r)?,
+                    None => r,
+                };
+                self.assign(name, v)
+            }
+            Expr::Comma(left, right) => {
+                self.eval(left)?;
+                self.eval(right)
+            }
+        }
+    }
+}
+
+fn apply_binary(op: &str, l: i64, r: i64) -> Result<i64, ArithError> {
+    Ok(match op {
+        "+" => l.wrapping_add(r),
+        "-" => l.wrapping_sub(r),
+        "*" => l.wrapping_mul(r),
+        "/" | "%" if r == 0 => return Err(ArithError::DivisionByZero),
+        "/" => l.wrapping_div(r),
+        "%" => l.wrapping_rem(r),
+        "**" => {
+            if r < 0 {
+                return Err(ArithError::NegativeExponent);
+            }
+            l.wrapping_pow(r.min(u32::MAX as i64) as u32)
+        }
+        "<<" => l.wrapping_shl(r as u32),
+        ">>" => l.wrapping_shr(r as u32),
+        "&" => l & r,
+        "^" => l ^ r,
+        "|" => l | r,
+        "==" => (l == r) as i64,
+        "!=" => (l != r) as i64,
+        "<" => (l < r) as i64,
+        ">" => (l > r) as i64,
+        "<=" => (l <= r) as i64,
+        ">=" => (l >= r) as i64,
+        _ => return Err(ArithError::Syntax(format!("unknown operator {}", op))),
+    })
+}
+
+fn parse_tokens(tokens: Vec<ArithToken>) -> Result<Expr, ArithError> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_comma()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(token) => {
+            let token = match token {
+                ArithToken::Num(n) => n.to_string(),
+                ArithToken::Ident(name) => name.clone(),
+                ArithToken::Op(op) => op.to_string(),
+            };
+            Err(ArithError::Syntax(format!(
+                "invalid arithmetic operator (error token is \"{}\")",
+                token
+            )))
+        }
+    }
+}
+
+// 对已经做过参数展开的表达式求值，空表达式的值是 0
+pub fn evaluate(input: &str, state: &mut ShellState) -> Result<i64, ArithError> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let expr = parse_tokens(tokens)?;
+    Evaluator { state, depth: 0 }.eval(&expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str) -> Result<i64, ArithError> {
+        evaluate(expr, &mut ShellState::new())
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), 7);
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), 9);
+        assert_eq!(eval("2 ** 3 ** 2").unwrap(), 512);
+        assert_eq!(eval("-2 ** 2").unwrap(), 4);
+        assert_eq!(eval("1 << 2 + 1").unwrap(), 8);
+        assert_eq!(eval("1 | 2 ^ 3 & 4").unwrap(), 3);
+        assert_eq!(eval("1 < 2 == 1").unwrap(), 1);
+        assert_eq!(eval("0 || 1 && 0").unwrap(), 0);
+        assert_eq!(eval("!0 + ~0").unwrap(), 0);
+        assert_eq!(eval("1 ? 2 : 0 ? 3 : 4").unwrap(), 2);
+        assert_eq!(eval("1, 2, 3").unwrap(), 3);
+        assert_eq!(eval("7 / 2 * 2 + 7 % 2").unwrap(), 7);
+        assert_eq!(eval("-7 / 2").unwrap(), -3);
+        assert_eq!(eval("").unwrap(), 0);
+    }
+
+    #[test]
+    fn assignments() {
+        let mut state = ShellState::new();
+        assert_eq!(evaluate("x = 5", &mut state).unwrap(), 5);
+        assert_eq!(evaluate("x += 2, x *= 3", &mut state).unwrap(), 21);
+        assert_eq!(evaluate("x <<= 1", &mut state).unwrap(), 42);
+        assert_eq!(evaluate("x %= 5", &mut state).unwrap(), 2);
+        assert_eq!(evaluate("x |= 8", &mut state).unwrap(), 10);
+        assert_eq!(evaluate("y = x++ + ++x", &mut state).unwrap(), 22);
+        assert_eq!(state.get_var("x").as_deref(), Some("12"));
+        assert_eq!(evaluate("x-- - --x", &mut state).unwrap(), 2);
+        // 赋值是右结合的
+        assert_eq!(evaluate("a = b = 3", &mut state).unwrap(), 3);
+        assert_eq!(state.get_var("a").as_deref(), Some("3"));
+        assert_eq!(state.get_var("b").as_deref(), Some("3"));
+        // 未设置的变量当作 0
+        assert_eq!(evaluate("unset_var + 1", &mut state).unwrap(), 1);
+        assert!(matches!(
+            evaluate("1 = 2", &mut state),
+            Err(ArithError::NotAVariable)
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(eval("1 / 0"), Err(ArithError::DivisionByZero)));
+        assert!(matches!(eval("1 % 0"), Err(ArithError::DivisionByZero)));
+        assert!(matches!(eval("2 ** -1"), Err(ArithError::NegativeExponent)));
+        assert!(matches!(eval("1 +"), Err(ArithError::Syntax(_))));
+        assert!(matches!(eval("1 2"), Err(ArithError::Syntax(_))));
+        assert!(matches!(eval("1 $ 2"), Err(ArithError::Syntax(_))));
+        assert_eq!(eval("1 / 0").unwrap_err().to_string(), "division by 0");
+    }
+
+    #[test]
+    fn overflow_wraps() {
+        assert_eq!(eval("9223372036854775807 + 1").unwrap(), i64::MIN);
+        assert_eq!(eval("-9223372036854775807 - 2").unwrap(), i64::MAX);
+        assert_eq!(eval("9223372036854775808").unwrap(), i64::MIN);
+        assert_eq!(eval("2 ** 64").unwrap(), 0);
+        assert_eq!(
+            eval("x = -9223372036854775807 - 1, x / -1").unwrap(),
+            i64::MIN
+        );
+        assert_eq!(eval("x = -9223372036854775807 - 1, x % -1").unwrap(), 0);
+    }
+
+    #[test]
+    fn number_bases() {
+        assert_eq!(eval("010").unwrap(), 8);
+        assert_eq!(eval("0x1F + 0X10").unwrap(), 47);
+        assert_eq!(eval("2#1010").unwrap(), 10);
+        assert_eq!(eval("16#ff").unwrap(), 255);
+        assert_eq!(eval("36#Z").unwrap(), 35);
+        // 进制大于 36 时区分大小写，@ 和 _ 是 62 和 63
+        assert_eq!(eval("64#a").unwrap(), 10);
+        assert_eq!(eval("64#A").unwrap(), 36);
+        assert_eq!(eval("64#@_").unwrap(), 62 * 64 + 63);
+        for bad in ["08", "2#2", "1#0", "65#1", "10#", "0xg"] {
+            assert!(
+                matches!(eval(bad), Err(ArithError::InvalidNumber(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+}
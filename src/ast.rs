@@ -0,0 +1,909 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Range;
+use std::rc::Rc;
+
+use crate::conditional::{is_binary_op, is_unary_op};
+use crate::lexer::{Args, Token, tokens_generate, word_generate};
+use crate::parser::{ParseError, is_valid_name};
+
+// 命令列表中管道之间的连接方式
+#[derive(Clone, Copy, PartialEq)]
+pub enum ListOp {
+    // ; 无条件执行
+    Seq,
+    // && 上一条成功才执行
+    And,
+    // || 上一条失败才执行
+    Or,
+}
+// a | b && c; d
+// 每条管道保留原始 token，执行到它时才展开，这样前面命令设置的变量和 $? 才能生效
+pub struct CommandList {
+    pub items: Vec<ListItem>,
+}
+pub struct ListItem {
+    pub op: ListOp,
+    pub pipeline: PipelineNode,
+    // 以 & 结尾，放到后台执行
+    pub background: bool,
+}
+pub struct PipelineNode {
+    pub stages: Vec<CommandNode>,
+    // 管道在输入里的原文，作为作业的名字
+    pub text: String,
+}
+pub enum CommandNode {
+    // 简单命令：单词和重定向
+    Simple(Vec<Token>),
+    // 复合命令和跟在它后面的重定向，比如 while ...; done < file
+    Compound(Rc<Compound>, Vec<Token>),
+    // 函数定义，执行到时才记到 shell 里
+    Function(Rc<FunctionDef>),
+}
+// name() { ...; } 或 function name { ...; }
+pub struct FunctionDef {
+    pub name: String,
+    pub body: Rc<Compound>,
+    // 定义时写在函数体后面的重定向，每次调用都生效
+    pub redirections: Vec<Token>,
+    // 定义的原文，type 显示函数体时用
+    pub text: String,
+}
+pub enum Compound {
+    // { list; }
+    Group(CommandList),
+    // if/elif 的每个分支是 (条件, 命令)，最后是 else
+    If {
+        branches: Vec<(CommandList, CommandList)>,
+        otherwise: Option<CommandList>,
+    },
+    // until 在条件失败时继续循环
+    While {
+        cond: CommandList,
+        body: CommandList,
+        until: bool,
+    },
+    // for name in words; 没有 in 时遍历位置参数
+    For {
+        var: String,
+        words: Option<Vec<Token>>,
+        body: CommandList,
+    },
+    // for ((init; cond; step))
+    ArithFor {
+        init: String,
+        cond: String,
+        step: String,
+        body: CommandList,
+    },
+    Case {
+        word: Vec<Args>,
+        arms: Vec<CaseArm>,
+    },
+    // [[ expr ]]
+    Cond(CondExpr),
+}
+// [[ ]] 里的条件表达式，单词执行时才展开，并且不做分词和文件名展开
+pub enum CondExpr {
+    // 单独一个字符串：非空为真
+    Word(Vec<Args>),
+    // -f file
+    Unary(String, Vec<Args>),
+    // a == pattern、a =~ regex、a -lt b
+    Binary(Vec<Args>, String, Vec<Args>),
+    Not(Box<CondExpr>),
+    And(Box<CondExpr>, Box<CondExpr>),
+    Or(Box<CondExpr>, Box<CondExpr>),
+}
+pub struct CaseArm {
+    // pat1 | pat2)
+    pub patterns: Vec<Vec<Args>>,
+    pub body: CommandList,
+    pub term: CaseTerm,
+}
+// case 分支的结尾
+#[derive(Clone, Copy, PartialEq)]
+pub enum CaseTerm {
+    // ;; 执行完就结束
+    Break,
+    // ;& 接着执行下一个分支，不再匹配
+    FallThrough,
+    // ;;& 继续匹配后面的分支
+    Continue,
+}
+
+// 保留字只有出现在命令开头、并且没有加引号时才算数
+pub const KEYWORDS: &[&str] = &[
+    "if", "then", "elif", "else", "fi", "while", "until", "for", "in", "do", "done", "case",
+    "esac", "{", "}", "function", "[[", "]]",
+];
+
+// 把 token 序列解析成命令列表，spans 是每个 token 在 input 里的位置
+// 简单命令的第一个单词在解析时做别名展开
+pub fn parse_program(
+    tokens: Vec<Token>,
+    spans: Vec<Range<usize>>,
+    input: &str,
+    aliases: &BTreeMap<String, String>,
+) -> Result<CommandList, ParseError> {
+    let len = tokens.len();
+    let mut parser = Parser {
+        tokens,
+        spans,
+        pos: 0,
+        input,
+        aliases,
+        origins: vec![Vec::new(); len],
+        alias_next: vec![false; len],
+    };
+    let list = parser.parse_list(&[])?;
+    // 顶层剩下的只能是多出来的 fi、)、;; 这类 token
+    if parser.pos < parser.tokens.len() {
+        return Err(parser.unexpected());
+    }
+    Ok(list)
+}
+
+// 递归下降：每种复合命令对应一个函数
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    spans: Vec<Range<usize>>,
+    pos: usize,
+    input: &'a str,
+    aliases: &'a BTreeMap<String, String>,
+    // 每个 token 是从哪些别名展开出来的，这些别名不再展开，避免 alias ls='ls -F' 无限递归
+    origins: Vec<Vec<String>>,
+    // 前一个别名的值以空格结尾时，紧跟着的单词也要做别名展开
+    alias_next: Vec<bool>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    // 下一个 token 是不带引号的单个单词时返回它，用来识别保留字
+    fn keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(args)) => match args.as_slice() {
+                [Args::Raw(s)] => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn op(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    // 当前 token 不该出现在这里；输入已经结束说明还需要下一行
+    fn unexpected(&self) -> ParseError {
+        match self.peek() {
+            Some(token) => {
+                ParseError::UnexpectedToken(token.to_string(), self.spans[self.pos].clone())
+            }
+            None => ParseError::UnexpectedEof(self.input.len()..self.input.len()),
+        }
+    }
+
+    fn expect_keyword(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.keyword() != Some(word) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // 换行被词法分析转成了 ;，在 then、do、in 这些保留字后面可以直接跳过
+    fn skip_newlines(&mut self) {
+        while self.op() == Some(";") {
+            self.pos += 1;
+        }
+    }
+
+    // 命令列表在遇到结束它的保留字 (比如 fi、done) 或 case 分支的结尾时停下
+    fn at_list_end(&self, terminators: &[&str]) -> bool {
+        match self.peek() {
+            None => true,
+            Some(Token::Op(op)) => matches!(op.as_str(), ")" | ";;" | ";&" | ";;&"),
+            Some(_) => self.keyword().is_some_and(|k| terminators.contains(&k)),
+        }
+    }
+
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<CommandList, ParseError> {
+        let nested = !terminators.is_empty();
+        let mut items = Vec::new();
+        let mut op = ListOp::Seq;
+        loop {
+            // && || 后面必须还有命令，不检查结束
+            if op == ListOp::Seq {
+                if nested {
+                    self.skip_newlines();
+                }
+                if self.at_list_end(terminators) {
+                    break;
+                }
+            }
+            let pipeline = self.parse_pipeline()?;
+            let (next_op, background) = match self.op() {
+                Some(";") => (ListOp::Seq, false),
+                Some("&") => (ListOp::Seq, true),
+                Some("&&") => (ListOp::And, false),
+                Some("||") => (ListOp::Or, false),
+                // 后面不是分隔符，交给调用者判断是不是合法的结尾
+                _ => {
+                    items.push(ListItem {
+                        op,
+                        pipeline,
+                        background: false,
+                    });
+                    break;
+                }
+            };
+            self.pos += 1;
+            items.push(ListItem {
+                op,
+                pipeline,
+                background,
+            });
+            op = next_op;
+        }
+        Ok(CommandList { items })
+    }
+
+    // if、while 这些地方的命令列表不能是空的
+    fn parse_body(&mut self, terminators: &[&str]) -> Result<CommandList, ParseError> {
+        let list = self.parse_list(terminators)?;
+        if list.items.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(list)
+    }
+
+    fn parse_pipeline(&mut self) -> Result<PipelineNode, ParseError> {
+        let start = self.pos;
+        let mut stages = vec![self.parse_command()?];
+        while self.op() == Some("|") {
+            self.pos += 1;
+            stages.push(self.parse_command()?);
+        }
+        let text = self.input[self.spans[start].start..self.spans[self.pos - 1].end].to_string();
+        Ok(PipelineNode { stages, text })
+    }
+
+    // 把当前位置的别名换成它的值，换完之后的第一个单词还可能是别名
+    fn expand_alias(&mut self) {
+        loop {
+            let Some(name) = self.keyword().map(str::to_string) else {
+                return;
+            };
+            if KEYWORDS.contains(&name.as_str()) || self.origins[self.pos].contains(&name) {
+                return;
+            }
+            let Some(value) = self.aliases.get(&name) else {
+                return;
+            };
+            let Ok((tokens, _)) = tokens_generate(value) else {
+                return;
+            };
+            let n = tokens.len();
+            let span = self.spans[self.pos].clone();
+            let mut origin = self.origins[self.pos].clone();
+            origin.push(name);
+            let range = self.pos..self.pos + 1;
+            self.tokens.splice(range.clone(), tokens);
+            self.spans.splice(range.clone(), vec![span; n]);
+            self.origins.splice(range.clone(), vec![origin; n]);
+            self.alias_next.splice(range, vec![false; n]);
+            if value.ends_with([' ', '\t']) && self.pos + n < self.tokens.len() {
+                self.alias_next[self.pos + n] = true;
+            }
+        }
+    }
+
+    fn parse_command(&mut self) -> Result<CommandNode, ParseError> {
+        self.expand_alias();
+        let compound = match self.keyword() {
+            Some("if") => self.parse_if()?,
+            Some("while") => self.parse_while(false)?,
+            Some("until") => self.parse_while(true)?,
+            Some("for") => self.parse_for()?,
+            Some("case") => self.parse_case()?,
+            Some("[[") => self.parse_cond()?,
+            Some("{") => {
+                self.pos += 1;
+                let body = self.parse_body(&["}"])?;
+                self.expect_keyword("}")?;
+                Compound::Group(body)
+            }
+            Some("function") => return self.parse_function(true),
+            Some(k) if KEYWORDS.contains(&k) => return Err(self.unexpected()),
+            // name ( ) 开头的是函数定义
+            Some(_) if self.is_function_start() => return self.parse_function(false),
+            _ => return self.parse_simple(),
+        };
+        let mut redirections = Vec::new();
+        while matches!(self.op(), Some(op) if is_redirect(op)) {
+            self.parse_redirect(&mut redirections)?;
+        }
+        Ok(CommandNode::Compound(Rc::new(compound), redirections))
+    }
+
+    fn is_function_start(&self) -> bool {
+        let op = |i: usize, s: &str| matches!(self.tokens.get(self.pos + i), Some(Token::Op(op)) if op == s);
+        op(1, "(") && op(2, ")")
+    }
+
+    // name () compound 或 function name [()] compound，函数体必须是复合命令
+    fn parse_function(&mut self, keyword: bool) -> Result<CommandNode, ParseError> {
+        let start = self.pos;
+        if keyword {
+            self.pos += 1;
+        }
+        let name = match self.keyword() {
+            Some(name) if !KEYWORDS.contains(&name) => name.to_string(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        if !keyword || self.op() == Some("(") {
+            self.pos += 1;
+            if self.op() != Some(")") {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+        }
+        self.skip_newlines();
+        if !matches!(
+            self.keyword(),
+            Some("{" | "if" | "while" | "until" | "for" | "case")
+        ) {
+            return Err(self.unexpected());
+        }
+        let CommandNode::Compound(body, redirections) = self.parse_command()? else {
+            unreachable!()
+        };
+        let text = self.input[self.spans[start].start..self.spans[self.pos - 1].end].to_string();
+        Ok(CommandNode::Function(Rc::new(FunctionDef {
+            name,
+            body,
+            redirections,
+            text,
+        })))
+    }
+
+    fn parse_simple(&mut self) -> Result<CommandNode, ParseError> {
+        let mut tokens = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Word(_) | Token::Arith(_) | Token::HereDoc(_)) => {
+                    if self.alias_next[self.pos] {
+                        self.alias_next[self.pos] = false;
+                        self.expand_alias();
+                        continue;
+                    }
+                    tokens.extend(self.next());
+                }
+                Some(Token::Op(op)) if is_redirect(op) => self.parse_redirect(&mut tokens)?,
+                _ => break,
+            }
+        }
+        if tokens.is_empty() {
+            return Err(self.unexpected());
+        }
+        Ok(CommandNode::Simple(tokens))
+    }
+
+    // 重定向后面必须跟着文件名或者 here-document
+    fn parse_redirect(&mut self, tokens: &mut Vec<Token>) -> Result<(), ParseError> {
+        tokens.extend(self.next());
+        match self.peek() {
+            Some(Token::Word(_) | Token::HereDoc(_)) => tokens.extend(self.next()),
+            Some(_) => return Err(self.unexpected()),
+            None => {
+                let len = self.input.len();
+                return Err(ParseError::UnexpectedToken("newline".to_string(), len..len));
+            }
+        }
+        Ok(())
+    }
+
+    // if list; then list; [elif list; then list;]... [else list;] fi
+    fn parse_if(&mut self) -> Result<Compound, ParseError> {
+        self.pos += 1;
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        loop {
+            let cond = self.parse_body(&["then"])?;
+            self.expect_keyword("then")?;
+            let body = self.parse_body(&["elif", "else", "fi"])?;
+            branches.push((cond, body));
+            match self.keyword() {
+                Some("elif") => self.pos += 1,
+                Some("else") => {
+                    self.pos += 1;
+                    otherwise = Some(self.parse_body(&["fi"])?);
+                    self.expect_keyword("fi")?;
+                    break;
+                }
+                Some("fi") => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return Err(self.unexpected()),
+            }
+        }
+        Ok(Compound::If {
+            branches,
+            otherwise,
+        })
+    }
+
+    // while/until list; do list; done
+    fn parse_while(&mut self, until: bool) -> Result<Compound, ParseError> {
+        self.pos += 1;
+        let cond = self.parse_body(&["do"])?;
+        self.expect_keyword("do")?;
+        let body = self.parse_body(&["done"])?;
+        self.expect_keyword("done")?;
+        Ok(Compound::While { cond, body, until })
+    }
+
+    // for name [in words]; do list; done 或者 for ((init; cond; step)); do list; done
+    fn parse_for(&mut self) -> Result<Compound, ParseError> {
+        self.pos += 1;
+        if let Some(Token::Arith(expr)) = self.peek().cloned() {
+            let parts: Vec<&str> = expr.split(';').collect();
+            let [init, cond, step] = parts.as_slice() else {
+                return Err(self.unexpected());
+            };
+            let (init, cond, step) = (init.to_string(), cond.to_string(), step.to_string());
+            self.pos += 1;
+            self.skip_newlines();
+            let body = self.parse_loop_body()?;
+            return Ok(Compound::ArithFor {
+                init,
+                cond,
+                step,
+                body,
+            });
+        }
+        let var = match self.keyword() {
+            Some(name) if is_valid_name(name) => name.to_string(),
+            _ => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+        let mut words = None;
+        if self.op() == Some(";") {
+            self.skip_newlines();
+        } else if self.keyword() == Some("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(Token::Word(_)) => list.extend(self.next()),
+                    Some(Token::Op(op)) if op == ";" => break,
+                    _ => return Err(self.unexpected()),
+                }
+            }
+            self.skip_newlines();
+            words = Some(list);
+        }
+        let body = self.parse_loop_body()?;
+        Ok(Compound::For { var, words, body })
+    }
+
+    fn parse_loop_body(&mut self) -> Result<CommandList, ParseError> {
+        self.expect_keyword("do")?;
+        let body = self.parse_body(&["done"])?;
+        self.expect_keyword("done")?;
+        Ok(body)
+    }
+
+    // [[ expr ]]：&& 和 || 连接条件，( ) 分组，< > 是比较而不是重定向
+    fn parse_cond(&mut self) -> Result<Compound, ParseError> {
+        self.pos += 1;
+        let expr = self.parse_cond_or()?;
+        self.skip_cond_newlines();
+        self.expect_keyword("]]")?;
+        Ok(Compound::Cond(expr))
+    }
+
+    // [[ ]] 里可以换行，只跳过由换行转成的 ;，写出来的 ; 仍然是语法错误
+    fn skip_cond_newlines(&mut self) {
+        while self.op() == Some(";") && self.input.get(self.spans[self.pos].clone()) == Some("\n") {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_cond_or(&mut self) -> Result<CondExpr, ParseError> {
+        let mut expr = self.parse_cond_and()?;
+        self.skip_cond_newlines();
+        while self.op() == Some("||") {
+            self.pos += 1;
+            let right = self.parse_cond_and()?;
+            expr = CondExpr::Or(Box::new(expr), Box::new(right));
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_and(&mut self) -> Result<CondExpr, ParseError> {
+        let mut expr = self.parse_cond_not()?;
+        self.skip_cond_newlines();
+        while self.op() == Some("&&") {
+            self.pos += 1;
+            let right = self.parse_cond_not()?;
+            expr = CondExpr::And(Box::new(expr), Box::new(right));
+            self.skip_cond_newlines();
+        }
+        Ok(expr)
+    }
+
+    fn parse_cond_not(&mut self) -> Result<CondExpr, ParseError> {
+        self.skip_cond_newlines();
+        if self.keyword() == Some("!") {
+            self.pos += 1;
+            return Ok(CondExpr::Not(Box::new(self.parse_cond_not()?)));
+        }
+        self.parse_cond_primary()
+    }
+
+    fn parse_cond_primary(&mut self) -> Result<CondExpr, ParseError> {
+        if self.op() == Some("(") {
+            self.pos += 1;
+            let expr = self.parse_cond_or()?;
+            if self.op() != Some(")") {
+                return Err(self.unexpected());
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+        let word = self.parse_cond_word()?;
+        let next_op = match self.peek() {
+            Some(Token::Op(op)) if op == "<" || op == ">" => Some(op.clone()),
+            Some(Token::Word(args)) => match args.as_slice() {
+                [Args::Raw(op)] if is_binary_op(op) || op == "=~" => Some(op.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(op) = next_op {
+            self.pos += 1;
+            let right = if op == "=~" {
+                self.parse_cond_regex()?
+            } else {
+                self.parse_cond_word()?
+            };
+            return Ok(CondExpr::Binary(word, op, right));
+        }
+        // -f ]] 这种后面没有操作数的，当作普通字符串
+        if let [Args::Raw(op)] = word.as_slice()
+            && is_unary_op(op)
+            && self.keyword() != Some("]]")
+            && matches!(self.peek(), Some(Token::Word(_)))
+        {
+            return Ok(CondExpr::Unary(op.clone(), self.parse_cond_word()?));
+        }
+        Ok(CondExpr::Word(word))
+    }
+
+    fn parse_cond_word(&mut self) -> Result<Vec<Args>, ParseError> {
+        match self.peek() {
+            Some(Token::Word(args)) if self.keyword() != Some("]]") => {
+                let args = args.clone();
+                self.pos += 1;
+                Ok(args)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    // =~ 右边的正则里可以有不加引号的 ( ) |，词法分析会把它们拆开
+    // 所以把紧挨着的 token 按原文拼回一个单词
+    fn parse_cond_regex(&mut self) -> Result<Vec<Args>, ParseError> {
+        if self.keyword() == Some("]]")
+            || !matches!(self.peek(), Some(Token::Word(_) | Token::Op(_)))
+        {
+            return Err(self.unexpected());
+        }
+        let start = self.spans[self.pos].start;
+        let mut end = self.spans[self.pos].end;
+        self.pos += 1;
+        while let Some(span) = self.spans.get(self.pos)
+            && span.start == end
+            && !matches!(self.op(), Some("&&" | "||"))
+        {
+            end = span.end;
+            self.pos += 1;
+        }
+        Ok(word_generate(&self.input[start..end]))
+    }
+
+    // case word in [(]pat [| pat]...) list ;; ... esac
+    fn parse_case(&mut self) -> Result<Compound, ParseError> {
+        self.pos += 1;
+        let word = match self.next() {
+            Some(Token::Word(args)) => args,
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected());
+            }
+        };
+        self.skip_newlines();
+        self.expect_keyword("in")?;
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.keyword() == Some("esac") {
+                self.pos += 1;
+                break;
+            }
+            if self.op() == Some("(") {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.peek() {
+                    Some(Token::Word(args)) => {
+                        patterns.push(args.clone());
+                        self.pos += 1;
+                    }
+                    _ => return Err(self.unexpected()),
+                }
+                match self.op() {
+                    Some("|") => self.pos += 1,
+                    Some(")") => {
+                        self.pos += 1;
+                        break;
+                    }
+                    _ => return Err(self.unexpected()),
+                }
+            }
+            // 分支的命令可以是空的
+            let body = self.parse_list(&["esac"])?;
+            let term = match self.op() {
+                Some(";;") => CaseTerm::Break,
+                Some(";&") => CaseTerm::FallThrough,
+                Some(";;&") => CaseTerm::Continue,
+                // 最后一个分支可以省略 ;;
+                _ if self.keyword() == Some("esac") => {
+                    arms.push(CaseArm {
+                        patterns,
+                        body,
+                        term: CaseTerm::Break,
+                    });
+                    continue;
+                }
+                _ => return Err(self.unexpected()),
+            };
+            self.pos += 1;
+            arms.push(CaseArm {
+                patterns,
+                body,
+                term,
+            });
+        }
+        Ok(Compound::Case { word, arms })
+    }
+}
+
+// 逐行记下还没结束的复合命令，读一行只看这一行的 token，不用每次都重新解析整段输入
+// 只在和解析器相同的位置上识别保留字；结束的保留字对不上时交给解析器报错
+pub struct Nesting {
+    open: Vec<Open>,
+    // 下一个单词在命令开头，可能是保留字
+    command_start: bool,
+    // 下一个单词是 function 后面的函数名
+    function_name: bool,
+    // 当前的命令列表里还没有命令，if、while 这些地方的列表不能是空的
+    empty: bool,
+    // 最后是 | && ||，后面还要跟命令
+    continued: bool,
+    // 肯定是语法错误，不用再等下一行
+    mismatched: bool,
+}
+enum Open {
+    // 等待 fi、done、} 或者 ]]
+    Keyword(&'static str),
+    // case 还没读到 in
+    CaseWord,
+    // case 分支的模式，到 ) 为止
+    CasePattern,
+    // case 分支的命令，到 ;; 或 esac 为止
+    CaseBody,
+}
+
+impl Nesting {
+    pub fn new() -> Self {
+        Nesting {
+            open: Vec::new(),
+            command_start: true,
+            function_name: false,
+            empty: false,
+            continued: false,
+            mismatched: false,
+        }
+    }
+
+    // 还有复合命令没结束，或者最后的操作符后面还要跟命令
+    pub fn is_open(&self) -> bool {
+        !self.mismatched && (!self.open.is_empty() || self.continued)
+    }
+
+    // 一整行的 token，行尾的换行和 ; 一样分隔命令
+    pub fn push_line(&mut self, tokens: &[Token]) {
+        for token in tokens {
+            self.push(token);
+        }
+        self.command_start = true;
+    }
+
+    fn push(&mut self, token: &Token) {
+        let word = match token {
+            Token::Word(args) => match args.as_slice() {
+                [Args::Raw(s)] => Some(s.as_str()),
+                _ => None,
+            },
+            _ => None,
+        };
+        match self.open.last_mut() {
+            // [[ ]] 里只找 ]]
+            Some(Open::Keyword("]]")) => {
+                if word == Some("]]") {
+                    self.open.pop();
+                    self.command_start = false;
+                }
+                return;
+            }
+            Some(last @ Open::CaseWord) => {
+                if word == Some("in") {
+                    *last = Open::CasePattern;
+                }
+                return;
+            }
+            Some(last @ Open::CasePattern) => {
+                if word == Some("esac") {
+                    self.open.pop();
+                    self.command_start = false;
+                } else if matches!(token, Token::Op(op) if op == ")") {
+                    *last = Open::CaseBody;
+                    self.command_start = true;
+                    self.empty = false;
+                }
+                return;
+            }
+            _ => {}
+        }
+        let Token::Op(op) = token else {
+            self.continued = false;
+            if self.function_name {
+                self.function_name = false;
+                self.command_start = true;
+                return;
+            }
+            let keyword = if self.command_start { word } else { None };
+            self.command_start = false;
+            let empty = mem::replace(&mut self.empty, false);
+            match keyword {
+                Some("if") => self.enter("fi", true),
+                Some("while" | "until") => self.enter("done", true),
+                Some("for") => self.enter("done", false),
+                Some("{") => self.enter("}", true),
+                Some("[[") => self.enter("]]", false),
+                Some("case") => self.open.push(Open::CaseWord),
+                Some("function") => self.function_name = true,
+                // 前面的列表是空的，或者不在对应的复合命令里
+                Some("then" | "else" | "elif" | "do") if empty => self.mismatched = true,
+                Some(k @ ("then" | "else" | "elif" | "do")) => {
+                    let close = if k == "do" { "done" } else { "fi" };
+                    if !matches!(self.open.last(), Some(Open::Keyword(c)) if *c == close) {
+                        self.mismatched = true;
+                    }
+                    self.command_start = true;
+                    self.empty = true;
+                }
+                Some("esac") if matches!(self.open.last(), Some(Open::CaseBody)) => {
+                    self.open.pop();
+                }
+                Some(k @ ("fi" | "done" | "}" | "esac")) => {
+                    if !empty
+                        && matches!(self.open.last(), Some(Open::Keyword(close)) if *close == k)
+                    {
+                        self.open.pop();
+                    } else {
+                        self.mismatched = true;
+                    }
+                }
+                _ => {}
+            }
+            return;
+        };
+        self.continued = matches!(op.as_str(), "|" | "&&" | "||");
+        match op.as_str() {
+            ";;" | ";&" | ";;&" => match self.open.last_mut() {
+                Some(last @ Open::CaseBody) => *last = Open::CasePattern,
+                _ => self.mismatched = true,
+            },
+            // 重定向后面是文件名
+            op if is_redirect(op) => self.command_start = false,
+            // 其它操作符 (包括 name() 的括号) 后面都是新的命令
+            _ => self.command_start = true,
+        }
+    }
+
+    fn enter(&mut self, close: &'static str, list: bool) {
+        self.open.push(Open::Keyword(close));
+        self.command_start = list;
+        self.empty = list;
+    }
+}
+
+// 重定向操作符：可选的 fd 数字加上 < 或 > 开头的部分，或者 &> &>>
+fn is_redirect(op: &str) -> bool {
+    let rest = op.trim_start_matches(|c: char| c.is_ascii_digit());
+    rest.starts_with('<') || rest.starts_with('>') || rest.starts_with("&>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> ParseError {
+        let (tokens, spans) = tokens_generate(input).unwrap();
+        match parse_program(tokens, spans, input, &BTreeMap::new()) {
+            Ok(_) => panic!("{:?} should not parse", input),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn unexpected_token_spans() {
+        let err = error("echo > |");
+        assert_eq!(err.to_string(), "syntax error near unexpected token `|'");
+        assert_eq!(err.span(), &(7..8));
+        assert!(!err.is_incomplete());
+        assert_eq!(err.caret("echo > |"), "echo > |\n       ^");
+
+        let err = error("if true; then echo; fi fi");
+        assert_eq!(err.span(), &(23..25));
+        assert_eq!(
+            err.caret("if true; then echo; fi fi"),
+            "if true; then echo; fi fi\n                       ^^"
+        );
+        // 换行后面的错误只显示它所在的那一行
+        let input = "echo a\n\tdone";
+        let err = error(input);
+        assert_eq!(err.span(), &(8..12));
+        assert_eq!(err.caret(input), "\tdone\n\t^^^^");
+    }
+
+    #[test]
+    fn unexpected_eof() {
+        for input in ["if true; then", "a &&", "while true\ndo", "case x in a)"] {
+            let err = error(input);
+            assert!(err.is_incomplete(), "{}", input);
+            assert_eq!(err.span(), &(input.len()..input.len()));
+        }
+        assert_eq!(
+            error("a |").to_string(),
+            "syntax error: unexpected end of file"
+        );
+        // 重定向后面缺文件名不是未完成的输入
+        let err = error("echo >");
+        assert_eq!(
+            err.to_string(),
+            "syntax error near unexpected token `newline'"
+        );
+        assert!(!err.is_incomplete());
+    }
+}
@@ -8,7 +8,11 @@ pub fn collect_all_commands() -> Trie {
     let mut commands = Trie::new();
 
     // 1. 加入内置命令
-    let builtins = vec!["echo", "exit", "type", "history", "pwd", "cd"];
+    let builtins = vec![
+        "echo", "exit", "type", "history", "pwd", "cd", "let", "shopt", "jobs", "fg", "bg", "wait",
+        "exec", "set", "source", "break", "continue", "local", "return", "alias", "unalias",
+        "export", "unset", "readonly", "test",
+    ];
     for b in builtins {
         commands.insert(b);
     }
@@ -18,7 +22,7 @@ pub fn collect_all_commands() -> Trie {
         env::split_paths(&paths)
             .flat_map(|dir| fs::read_dir(dir).into_iter().flatten())
             .flatten() // 解包 Result<DirEntry>
-            .filter(|entry| is_entry_executable(entry))
+            .filter(is_entry_executable)
             // 最后才转换文件名，这是无法避免的分配，但只有合法的可执行文件才会走到这一步
             .for_each(|entry| {
                 let os_name = entry.file_name();
@@ -0,0 +1,279 @@
+// 花括号展开：a{b,c}d -> abd acd，{1..10..2}，{a..e}
+// 只作用于未加引号的字符，在其它所有展开之前进行
+
+use crate::lexer::Args;
+
+// 单词拆成一个个片段：Raw 里的字符可以参与花括号语法，其它片段原样保留
+#[derive(Clone)]
+enum Piece {
+    Char(char),
+    Other(Args),
+}
+
+enum BraceBody {
+    // {a,b,c}：每个选项还可能包含嵌套的花括号
+    Alternatives(Vec<Vec<Piece>>),
+    // {1..5} / {a..e}
+    Sequence(Vec<String>),
+}
+
+pub fn brace_expand(word: Vec<Args>) -> Vec<Vec<Args>> {
+    let pieces: Vec<Piece> = word
+        .into_iter()
+        .flat_map(|arg| match arg {
+            Args::Raw(s) => s.chars().map(Piece::Char).collect::<Vec<_>>(),
+            other => vec![Piece::Other(other)],
+        })
+        .collect();
+    expand_pieces(pieces)
+        .into_iter()
+        .map(collect_args)
+        .collect()
+}
+
+// 把连续的字符重新合并成 Raw
+fn collect_args(pieces: Vec<Piece>) -> Vec<Args> {
+    let mut args = Vec::new();
+    let mut raw = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Char(c) => raw.push(c),
+            Piece::Other(arg) => {
+                if !raw.is_empty() {
+                    args.push(Args::Raw(std::mem::take(&mut raw)));
+                }
+                args.push(arg);
+            }
+        }
+    }
+    if !raw.is_empty() {
+        args.push(Args::Raw(raw));
+    }
+    args
+}
+
+fn expand_pieces(pieces: Vec<Piece>) -> Vec<Vec<Piece>> {
+    let Some((start, end, body)) = find_brace(&pieces) else {
+        return vec![pieces];
+    };
+    let prefix = &pieces[..start];
+    let suffix = &pieces[end + 1..];
+    let alternatives = match body {
+        BraceBody::Alternatives(alts) => alts,
+        BraceBody::Sequence(items) => items
+            .into_iter()
+            .map(|s| s.chars().map(Piece::Char).collect())
+            .collect(),
+    };
+    let mut result = Vec::new();
+    for alt in alternatives {
+        let mut combined = prefix.to_vec();
+        combined.extend(alt);
+        combined.extend_from_slice(suffix);
+        // 选项里和后缀里可能还有花括号，继续展开
+        result.extend(expand_pieces(combined));
+    }
+    result
+}
+
+// 找到第一个合法的花括号表达式，返回 ({ 的位置, } 的位置, 内容)
+// 像 {a} 这样既没有逗号也不是序列的花括号保持原样
+fn find_brace(pieces: &[Piece]) -> Option<(usize, usize, BraceBody)> {
+    for start in 0..pieces.len() {
+        if !matches!(pieces[start], Piece::Char('{')) {
+            continue;
+        }
+        let mut depth = 0;
+        let mut commas = Vec::new();
+        let mut end = None;
+        for (i, piece) in pieces.iter().enumerate().skip(start + 1) {
+            match piece {
+                Piece::Char('{') => depth += 1,
+                Piece::Char('}') if depth == 0 => {
+                    end = Some(i);
+                    break;
+                }
+                Piece::Char('}') => depth -= 1,
+                Piece::Char(',') if depth == 0 => commas.push(i),
+                _ => {}
+            }
+        }
+        let Some(end) = end else {
+            continue;
+        };
+        if !commas.is_empty() {
+            let mut alts = Vec::new();
+            let mut from = start + 1;
+            for comma in commas.into_iter().chain(std::iter::once(end)) {
+                alts.push(pieces[from..comma].to_vec());
+                from = comma + 1;
+            }
+            return Some((start, end, BraceBody::Alternatives(alts)));
+        }
+        let inner: Option<String> = pieces[start + 1..end]
+            .iter()
+            .map(|p| match p {
+                Piece::Char(c) => Some(*c),
+                Piece::Other(_) => None,
+            })
+            .collect();
+        if let Some(items) = inner.and_then(|s| parse_sequence(&s)) {
+            return Some((start, end, BraceBody::Sequence(items)));
+        }
+    }
+    None
+}
+
+// 解析 x..y[..step]
+fn parse_sequence(s: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = s.split("..").collect();
+    let (from, to, step) = match parts.as_slice() {
+        [from, to] => (*from, *to, None),
+        [from, to, step] => (*from, *to, Some(step.parse::<i64>().ok()?)),
+        _ => return None,
+    };
+    // 步长的符号不重要，方向由起止决定；步长为 0 时按 1 处理
+    let step = step.map_or(1, |s: i64| s.unsigned_abs().max(1));
+
+    if let (Ok(a), Ok(b)) = (from.parse::<i64>(), to.parse::<i64>()) {
+        // 任意一端带前导 0 时，按较长的那个宽度补零
+        let has_padding = |s: &str| {
+            let digits = s.trim_start_matches('-');
+            digits.len() > 1 && digits.starts_with('0')
+        };
+        let width = if has_padding(from) || has_padding(to) {
+            from.len().max(to.len())
+        } else {
+            0
+        };
+        return Some(
+            range(a, b, step)?
+                .map(|n| {
+                    if n < 0 {
+                        format!("-{:0w$}", n.unsigned_abs(), w = width.saturating_sub(1))
+                    } else {
+                        format!("{:0w$}", n, w = width)
+                    }
+                })
+                .collect(),
+        );
+    }
+
+    let mut from_chars = from.chars();
+    let mut to_chars = to.chars();
+    match (
+        from_chars.next(),
+        from_chars.next(),
+        to_chars.next(),
+        to_chars.next(),
+    ) {
+        (Some(a), None, Some(b), None) if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+            Some(
+                range(a as i64, b as i64, step)?
+                    .filter_map(|n| char::from_u32(n as u32))
+                    .map(|c| c.to_string())
+                    .collect(),
+            )
+        }
+        _ => None,
+    }
+}
+
+// 差值或步长超出 i64 时返回 None，整个花括号按原样保留
+fn range(from: i64, to: i64, step: u64) -> Option<impl Iterator<Item = i64>> {
+    let step = i64::try_from(step).ok()?;
+    let count = to.checked_sub(from)?.unsigned_abs() / step as u64 + 1;
+    let direction = if from <= to {
+        step
+    } else {
+        step.checked_neg()?
+    };
+    Some((0..count).map(move |i| from + i as i64 * direction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 只含未加引号字符的单词，展开后拼回字符串
+    fn expand(word: &str) -> Vec<String> {
+        brace_expand(vec![Args::Raw(word.to_string())])
+            .into_iter()
+            .map(|args| {
+                args.into_iter()
+                    .map(|arg| match arg {
+                        Args::Raw(s) => s,
+                        other => panic!("unexpected {:?}", other),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alternatives() {
+        assert_eq!(expand("a{b,c}d"), ["abd", "acd"]);
+        assert_eq!(expand("{,x}y"), ["y", "xy"]);
+        assert_eq!(expand("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        // 没有逗号也不是序列时保持原样
+        assert_eq!(expand("{a}"), ["{a}"]);
+        assert_eq!(expand("{a,b"), ["{a,b"]);
+    }
+
+    #[test]
+    fn nested_braces() {
+        assert_eq!(expand("a{b,c{d,e}}f"), ["abf", "acdf", "acef"]);
+        assert_eq!(expand("{x,{1..3}}"), ["x", "1", "2", "3"]);
+        assert_eq!(expand("{{a,b},c}"), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn ranges_and_steps() {
+        assert_eq!(expand("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(expand("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(expand("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(expand("{1..10..4}"), ["1", "5", "9"]);
+        // 步长的符号不影响方向，0 当作 1
+        assert_eq!(expand("{5..1..-2}"), ["5", "3", "1"]);
+        assert_eq!(expand("{1..2..0}"), ["1", "2"]);
+        assert_eq!(expand("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(expand("{c..a}"), ["c", "b", "a"]);
+        assert_eq!(expand("{1..b}"), ["{1..b}"]);
+        assert_eq!(expand("{1..2..x}"), ["{1..2..x}"]);
+    }
+
+    #[test]
+    fn zero_padding() {
+        assert_eq!(expand("{08..10}"), ["08", "09", "10"]);
+        assert_eq!(expand("{1..003}"), ["001", "002", "003"]);
+        assert_eq!(expand("{02..-1}"), ["02", "01", "00", "-1"]);
+        assert_eq!(expand("{-03..0}"), ["-03", "-02", "-01", "000"]);
+    }
+
+    #[test]
+    fn overflow_leaves_word_unexpanded() {
+        let min = i64::MIN;
+        let max = i64::MAX;
+        for word in [
+            format!("{{{}..{}}}", min, max),
+            format!("{{{}..-2}}", max),
+            format!("{{1..2..{}}}", min),
+        ] {
+            assert_eq!(expand(&word), vec![word]);
+        }
+        assert_eq!(
+            expand(&format!("{{{}..{}}}", max - 1, max)),
+            [(max - 1).to_string(), max.to_string()]
+        );
+        assert_eq!(
+            expand(&format!("{{{}..{}}}", min + 1, min)),
+            [(min + 1).to_string(), min.to_string()]
+        );
+    }
+
+    #[test]
+    fn quoted_braces_are_literal() {
+        let word = vec![Args::Raw("a".into()), Args::SingleQuotes("{b,c}".into())];
+        assert_eq!(brace_expand(word).len(), 1);
+    }
+}
@@ -1,38 +1,108 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufWriter, Error, Write};
+use std::io::{self, BufWriter, Error, Read, Write};
+use std::mem;
 use std::num::ParseIntError;
+use std::os::unix::process::CommandExt;
 use std::process::{Child, Command, exit};
+use std::rc::Rc;
 use std::{env, path::PathBuf};
 
-use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
-
-use crate::lexer::{Token, tokens_generate};
+use crate::arithmetic::evaluate;
+use crate::ast::{
+    CaseTerm, CommandList, CommandNode, Compound, CondExpr, FunctionDef, KEYWORDS, ListItem,
+    ListOp, Nesting, PipelineNode, parse_program,
+};
+use crate::conditional::{
+    TestError, binary_test, eval_test, is_integer_op, regex_match, unary_test,
+};
+use crate::job::{Job, ProcState, prepare_child, take_interrupt, wait_pid};
+use crate::lexer::{Args, LineLexer, Token, input_incomplete, tokens_generate};
 use crate::navigation::{change_directory, get_current_working_dir};
-use crate::parser::{Redirection, parse_tokens_to_args};
-use crate::terminal_io::{InputStream, IoContext, OutputStream};
+use crate::parser::{
+    ExpansionError, ParseError, Redirection, consolidate_args, expand_arith, expand_pattern_args,
+    expand_regex_args, is_valid_name, parse_tokens_to_args,
+};
+use crate::pattern::pattern_matches;
+use crate::script::source_file;
+use crate::state::{
+    Flow, ReadonlyError, ShellOptions, ShellState, Variable, format_history, parse_history,
+};
+use crate::terminal_io::{InputStream, IoContext, OutputStream, terminal};
+
+// 函数递归调用的最大层数，超过之后报错而不是把栈用完
+const MAX_FUNCTION_DEPTH: usize = 1000;
 
 pub enum BuiltinCommand {
-    ECHO,
-    EXIT,
-    HISTORY,
-    TYPE,
-    PWD,
-    CD,
+    Echo,
+    Exit,
+    History,
+    Type,
+    Pwd,
+    Cd,
+    Let,
+    Shopt,
+    Jobs,
+    Fg,
+    Bg,
+    Wait,
+    Exec,
+    Set,
+    Source,
+    Break,
+    Continue,
+    Local,
+    Return,
+    Alias,
+    Unalias,
+    Export,
+    Unset,
+    Readonly,
+    Env,
+    Test,
+    Bracket,
 }
 impl BuiltinCommand {
     pub fn name(&self) -> &'static str {
         match self {
-            BuiltinCommand::ECHO => "echo",
-            BuiltinCommand::EXIT => "exit",
-            BuiltinCommand::HISTORY => "history",
-            BuiltinCommand::TYPE => "type",
-            BuiltinCommand::PWD => "pwd",
-            BuiltinCommand::CD => "cd",
+            BuiltinCommand::Echo => "echo",
+            BuiltinCommand::Exit => "exit",
+            BuiltinCommand::History => "history",
+            BuiltinCommand::Type => "type",
+            BuiltinCommand::Pwd => "pwd",
+            BuiltinCommand::Cd => "cd",
+            BuiltinCommand::Let => "let",
+            BuiltinCommand::Shopt => "shopt",
+            BuiltinCommand::Jobs => "jobs",
+            BuiltinCommand::Fg => "fg",
+            BuiltinCommand::Bg => "bg",
+            BuiltinCommand::Wait => "wait",
+            BuiltinCommand::Exec => "exec",
+            BuiltinCommand::Set => "set",
+            BuiltinCommand::Source => "source",
+            BuiltinCommand::Break => "break",
+            BuiltinCommand::Continue => "continue",
+            BuiltinCommand::Local => "local",
+            BuiltinCommand::Return => "return",
+            BuiltinCommand::Alias => "alias",
+            BuiltinCommand::Unalias => "unalias",
+            BuiltinCommand::Export => "export",
+            BuiltinCommand::Unset => "unset",
+            BuiltinCommand::Readonly => "readonly",
+            BuiltinCommand::Env => "env",
+            BuiltinCommand::Test => "test",
+            BuiltinCommand::Bracket => "[",
         }
     }
 }
+// 命令前面展开好的 NAME=value
+pub type Assignments = Vec<(String, String)>;
 pub struct Pipeline {
     pub commands: Vec<MskCommand>,
+    // 每条命令前面的 NAME=value，和 commands 一一对应
+    pub envs: Vec<Assignments>,
+    // 展开前的命令文本，作为作业的名字
+    pub text: String,
 }
 pub enum MskCommand {
     Builtin(
@@ -46,63 +116,549 @@ pub enum MskCommand {
         Option<Vec<String>>,
         Option<Vec<Redirection>>,
     ),
-    Unknown(String),
+    // 复合命令在当前 shell 里执行，比如 if、while、for、case
+    Compound(Rc<Compound>, Option<Vec<Redirection>>),
+    // 调用函数：参数成为函数里的位置参数
+    Function(Rc<FunctionDef>, Vec<String>, Option<Vec<Redirection>>),
+    // 定义函数
+    Define(Rc<FunctionDef>),
+    // 只有 NAME=value 没有命令：给 shell 变量赋值，值在执行时才展开
+    Assign(Vec<(String, Vec<Args>)>, Option<Vec<Redirection>>),
+    Unknown(String, Option<Vec<Redirection>>),
 }
 impl MskCommand {
     fn get_redirections(&self) -> &Option<Vec<Redirection>> {
         match self {
             MskCommand::Builtin(_, _, redirections) => redirections,
             MskCommand::External(_, _, _, redirections) => redirections,
-            MskCommand::Unknown(_) => &None,
+            MskCommand::Compound(_, redirections) => redirections,
+            MskCommand::Function(_, _, redirections) => redirections,
+            MskCommand::Assign(_, redirections) => redirections,
+            MskCommand::Unknown(_, redirections) => redirections,
+            MskCommand::Define(_) => &None,
         }
     }
 }
-fn split_vec_by_sep<T: Eq>(vec: Vec<T>, sep: T) -> Vec<Vec<T>> {
-    let mut result = Vec::new();
-    let mut current_group = Vec::new();
-
-    for item in vec {
-        if item == sep {
-            // 遇到分隔符：若当前组非空，存入结果并重置
-            if !current_group.is_empty() {
-                result.push(current_group);
-                current_group = Vec::new();
+// 展开管道里的每条命令，展开后为空的简单命令直接跳过
+pub fn expand_pipeline(
+    node: &PipelineNode,
+    state: &mut ShellState,
+) -> Result<Option<Pipeline>, ExpansionError> {
+    let mut commands: Vec<MskCommand> = Vec::new();
+    let mut envs = Vec::new();
+    for stage in &node.stages {
+        match stage {
+            CommandNode::Simple(tokens) => {
+                let (assignments, tokens) = split_assignments(tokens);
+                let (all_parts, redirections) = parse_tokens_to_args(tokens, state)?;
+                match parse_command(all_parts, redirections.clone(), state) {
+                    // 命令前面的赋值只对这条命令生效，先展开好
+                    Some(cmd) => {
+                        let mut env = Vec::new();
+                        for (name, value) in assignments {
+                            env.push((name, consolidate_args(value, state)?));
+                        }
+                        commands.push(cmd);
+                        envs.push(env);
+                    }
+                    None if !assignments.is_empty() => {
+                        commands.push(MskCommand::Assign(assignments, redirections));
+                        envs.push(Vec::new());
+                    }
+                    None => {}
+                }
             }
-        } else {
-            // 非分隔符：加入当前组
-            current_group.push(item);
+            // 复合命令里面的命令执行到时才展开，这里只展开它的重定向
+            CommandNode::Compound(compound, tokens) => {
+                let (_, redirections) = parse_tokens_to_args(tokens.clone(), state)?;
+                commands.push(MskCommand::Compound(Rc::clone(compound), redirections));
+                envs.push(Vec::new());
+            }
+            CommandNode::Function(function) => {
+                commands.push(MskCommand::Define(Rc::clone(function)));
+                envs.push(Vec::new());
+            }
+        }
+    }
+    if commands.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Pipeline {
+            commands,
+            envs,
+            text: node.text.clone(),
+        }))
+    }
+}
+// 简单命令开头的 NAME=value 单词，等号前面必须是不加引号的合法变量名
+fn split_assignments(tokens: &[Token]) -> (Vec<(String, Vec<Args>)>, Vec<Token>) {
+    let mut assignments = Vec::new();
+    let mut rest = tokens.iter();
+    while let Some(Token::Word(args)) = rest.as_slice().first()
+        && let Some(Args::Raw(first)) = args.first()
+        && let Some((name, value)) = first.split_once('=')
+        && is_valid_name(name)
+    {
+        let mut value_args = Vec::new();
+        if !value.is_empty() {
+            value_args.push(Args::Raw(value.to_string()));
+        }
+        value_args.extend(args[1..].iter().cloned());
+        assignments.push((name.to_string(), value_args));
+        rest.next();
+    }
+    (assignments, rest.cloned().collect())
+}
+// 命令前面的 NAME=value 在命令执行期间临时生效，并且带上 export 属性，外部命令也能看到
+fn run_with_env(
+    cmd: MskCommand,
+    env: Assignments,
+    io_ctx: IoContext,
+    state: &mut ShellState,
+    pgroup: Option<i32>,
+    foreground: bool,
+) -> Result<CmdStatus, ProcessCmdError> {
+    let mut saved = Vec::new();
+    let mut result = Ok(());
+    let traced: Vec<String> = env
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, quote_trace(value)))
+        .collect();
+    for (name, value) in env {
+        if state.vars.get(&name).is_some_and(|var| var.readonly) {
+            result = Err(ReadonlyError(name));
+            break;
+        }
+        let var = Variable {
+            value: Some(value),
+            exported: true,
+            readonly: false,
+        };
+        let old = state.vars.insert(name.clone(), var);
+        saved.push((name, old));
+    }
+    let result = match result {
+        Ok(()) => {
+            if state.options.xtrace {
+                trace_command(&cmd, &traced, state);
+            }
+            process_single_cmd(cmd, io_ctx, state, pgroup, foreground)
+        }
+        Err(e) => Err(ExpansionError::from(e).into()),
+    };
+    for (name, old) in saved.into_iter().rev() {
+        state.restore_var(name, old);
+    }
+    result
+}
+// 空行返回 None，语法错误时什么都不执行
+pub fn parse_input(
+    input: &str,
+    aliases: &BTreeMap<String, String>,
+) -> Result<Option<CommandList>, ParseError> {
+    let (tokens, spans) = tokens_generate(input)?;
+    let list = parse_program(tokens, spans, input, aliases)?;
+    if list.items.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(list))
+    }
+}
+// 输入还没写完，需要再读一行：here-document 没等到结束符、行尾是反斜杠、
+// 引号没闭合、最后是 | && || 这类后面还要跟命令的操作符
+fn needs_more_input(input: &str) -> bool {
+    // 别名不影响输入是否完整
+    input_incomplete(input)
+        || matches!(parse_input(input, &BTreeMap::new()), Err(e) if e.is_incomplete())
+}
+// 一行行攒起来、还不是完整命令的输入
+// 每读一行只检查新的这一行，看起来完整了才解析整段确认一次
+pub struct InputBuffer {
+    text: String,
+    lexer: LineLexer,
+    nesting: Nesting,
+}
+impl InputBuffer {
+    pub fn new() -> Self {
+        InputBuffer {
+            text: String::new(),
+            lexer: LineLexer::default(),
+            nesting: Nesting::new(),
         }
     }
 
-    // 遍历结束后，将最后一个非空组存入结果
-    if !current_group.is_empty() {
-        result.push(current_group);
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
     }
 
+    // 加入一行，返回是否还需要再读一行
+    pub fn push_line(&mut self, line: &str) -> bool {
+        if !self.text.is_empty() {
+            self.text.push('\n');
+        }
+        self.text.push_str(line);
+        if let Some(tokens) = self.lexer.push_line(line) {
+            self.nesting.push_line(&tokens);
+        }
+        !self.lexer.is_complete() || self.nesting.is_open() || needs_more_input(&self.text)
+    }
+
+    // 取出攒下的输入，重新开始
+    pub fn take(&mut self) -> String {
+        mem::replace(self, InputBuffer::new()).text
+    }
+}
+// 依次执行命令列表，返回最后一条被执行的管道的退出码
+// 展开出错时放弃这一行剩下的命令；break/continue 之后剩下的命令也不再执行
+pub fn run_list(
+    list: &CommandList,
+    state: &mut ShellState,
+    io_ctx: &IoContext,
+) -> Result<i32, ProcessCmdError> {
+    let mut status = state.last_status;
+    for (i, item) in list.items.iter().enumerate() {
+        if interrupted(state) {
+            status = 130;
+        }
+        if state.flow.is_some() {
+            break;
+        }
+        let should_run = match item.op {
+            ListOp::Seq => true,
+            ListOp::And => status == 0,
+            ListOp::Or => status != 0,
+        };
+        if !should_run {
+            continue;
+        }
+        // && || 左边的命令失败不触发 set -e，只看最后一条
+        let in_chain = list
+            .items
+            .get(i + 1)
+            .is_some_and(|next| next.op != ListOp::Seq);
+        if in_chain {
+            state.errexit_ignored += 1;
+        }
+        let result = run_item(item, state, io_ctx);
+        if in_chain {
+            state.errexit_ignored -= 1;
+        }
+        status = match result {
+            // 被 Ctrl-C 打断的复合命令和函数，$? 是 130
+            Ok(_) if matches!(state.flow, Some(Flow::Interrupt)) => 130,
+            Ok(status) => status,
+            // 非交互时 set -u 和 ${var:?msg} 的错误直接退出 shell
+            Err(ProcessCmdError::Expansion(
+                e @ (ExpansionError::Unbound(_) | ExpansionError::ParamUnset(..)),
+            )) if !state.interactive => {
+                eprintln!("{}{}", state.error_prefix(), e);
+                exit_shell(state, 1);
+            }
+            Err(e) => return Err(e),
+        };
+        state.last_status = status;
+        if status != 0
+            && !in_chain
+            && !item.background
+            && state.options.errexit
+            && state.errexit_ignored == 0
+        {
+            exit_shell(state, status);
+        }
+    }
+    Ok(status)
+}
+fn run_item(
+    item: &ListItem,
+    state: &mut ShellState,
+    io_ctx: &IoContext,
+) -> Result<i32, ProcessCmdError> {
+    match expand_pipeline(&item.pipeline, state)? {
+        Some(pipeline) => run_pipeline(pipeline, state, io_ctx.try_clone()?, item.background),
+        // 展开后没有命令：有命令替换时退出码是最后一个命令替换的退出码，比如 $(exit 4)
+        None if has_command_sub(&item.pipeline) => Ok(state.last_status),
+        None => Ok(0),
+    }
+}
+fn has_command_sub(node: &PipelineNode) -> bool {
+    node.stages.iter().any(|stage| match stage {
+        CommandNode::Simple(tokens) => tokens.iter().any(|token| {
+            matches!(token, Token::Word(args) if args.iter().any(|arg| matches!(arg, Args::Command(..))))
+        }),
+        _ => false,
+    })
+}
+// 条件里的命令失败不触发 set -e
+fn run_condition(
+    list: &CommandList,
+    state: &mut ShellState,
+    io_ctx: &IoContext,
+) -> Result<i32, ProcessCmdError> {
+    state.errexit_ignored += 1;
+    let result = run_list(list, state, io_ctx);
+    state.errexit_ignored -= 1;
     result
 }
-pub fn parse_tokens_to_pipeline(tokens: Vec<Token>) -> Option<Pipeline> {
-    let tokens_split = split_vec_by_sep(tokens, Token::Op("|".to_string()));
-    let commands: Vec<MskCommand> = tokens_split
-        .into_iter()
-        .map(|v| parse_tokens_to_args(v))
-        .map(|(all_parts, redirections)| parse_command(all_parts, redirections))
-        .flatten()
-        .collect();
-    if commands.is_empty() {
-        None
-    } else {
-        Some(Pipeline { commands })
+// 和 exit 内建命令一样先保存历史记录再退出
+fn exit_shell(state: &ShellState, code: i32) -> ! {
+    if let Err(e) = state.save_history() {
+        eprintln!("{}{}", state.error_prefix(), e);
+    }
+    let _ = io::stdout().flush();
+    exit(code)
+}
+// 执行复合命令，io_ctx 已经带上了它自己的重定向
+fn run_compound(
+    compound: &Compound,
+    state: &mut ShellState,
+    io_ctx: &IoContext,
+) -> Result<i32, ProcessCmdError> {
+    match compound {
+        Compound::Group(list) => run_list(list, state, io_ctx),
+        Compound::If {
+            branches,
+            otherwise,
+        } => {
+            for (cond, body) in branches {
+                let status = run_condition(cond, state, io_ctx)?;
+                if state.flow.is_some() {
+                    return Ok(status);
+                }
+                if status == 0 {
+                    return run_list(body, state, io_ctx);
+                }
+            }
+            // 没有分支被执行时退出码为 0
+            match otherwise {
+                Some(body) => run_list(body, state, io_ctx),
+                None => Ok(0),
+            }
+        }
+        Compound::While { .. } | Compound::For { .. } | Compound::ArithFor { .. } => {
+            // 出错时也要恢复循环层数
+            state.loop_depth += 1;
+            let result = run_loop(compound, state, io_ctx);
+            state.loop_depth -= 1;
+            result
+        }
+        // 和 test 一样：真为 0，假为 1，表达式有错为 2
+        Compound::Cond(expr) => match eval_cond(expr, state) {
+            Ok(true) => Ok(0),
+            Ok(false) => Ok(1),
+            Err(e) => {
+                let mut stderr = io_ctx.stderr.try_clone()?;
+                writeln!(stderr.as_err_write(), "{}{}", state.error_prefix(), e)?;
+                Ok(2)
+            }
+        },
+        Compound::Case { word, arms } => {
+            let word = consolidate_args(word.clone(), state)?;
+            let mut status = 0;
+            // 上一个分支以 ;& 结尾，这个分支不用匹配直接执行
+            let mut fall_through = false;
+            for arm in arms {
+                if !fall_through {
+                    let mut matched = false;
+                    for pattern in &arm.patterns {
+                        let pattern = expand_pattern_args(pattern.clone(), state)?;
+                        if pattern_matches(&pattern, &word) {
+                            matched = true;
+                            break;
+                        }
+                    }
+                    if !matched {
+                        continue;
+                    }
+                }
+                status = run_list(&arm.body, state, io_ctx)?;
+                if state.flow.is_some() {
+                    break;
+                }
+                match arm.term {
+                    CaseTerm::Break => break,
+                    CaseTerm::FallThrough => fall_through = true,
+                    CaseTerm::Continue => fall_through = false,
+                }
+            }
+            Ok(status)
+        }
     }
 }
-pub fn parse_input(input: &str) -> Option<Pipeline> {
-    let tokens = tokens_generate(input);
-    parse_tokens_to_pipeline(tokens)
+// 循环的退出码是最后一次执行循环体的退出码，一次都没执行时为 0
+fn run_loop(
+    compound: &Compound,
+    state: &mut ShellState,
+    io_ctx: &IoContext,
+) -> Result<i32, ProcessCmdError> {
+    let mut status = 0;
+    match compound {
+        Compound::While { cond, body, until } => loop {
+            let cond_status = run_condition(cond, state, io_ctx)?;
+            if loop_should_exit(state) || (cond_status == 0) == *until {
+                break;
+            }
+            status = run_list(body, state, io_ctx)?;
+            if loop_should_exit(state) {
+                break;
+            }
+        },
+        Compound::For { var, words, body } => {
+            // 没有 in 时遍历位置参数
+            let words = match words {
+                Some(tokens) => parse_tokens_to_args(tokens.clone(), state)?.0,
+                None => state.positional.clone(),
+            };
+            for word in words {
+                state.set_var(var, &word).map_err(ExpansionError::from)?;
+                status = run_list(body, state, io_ctx)?;
+                if loop_should_exit(state) {
+                    break;
+                }
+            }
+        }
+        Compound::ArithFor {
+            init,
+            cond,
+            step,
+            body,
+        } => {
+            eval_arith(init, state)?;
+            // 条件为空时一直循环
+            while cond.trim().is_empty() || eval_arith(cond, state)? != 0 {
+                status = run_list(body, state, io_ctx)?;
+                if loop_should_exit(state) {
+                    break;
+                }
+                eval_arith(step, state)?;
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(status)
+}
+// 处理循环体留下的 break/continue：返回 true 表示结束这一层循环
+// 还要跳出更外层时把剩下的层数留给外层
+fn loop_should_exit(state: &mut ShellState) -> bool {
+    interrupted(state);
+    match state.flow {
+        Some(Flow::Break(n)) => {
+            state.flow = (n > 1).then_some(Flow::Break(n - 1));
+            true
+        }
+        Some(Flow::Continue(n)) if n > 1 => {
+            state.flow = Some(Flow::Continue(n - 1));
+            true
+        }
+        Some(Flow::Continue(_)) => {
+            state.flow = None;
+            false
+        }
+        // return 交给外层的函数处理，Ctrl-C 一直传到最外层
+        Some(Flow::Return | Flow::Interrupt) => true,
+        None => false,
+    }
+}
+// 收到过 Ctrl-C 时放弃剩下的命令，一直退回到提示符，和前台作业被 Ctrl-C 打断一样
+fn interrupted(state: &mut ShellState) -> bool {
+    if take_interrupt() {
+        // 换行写到终端，命令的输出可能被重定向到了文件或者管道
+        let _ = writeln!(terminal());
+        state.flow = Some(Flow::Interrupt);
+        state.last_status = 130;
+    }
+    matches!(state.flow, Some(Flow::Interrupt))
+}
+// [[ ]]：== 和 != 右边是模式，=~ 右边是正则，整数比较的两边按算术表达式求值
+fn eval_cond(expr: &CondExpr, state: &mut ShellState) -> Result<bool, ProcessCmdError> {
+    match expr {
+        CondExpr::Word(word) => Ok(!consolidate_args(word.clone(), state)?.is_empty()),
+        CondExpr::Unary(op, word) => Ok(unary_test(op, &consolidate_args(word.clone(), state)?)),
+        CondExpr::Binary(left, op, right) => {
+            let left = consolidate_args(left.clone(), state)?;
+            match op.as_str() {
+                "==" | "=" | "!=" => {
+                    let pattern = expand_pattern_args(right.clone(), state)?;
+                    Ok(pattern_matches(&pattern, &left) == (op != "!="))
+                }
+                "=~" => {
+                    let regex = expand_regex_args(right.clone(), state)?;
+                    let matched = regex_match(&regex, &left)?;
+                    // 匹配失败时清空上一次的结果
+                    let groups = matched.clone().unwrap_or_default();
+                    state.arrays.insert("BASH_REMATCH".to_string(), groups);
+                    Ok(matched.is_some())
+                }
+                op if is_integer_op(op) => {
+                    let right = consolidate_args(right.clone(), state)?;
+                    let mut number = |value: String| {
+                        evaluate(&value, state).map_err(|e| ExpansionError::Arith(value, e))
+                    };
+                    let left = number(left)?.to_string();
+                    let right = number(right)?.to_string();
+                    Ok(binary_test(&left, op, &right)?)
+                }
+                op => {
+                    let right = consolidate_args(right.clone(), state)?;
+                    Ok(binary_test(&left, op, &right)?)
+                }
+            }
+        }
+        CondExpr::Not(expr) => Ok(!eval_cond(expr, state)?),
+        CondExpr::And(left, right) => Ok(eval_cond(left, state)? && eval_cond(right, state)?),
+        CondExpr::Or(left, right) => Ok(eval_cond(left, state)? || eval_cond(right, state)?),
+    }
+}
+// for ((...)) 里的表达式，先做参数展开再求值，空表达式什么都不做
+fn eval_arith(expr: &str, state: &mut ShellState) -> Result<i64, ProcessCmdError> {
+    if expr.trim().is_empty() {
+        return Ok(0);
+    }
+    Ok(expand_arith(expr, state)?)
+}
+// 调用函数：换上函数自己的位置参数，函数里的循环层数从 0 开始
+// 返回时恢复位置参数和 local 变量
+fn call_function(
+    function: &FunctionDef,
+    args: Vec<String>,
+    state: &mut ShellState,
+    io_ctx: &mut IoContext,
+) -> Result<i32, ProcessCmdError> {
+    if interrupted(state) {
+        return Ok(130);
+    }
+    if state.locals.len() >= MAX_FUNCTION_DEPTH {
+        return Err(ProcessCmdError::ArgsError(format!(
+            "{}: maximum function nesting level exceeded ({})",
+            function.name, MAX_FUNCTION_DEPTH
+        )));
+    }
+    // 定义时写的重定向每次调用都重新展开
+    let (_, redirections) = parse_tokens_to_args(function.redirections.clone(), state)?;
+    if let Some(redirections) = redirections {
+        io_ctx.apply_redirections(&redirections, state.options.noclobber)?;
+    }
+    let saved_positional = mem::replace(&mut state.positional, args);
+    let saved_loop_depth = mem::replace(&mut state.loop_depth, 0);
+    state.locals.push(HashMap::new());
+    let result = run_compound(&function.body, state, io_ctx);
+    if matches!(state.flow, Some(Flow::Return)) {
+        state.flow = None;
+    }
+    if let Some(frame) = state.locals.pop() {
+        for (name, saved) in frame {
+            state.restore_var(name, saved);
+        }
+    }
+    state.loop_depth = saved_loop_depth;
+    state.positional = saved_positional;
+    result
 }
 // pub fn parse_command(input: &str) -> Option<MskCommand> {
+// 函数优先于内建命令和 PATH 里的命令
 pub fn parse_command(
     mut all_parts: Vec<String>,
     redirections: Option<Vec<Redirection>>,
+    state: &ShellState,
 ) -> Option<MskCommand> {
     // let tokens = tokens_generate(input);
 
@@ -117,27 +673,35 @@ pub fn parse_command(
     let cmd = all_parts.remove(0);
     let args = all_parts; // 剩下的就是参数列表
 
+    if let Some(function) = state.functions.get(&cmd) {
+        return Some(MskCommand::Function(
+            Rc::clone(function),
+            args,
+            redirections,
+        ));
+    }
+
     match cmd.as_str() {
         "echo" => Some(MskCommand::Builtin(
-            BuiltinCommand::ECHO,
+            BuiltinCommand::Echo,
             Some(args),
             redirections,
         )),
         "exit" => Some(MskCommand::Builtin(
-            BuiltinCommand::EXIT,
-            None,
+            BuiltinCommand::Exit,
+            Some(args),
             redirections,
         )),
         "type" => {
             if args.is_empty() {
                 Some(MskCommand::Builtin(
-                    BuiltinCommand::TYPE,
+                    BuiltinCommand::Type,
                     None,
                     redirections,
                 ))
             } else {
                 Some(MskCommand::Builtin(
-                    BuiltinCommand::TYPE,
+                    BuiltinCommand::Type,
                     Some(args),
                     redirections,
                 ))
@@ -146,135 +710,446 @@ pub fn parse_command(
         "history" => {
             if args.is_empty() {
                 Some(MskCommand::Builtin(
-                    BuiltinCommand::HISTORY,
+                    BuiltinCommand::History,
                     None,
                     redirections,
                 ))
             } else {
                 Some(MskCommand::Builtin(
-                    BuiltinCommand::HISTORY,
+                    BuiltinCommand::History,
                     Some(args),
                     redirections,
                 ))
             }
         }
-        "pwd" => Some(MskCommand::Builtin(BuiltinCommand::PWD, None, redirections)),
+        "pwd" => Some(MskCommand::Builtin(BuiltinCommand::Pwd, None, redirections)),
         "cd" => {
             if args.is_empty() {
-                Some(MskCommand::Builtin(BuiltinCommand::CD, None, redirections))
+                Some(MskCommand::Builtin(BuiltinCommand::Cd, None, redirections))
             } else {
                 Some(MskCommand::Builtin(
-                    BuiltinCommand::CD,
+                    BuiltinCommand::Cd,
                     Some(args),
                     redirections,
                 ))
             }
         }
+        "let" => Some(MskCommand::Builtin(
+            BuiltinCommand::Let,
+            Some(args),
+            redirections,
+        )),
+        "shopt" => Some(MskCommand::Builtin(
+            BuiltinCommand::Shopt,
+            Some(args),
+            redirections,
+        )),
+        "jobs" => Some(MskCommand::Builtin(
+            BuiltinCommand::Jobs,
+            Some(args),
+            redirections,
+        )),
+        "fg" => Some(MskCommand::Builtin(
+            BuiltinCommand::Fg,
+            Some(args),
+            redirections,
+        )),
+        "bg" => Some(MskCommand::Builtin(
+            BuiltinCommand::Bg,
+            Some(args),
+            redirections,
+        )),
+        "wait" => Some(MskCommand::Builtin(
+            BuiltinCommand::Wait,
+            Some(args),
+            redirections,
+        )),
         "" => None,
+        "exec" => Some(MskCommand::Builtin(
+            BuiltinCommand::Exec,
+            Some(args),
+            redirections,
+        )),
+        "source" | "." => Some(MskCommand::Builtin(
+            BuiltinCommand::Source,
+            Some(args),
+            redirections,
+        )),
+        "set" => Some(MskCommand::Builtin(
+            BuiltinCommand::Set,
+            Some(args),
+            redirections,
+        )),
+        "break" => Some(MskCommand::Builtin(
+            BuiltinCommand::Break,
+            Some(args),
+            redirections,
+        )),
+        "continue" => Some(MskCommand::Builtin(
+            BuiltinCommand::Continue,
+            Some(args),
+            redirections,
+        )),
+        "local" => Some(MskCommand::Builtin(
+            BuiltinCommand::Local,
+            Some(args),
+            redirections,
+        )),
+        "return" => Some(MskCommand::Builtin(
+            BuiltinCommand::Return,
+            Some(args),
+            redirections,
+        )),
+        "alias" => Some(MskCommand::Builtin(
+            BuiltinCommand::Alias,
+            Some(args),
+            redirections,
+        )),
+        "unalias" => Some(MskCommand::Builtin(
+            BuiltinCommand::Unalias,
+            Some(args),
+            redirections,
+        )),
+        "export" => Some(MskCommand::Builtin(
+            BuiltinCommand::Export,
+            Some(args),
+            redirections,
+        )),
+        "unset" => Some(MskCommand::Builtin(
+            BuiltinCommand::Unset,
+            Some(args),
+            redirections,
+        )),
+        "test" => Some(MskCommand::Builtin(
+            BuiltinCommand::Test,
+            Some(args),
+            redirections,
+        )),
+        "[" => Some(MskCommand::Builtin(
+            BuiltinCommand::Bracket,
+            Some(args),
+            redirections,
+        )),
+        "readonly" => Some(MskCommand::Builtin(
+            BuiltinCommand::Readonly,
+            Some(args),
+            redirections,
+        )),
+        // 不带参数的 env 只是列出环境，带参数时交给外部的 env 去运行命令
+        "env" if args.is_empty() => Some(MskCommand::Builtin(
+            BuiltinCommand::Env,
+            Some(args),
+            redirections,
+        )),
         other => {
-            let env_path = env::var_os("PATH");
-            if let Some(os_string) = env_path {
-                let path_buf_iter = env::split_paths(&os_string);
-                let executable_path = path_buf_iter
-                    .map(|path| path.join(&cmd))
-                    .filter(|candidate| is_executable(candidate))
-                    .collect::<Vec<PathBuf>>();
-                if !executable_path.is_empty() {
-                    return Some(MskCommand::External(
-                        other.to_string(),
-                        executable_path,
-                        Some(args),
-                        redirections,
-                    ));
-                }
+            let executable_path = find_executable(other, state);
+            if !executable_path.is_empty() {
+                return Some(MskCommand::External(
+                    other.to_string(),
+                    executable_path,
+                    Some(args),
+                    redirections,
+                ));
             }
-            Some(MskCommand::Unknown(other.to_string()))
+            Some(MskCommand::Unknown(other.to_string(), redirections))
         }
     }
 }
-#[derive(Debug)]
+// 在 PATH 里查找可执行文件，带 / 的名字直接当作路径
+fn find_executable(name: &str, state: &ShellState) -> Vec<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) {
+            vec![path]
+        } else {
+            Vec::new()
+        };
+    }
+    match state.get_var("PATH") {
+        Some(paths) => env::split_paths(&paths)
+            .map(|path| path.join(name))
+            .filter(|candidate| is_executable(candidate))
+            .collect(),
+        None => Vec::new(),
+    }
+}
+// source 的文件名不带 / 时先在 PATH 里找，找不到再用当前目录下的
+fn find_source_file(name: &str, state: &ShellState) -> PathBuf {
+    if !name.contains('/')
+        && let Some(paths) = state.get_var("PATH")
+        && let Some(path) = env::split_paths(&paths)
+            .map(|path| path.join(name))
+            .find(|candidate| candidate.is_file())
+    {
+        return path;
+    }
+    PathBuf::from(name)
+}
+// 外部命令的公共设置：标准输入输出、3 号以上的 fd、信号处理
+// io_ctx.extra_fds 里的文件要活到 spawn/exec 结束，子进程才能从中复制，所以这里只借用
+fn setup_external(command: &mut Command, io_ctx: &mut IoContext, foreground: bool) {
+    let mut child_fds = io_ctx.child_fds();
+    unsafe {
+        command.pre_exec(move || {
+            prepare_child(foreground);
+            child_fds.apply()
+        });
+    }
+    command
+        .stdin(mem::replace(&mut io_ctx.stdin, InputStream::Inherit).into_stdio())
+        .stdout(mem::replace(&mut io_ctx.stdout, OutputStream::Inherit).into_stdio())
+        .stderr(mem::replace(&mut io_ctx.stderr, OutputStream::Inherit).into_stdio());
+}
+#[derive(Debug, thiserror::Error)]
 pub enum ProcessCmdError {
-    IOError(Error),
+    #[error("{0}")]
+    IOError(#[from] Error),
+    #[error("{0}")]
     ArgsError(String),
-    Other,
+    #[error("{0}")]
+    Expansion(#[from] ExpansionError),
+    #[error("{0}")]
+    Syntax(#[from] ParseError),
+    #[error("{0}")]
+    Test(#[from] TestError),
+}
+// 单条命令的执行结果：外部命令交回子进程，内建命令直接给出退出码
+pub enum CmdStatus {
+    Spawned(Child),
+    Exited(i32),
 }
 impl From<ParseIntError> for ProcessCmdError {
     fn from(_value: ParseIntError) -> Self {
         ProcessCmdError::ArgsError("该参数应为数字".into())
     }
 }
-impl From<std::io::Error> for ProcessCmdError {
-    fn from(e: std::io::Error) -> Self {
-        ProcessCmdError::IOError(e)
-    }
-}
+// io_ctx 是整条管道的输入输出：第一条命令从它的 stdin 读，最后一条命令写到它的 stdout
+// 命令替换时 stdout 是管道的写端，复合命令里是复合命令自己的重定向
+// 返回最后一条命令的退出码
 pub fn run_pipeline(
     pipelne: Pipeline,
-    history: &mut Vec<String>,
-    history_written_count: &mut usize,
-) -> Result<(), ProcessCmdError> {
-    let _ = disable_raw_mode();
-    let mut children: Vec<Child> = Vec::new();
+    state: &mut ShellState,
+    io_ctx: IoContext,
+    background: bool,
+) -> Result<i32, ProcessCmdError> {
+    let text = pipelne.text;
+    let mut children: Vec<(usize, i32)> = Vec::new();
+    let mut staged: Vec<(usize, MskCommand, Assignments, IoContext)> = Vec::new();
     let mut previous_read_end = None;
-    let mut first_cmd = true;
-    let mut cmds = pipelne.commands.into_iter().peekable();
-    while let Some(cmd) = cmds.next() {
-        let io_ctx;
+    let mut cmds = pipelne.commands.into_iter().zip(pipelne.envs).peekable();
+    while let Some((cmd, env)) = cmds.next() {
+        let mut cmd_io = io_ctx.try_clone()?;
+        // 不是第一条命令：从上一条命令的管道读
+        if let Some(reader) = previous_read_end.take() {
+            cmd_io.stdin = InputStream::Pipe(reader);
+        }
+        // 后面还有命令：输出要给下一个命令当输入，读端留给下一条命令
         if cmds.peek().is_some() {
             let (reader, writer) = std::io::pipe()?;
-            // 如果后面有命令检查现在是不是第一条命令
-            if first_cmd {
-                // 第一条命令的输入就是系统，输出要给下一个命令当输入
-                first_cmd = false;
-                io_ctx = IoContext {
-                    stdout: OutputStream::Pipe(writer),
-                    stderr: OutputStream::Inherit,
-                    stdin: InputStream::Inherit,
-                };
-            } else {
-                // 下一条还有命令，但是自己不是第一条命令
-                io_ctx = IoContext {
-                    stdout: OutputStream::Pipe(writer),
-                    stderr: OutputStream::Inherit,
-                    // 此时可以安全unwrap因为第一次运行保证了里面必定有值
-                    stdin: InputStream::Pipe(previous_read_end.take().unwrap()),
-                };
-            }
-            // 给下一条命令保存读端
+            cmd_io.stdout = OutputStream::Pipe(writer);
             previous_read_end = Some(reader);
+        }
+        cmd_io.inherit_fds(&state.fds)?;
+
+        staged.push((staged.len(), cmd, env, cmd_io));
+    }
+    // 管道的写端只能留在各条命令手里，否则读的一方等不到 EOF
+    drop(io_ctx);
+    let mut statuses = vec![0; staged.len()];
+    // 管道里有多条命令或者放到后台时，内建命令和复合命令也 fork 到子 shell 里，
+    // 和外部命令同时运行：既不会因为管道缓冲区写满而互相等待，里面的赋值也不会留在当前 shell
+    let in_subshell = staged.len() > 1 || background;
+    // 后台作业和作业控制下的前台作业都放进自己的进程组，组号就是第一个进程的 pid
+    // 前台作业还要占有终端，这样 Ctrl-Z、Ctrl-C 只会发给它
+    let foreground = !background && state.job_control.is_some();
+    let mut pgroup = (background || foreground).then_some(0);
+    let mut pending = staged.into_iter();
+    while let Some((idx, cmd, env, io_ctx)) = pending.next() {
+        let result = if in_subshell && !matches!(cmd, MskCommand::External(..)) {
+            fork_subshell(state, pgroup, foreground, |state| {
+                // 后面几条命令的管道不能留在子进程里，否则它们等不到 EOF
+                pending.by_ref().for_each(drop);
+                match run_with_env(cmd, env, io_ctx, state, None, false) {
+                    Ok(CmdStatus::Exited(code)) => code,
+                    Ok(CmdStatus::Spawned(mut child)) => {
+                        child.wait().map_or(1, |status| status.code().unwrap_or(1))
+                    }
+                    Err(e) => {
+                        eprintln!("{}{}", state.error_prefix(), e);
+                        1
+                    }
+                }
+            })
+            .map(Some)
+            .map_err(ProcessCmdError::from)
         } else {
-            if first_cmd {
-                // 如果后面没有管道就证明自己是最后一条命令，直接写入标准输出
-                // 但是如果如果自己同时是第一条命令，标准输入是继承
-                io_ctx = IoContext::new()
-            } else {
-                io_ctx = IoContext {
-                    stdout: OutputStream::Inherit,
-                    stderr: OutputStream::Inherit,
-                    // 此时可以安全unwrap因为第一次运行保证了里面必定有值
-                    stdin: InputStream::Pipe(previous_read_end.take().unwrap()),
-                };
+            match run_with_env(cmd, env, io_ctx, state, pgroup, foreground) {
+                Ok(CmdStatus::Spawned(child)) => Ok(Some(child.id() as i32)),
+                Ok(CmdStatus::Exited(code)) => {
+                    statuses[idx] = code;
+                    Ok(None)
+                }
+                Err(e) => Err(e),
+            }
+        };
+        match result {
+            Ok(Some(pid)) => {
+                if pgroup == Some(0) {
+                    pgroup = Some(pid);
+                    if let Some(job_control) = &state.job_control
+                        && foreground
+                    {
+                        job_control.give_terminal(pid, None);
+                    }
+                }
+                children.push((idx, pid));
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("{}{}", state.error_prefix(), e);
+                statuses[idx] = 1;
             }
         }
-
-        match process_single_cmd(cmd, io_ctx, history, history_written_count) {
-            Ok(Some(child)) => children.push(child),
-            Ok(None) => {} // Builtin 命令没有子进程
-            Err(e) => eprintln!("Command execution error: {:?}\r", e),
+    }
+    if children.is_empty() {
+        return Ok(if background {
+            0
+        } else {
+            pipeline_status(&statuses, state)
+        });
+    }
+    let pids = children.iter().map(|(_, pid)| *pid).collect();
+    let mut job = Job::new(pgroup.unwrap_or(0), pids, text);
+    if background {
+        let last_pid = job.last_pid().unwrap_or(0);
+        state.last_bg_pid = Some(last_pid as u32);
+        let id = state.jobs.add(job);
+        // 和 bash 一样只有交互时才显示作业号和 pid
+        if state.interactive {
+            let _ = writeln!(terminal(), "[{}] {}", id, last_pid);
         }
+        return Ok(0);
     }
-    for mut child in children {
-        let _ = child.wait();
+    job.wait();
+    if let Some(job_control) = &state.job_control
+        && foreground
+    {
+        job.tmodes = job_control.reclaim_terminal();
     }
-    let _ = enable_raw_mode();
-    Ok(())
+    if job.interrupted() {
+        if state.interactive {
+            let _ = writeln!(terminal());
+        }
+        // 和 bash 一样，Ctrl-C 打断前台命令时整行剩下的命令都不再执行
+        state.flow = Some(Flow::Interrupt);
+    }
+    for ((idx, _), proc) in children.iter().zip(&job.procs) {
+        statuses[*idx] = proc.state.code();
+    }
+    if job.state() == ProcState::Stopped {
+        // 被暂停的前台作业转入作业表，之后可以用 fg/bg 继续
+        let id = state.jobs.add(job);
+        let _ = writeln!(terminal(), "\n{}", state.jobs.format(id, false));
+        return Ok(ProcState::Stopped.code());
+    }
+    Ok(pipeline_status(&statuses, state))
+}
+// 管道的退出码是最后一条命令的；pipefail 时是最右边一个失败的命令的
+fn pipeline_status(statuses: &[i32], state: &ShellState) -> i32 {
+    if state.options.pipefail {
+        statuses
+            .iter()
+            .rev()
+            .copied()
+            .find(|&code| code != 0)
+            .unwrap_or(0)
+    } else {
+        statuses.last().copied().unwrap_or(0)
+    }
+}
+// 命令替换：在子 shell 里执行 input，收集它的标准输出
+// 末尾的换行会被去掉，$? 是子 shell 的退出码
+pub fn capture_output(input: &str, state: &mut ShellState) -> Result<String, ProcessCmdError> {
+    // 有语法错误就什么都不执行
+    let list = parse_input(input, &state.aliases)?;
+    let (mut reader, writer) = std::io::pipe()?;
+    // 写端只交给子进程，父进程这边随闭包一起关闭，读到 EOF 就说明子 shell 结束了
+    let pid = fork_subshell(state, None, false, move |state| {
+        // 和 bash 一样，命令替换里不继承 set -e
+        state.errexit_ignored += 1;
+        let io_ctx = IoContext {
+            stdout: OutputStream::Pipe(writer),
+            ..IoContext::new()
+        };
+        match list.map(|list| run_list(&list, state, &io_ctx)) {
+            Some(Ok(status)) => status,
+            Some(Err(e)) => {
+                eprintln!("{}{}", state.error_prefix(), e);
+                1
+            }
+            None => 0,
+        }
+    })?;
+    let mut buf = Vec::new();
+    let read_result = reader.read_to_end(&mut buf);
+    if let Some(proc_state) = wait_pid(pid, 0) {
+        state.last_status = proc_state.code();
+    }
+    read_result?;
+    let mut output = String::from_utf8_lossy(&buf).into_owned();
+    while output.ends_with('\n') {
+        output.pop();
+    }
+    Ok(output)
+}
+// fork 出一个子 shell 执行 run，返回子进程的 pid；子进程执行完以 run 的返回值退出
+// 子 shell 里的赋值、cd、exit 都不会影响当前 shell
+// pgroup 不是 None 时子进程加入这个进程组 (0 表示自己新建一个)，和外部命令一样
+fn fork_subshell(
+    state: &mut ShellState,
+    pgroup: Option<i32>,
+    foreground: bool,
+    run: impl FnOnce(&mut ShellState) -> i32,
+) -> io::Result<i32> {
+    // 缓冲区里还没写出去的内容不能让子进程再写一遍
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if pid == 0 {
+        if let Some(pgid) = pgroup {
+            unsafe {
+                libc::setpgid(0, pgid);
+            }
+            prepare_child(foreground);
+        }
+        // 和外部命令一样，读端关闭后再写管道就直接结束
+        unsafe {
+            libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        }
+        state.enter_subshell();
+        let status = run(state);
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        unsafe { libc::_exit(status) }
+    }
+    // 父子进程都设置一次进程组，不管谁先运行，之后交出终端时进程组都已经存在
+    if let Some(pgid) = pgroup {
+        unsafe {
+            libc::setpgid(pid, if pgid == 0 { pid } else { pgid });
+        }
+    }
+    Ok(pid)
 }
 pub fn process_single_cmd(
     cmd: MskCommand,
     mut io_ctx: IoContext,
-    history: &mut Vec<String>,
-    history_written_count: &mut usize,
-) -> Result<Option<Child>, ProcessCmdError> {
+    state: &mut ShellState,
+    pgroup: Option<i32>,
+    foreground: bool,
+) -> Result<CmdStatus, ProcessCmdError> {
     // let mut cmds = pipelne.commands.into_iter().peekable();
     // let mut io_ctx = IoContext::new();
     // io_ctx.stdin = stdin;
@@ -283,60 +1158,96 @@ pub fn process_single_cmd(
     // while let Some(cmd) = cmds.next() {
     let redirections_opt = cmd.get_redirections();
     if let Some(redirections) = redirections_opt {
-        io_ctx.apply_redirections(redirections)?;
+        io_ctx.apply_redirections(redirections, state.options.noclobber)?;
     }
-
+    if !matches!(cmd, MskCommand::Builtin(..)) {
+        return run_command(cmd, io_ctx, state, pgroup, foreground);
+    }
+    // 内建命令出错时和 bash 一样只报错、退出码为 1，不打断后面的命令
+    // 报错按命令自己的重定向写出去，2>/dev/null 才能让它闭嘴
+    let mut stderr = io_ctx.stderr.try_clone()?;
+    match run_command(cmd, io_ctx, state, pgroup, foreground) {
+        Err(e) => {
+            writeln!(stderr.as_err_write(), "{}{}", state.error_prefix(), e)?;
+            Ok(CmdStatus::Exited(1))
+        }
+        result => result,
+    }
+}
+// 执行一条已经装好重定向的命令
+fn run_command(
+    cmd: MskCommand,
+    mut io_ctx: IoContext,
+    state: &mut ShellState,
+    pgroup: Option<i32>,
+    foreground: bool,
+) -> Result<CmdStatus, ProcessCmdError> {
+    let mut status = 0;
     match cmd {
-        MskCommand::Builtin(BuiltinCommand::ECHO, args, _) => {
-            let mut writer = io_ctx.stdout.to_write();
+        MskCommand::Builtin(BuiltinCommand::Echo, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
             let output = args.unwrap().join(" ");
-            write!(writer, "{}\n", output)?;
+            writeln!(writer, "{}", output)?;
         }
-        MskCommand::Builtin(BuiltinCommand::EXIT, _, _) => {
-            if let Ok(path) = env::var("HISTFILE") {
-                let file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(path)?;
-                let mut writer = BufWriter::new(file);
-                for item in history {
-                    writeln!(writer, "{}", item)?;
-                }
-                writer.flush()?;
-            }
-            exit(0)
+        MskCommand::Builtin(BuiltinCommand::Exit, args, _) => {
+            // 不带参数时以上一条命令的退出码退出
+            let code = match args.unwrap_or_default().first() {
+                Some(n) => n.parse::<i32>()?,
+                None => state.last_status,
+            };
+            state.save_history()?;
+            exit(code)
         }
-        MskCommand::Builtin(BuiltinCommand::PWD, _, _) => {
-            let mut writer = io_ctx.stdout.to_write();
+        MskCommand::Builtin(BuiltinCommand::Pwd, _, _) => {
+            let mut writer = io_ctx.stdout.as_write();
             let pwd = get_current_working_dir();
-            write!(writer, "{}\n", format!("{}", &pwd))?;
+            writeln!(writer, "{}", &pwd)?;
         }
-        MskCommand::Builtin(BuiltinCommand::CD, args, _) => {
-            if let Some(path) = args {
-                change_directory(&path[0]);
-            } else {
-                change_directory("~");
+        MskCommand::Builtin(BuiltinCommand::Cd, args, _) => {
+            // 不带参数回到 HOME，cd - 回到上一个目录并打印出来
+            let (dir, show) = match args.unwrap_or_default().first().map(String::as_str) {
+                Some("-") => (state.get_var("OLDPWD").ok_or("OLDPWD"), true),
+                Some(dir) => (Ok(dir.to_string()), false),
+                None => (state.get_var("HOME").ok_or("HOME"), false),
+            };
+            match dir {
+                Err(name) => {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}cd: {} not set",
+                        state.error_prefix(),
+                        name
+                    )?;
+                    status = 1;
+                }
+                Ok(dir) => {
+                    if change_directory(&dir, state).is_err() {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}cd: {}: No such file or directory",
+                            state.error_prefix(),
+                            dir
+                        )?;
+                        status = 1;
+                    } else if show {
+                        writeln!(io_ctx.stdout.as_write(), "{}", get_current_working_dir())?;
+                    }
+                }
             }
         }
-        MskCommand::Builtin(BuiltinCommand::HISTORY, args_opt, _) => {
-            let mut writer = io_ctx.stdout.to_write();
+        MskCommand::Builtin(BuiltinCommand::History, args_opt, _) => {
+            let mut writer = io_ctx.stdout.as_write();
             if let Some(args) = args_opt {
                 if args.len() == 2 {
                     if args[0] == "-r" {
                         let content = fs::read_to_string(&args[1])?;
-                        let mut lines: Vec<String> = content
-                            .lines()
-                            .map(|line| line.trim().to_string())
-                            .filter(|line| !line.is_empty())
-                            .collect();
-                        history.append(&mut lines);
-                        *history_written_count = history.len();
+                        state.history.append(&mut parse_history(&content));
+                        state.history_written_count = state.history.len();
                     } else if args[0] == "-w" {
                         let file = File::create(&args[1])?;
                         let mut writer = BufWriter::new(file);
-                        for item in history {
-                            writeln!(writer, "{}", item)?;
+                        for item in &state.history {
+                            writeln!(writer, "{}", format_history(item))?;
                         }
                         writer.flush()?;
                     } else if args[0] == "-a" {
@@ -345,38 +1256,428 @@ pub fn process_single_cmd(
                             .append(true)
                             .open(&args[1])?;
                         let mut writer = BufWriter::new(file);
-                        for item in &history[*history_written_count..] {
-                            writeln!(writer, "{}", item)?;
+                        for item in &state.history[state.history_written_count..] {
+                            writeln!(writer, "{}", format_history(item))?;
                         }
                         writer.flush()?;
-                        *history_written_count = history.len();
+                        state.history_written_count = state.history.len();
                     }
                 } else {
                     let limit = args[0].parse::<usize>()?;
-                    let history_len = history.len();
+                    let history_len = state.history.len();
                     // 若 limit >= 历史总数，从 0 开始；否则从 history_len - limit 开始
                     let start_idx = history_len.saturating_sub(limit);
-                    for (idx, command) in history[start_idx..].iter().enumerate() {
+                    for (idx, command) in state.history[start_idx..].iter().enumerate() {
                         let display_idx = start_idx + idx + 1;
                         writeln!(writer, "{:5}  {}", display_idx, command)?;
                     }
                 }
             } else {
-                for (i, command) in history.iter().enumerate() {
+                for (i, command) in state.history.iter().enumerate() {
                     writeln!(writer, "{:5}  {}", i + 1, command)?;
                 }
             }
         }
-        MskCommand::Builtin(BuiltinCommand::TYPE, args_opt, _) => {
+        MskCommand::Builtin(BuiltinCommand::Let, args, _) => {
+            let args = args.unwrap_or_default();
+            if args.is_empty() {
+                return Err(ProcessCmdError::ArgsError(
+                    "let: expression expected".to_string(),
+                ));
+            }
+            // 依次求值，退出码取决于最后一个表达式是否为 0
+            let mut last = 0;
+            for expr in args {
+                match evaluate(&expr, state) {
+                    Ok(value) => last = value,
+                    Err(e) => {
+                        return Err(ProcessCmdError::ArgsError(format!("let: {}: {}", expr, e)));
+                    }
+                }
+            }
+            status = if last == 0 { 1 } else { 0 };
+        }
+        MskCommand::Builtin(BuiltinCommand::Shopt, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let mut args = args.unwrap_or_default().into_iter().peekable();
+            // -s 打开，-u 关闭，-p 以可复用的形式打印，-q 只设置退出码
+            let mut action = None;
+            let mut print_reusable = false;
+            let mut quiet = false;
+            while let Some(flag) = args.next_if(|a| a.starts_with('-')) {
+                match flag.as_str() {
+                    "-s" => action = Some(true),
+                    "-u" => action = Some(false),
+                    "-p" => print_reusable = true,
+                    "-q" => quiet = true,
+                    _ => {
+                        // 用法错误的退出码是 2
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}shopt: {}: invalid option",
+                            state.error_prefix(),
+                            flag
+                        )?;
+                        return Ok(CmdStatus::Exited(2));
+                    }
+                }
+            }
+            let names: Vec<String> = args.collect();
+            let names = if names.is_empty() {
+                ShellOptions::SHOPT_NAMES
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect()
+            } else {
+                names
+            };
+            for name in names {
+                let Some(option) = state.options.shopt_mut(&name) else {
+                    return Err(ProcessCmdError::ArgsError(format!(
+                        "shopt: {}: invalid shell option name",
+                        name
+                    )));
+                };
+                match action {
+                    Some(on) => *option = on,
+                    None => {
+                        let on = *option;
+                        if !on {
+                            status = 1;
+                        }
+                        if quiet {
+                            continue;
+                        }
+                        if print_reusable {
+                            writeln!(writer, "shopt {} {}", if on { "-s" } else { "-u" }, name)?;
+                        } else {
+                            writeln!(writer, "{:<15}\t{}", name, if on { "on" } else { "off" })?;
+                        }
+                    }
+                }
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Set, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let args = args.unwrap_or_default();
+            // 不带参数时列出所有变量
+            if args.is_empty() {
+                let mut vars: Vec<_> = state
+                    .vars
+                    .iter()
+                    .filter_map(|(name, var)| Some((name, var.value.as_ref()?)))
+                    .collect();
+                vars.sort();
+                for (name, value) in vars {
+                    writeln!(writer, "{}={}", name, quote_trace(value))?;
+                }
+            }
+            let mut args = args.into_iter();
+            // -X 打开选项，+X 关闭；-o name / +o name 用长名字
+            while let Some(arg) = args.next() {
+                // -- 之后的参数，或者从第一个不是选项的参数开始，都是新的位置参数
+                if arg == "--" {
+                    state.positional = args.by_ref().collect();
+                    break;
+                }
+                let on = arg.starts_with('-');
+                if !on && !arg.starts_with('+') {
+                    state.positional = std::iter::once(arg).chain(args.by_ref()).collect();
+                    break;
+                }
+                if arg.len() < 2 {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}set: {}: invalid option",
+                        state.error_prefix(),
+                        arg
+                    )?;
+                    return Ok(CmdStatus::Exited(2));
+                }
+                for flag in arg[1..].chars() {
+                    let name = if flag == 'o' {
+                        match args.next() {
+                            Some(name) => name,
+                            // 单独的 -o 列出所有选项，+o 以可复用的形式列出
+                            None => {
+                                for &name in ShellOptions::SET_NAMES {
+                                    let value = *state.options.set_mut(name).unwrap();
+                                    if on {
+                                        let text = if value { "on" } else { "off" };
+                                        writeln!(writer, "{:<15}\t{}", name, text)?;
+                                    } else {
+                                        let sign = if value { '-' } else { '+' };
+                                        writeln!(writer, "set {}o {}", sign, name)?;
+                                    }
+                                }
+                                continue;
+                            }
+                        }
+                    } else {
+                        match ShellOptions::set_flag_name(flag) {
+                            Some(name) => name.to_string(),
+                            None => {
+                                writeln!(
+                                    io_ctx.stderr.as_err_write(),
+                                    "{}set: {}{}: invalid option",
+                                    state.error_prefix(),
+                                    &arg[..1],
+                                    flag
+                                )?;
+                                return Ok(CmdStatus::Exited(2));
+                            }
+                        }
+                    };
+                    match state.options.set_mut(&name) {
+                        Some(option) => *option = on,
+                        None => {
+                            writeln!(
+                                io_ctx.stderr.as_err_write(),
+                                "{}set: {}: invalid option name",
+                                state.error_prefix(),
+                                name
+                            )?;
+                            return Ok(CmdStatus::Exited(2));
+                        }
+                    }
+                }
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Jobs, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let args = args.unwrap_or_default();
+            // -l 带上进程组号，-p 只列出进程组号
+            let long = args.iter().any(|a| a == "-l");
+            let pids_only = args.iter().any(|a| a == "-p");
+            state.jobs.poll();
+            for id in state.jobs.ids() {
+                if pids_only {
+                    if let Some(job) = state.jobs.get(id) {
+                        writeln!(writer, "{}", job.pgid)?;
+                    }
+                } else {
+                    writeln!(writer, "{}", state.jobs.format(id, long))?;
+                }
+            }
+            // 已经列出的状态不用在提示符前再报告一遍
+            state.jobs.acknowledge();
+        }
+        MskCommand::Builtin(BuiltinCommand::Fg, args, _) => {
+            let id = resolve_job(&args.unwrap_or_default(), state, "fg")?;
+            let mut writer = io_ctx.stdout.as_write();
+            state.jobs.touch(id);
+            let Some(job) = state.jobs.get_mut(id) else {
+                unreachable!()
+            };
+            writeln!(writer, "{}", job.command)?;
+            writer.flush()?;
+            if let Some(job_control) = &state.job_control {
+                job_control.give_terminal(job.pgid, job.tmodes);
+            }
+            job.resume();
+            job.wait();
+            if let Some(job_control) = &state.job_control {
+                job.tmodes = job_control.reclaim_terminal();
+            }
+            if job.interrupted() {
+                let _ = writeln!(terminal());
+            }
+            let job_state = job.state();
+            status = job_state.code();
+            if job_state == ProcState::Stopped {
+                let _ = writeln!(terminal(), "\n{}", state.jobs.format(id, false));
+            } else {
+                state.jobs.remove(id);
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Bg, args, _) => {
+            let id = resolve_job(&args.unwrap_or_default(), state, "bg")?;
+            let mut writer = io_ctx.stdout.as_write();
+            let Some(job) = state.jobs.get_mut(id) else {
+                unreachable!()
+            };
+            if job.state() == ProcState::Stopped {
+                job.resume();
+                state.jobs.touch(id);
+                writeln!(writer, "{}", state.jobs.format_resumed(id))?;
+            } else {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "{}bg: job {} already in background",
+                    state.error_prefix(),
+                    id
+                )?;
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Wait, args, _) => {
+            let args = args.unwrap_or_default();
+            // 不带参数时等待所有后台作业，退出码为 0
+            let ids = if args.is_empty() {
+                state.jobs.ids()
+            } else {
+                let mut ids = Vec::new();
+                for arg in &args {
+                    let found = if arg.starts_with('%') {
+                        state.jobs.resolve(arg)
+                    } else {
+                        state.jobs.find_pid(arg.parse::<i32>()?)
+                    };
+                    match found {
+                        Some(id) => ids.push(id),
+                        None => {
+                            if arg.starts_with('%') {
+                                writeln!(
+                                    io_ctx.stderr.as_err_write(),
+                                    "{}wait: {}: no such job",
+                                    state.error_prefix(),
+                                    arg
+                                )?;
+                            } else {
+                                writeln!(
+                                    io_ctx.stderr.as_err_write(),
+                                    "{}wait: pid {} is not a child of this shell",
+                                    state.error_prefix(),
+                                    arg
+                                )?;
+                            }
+                            status = 127;
+                        }
+                    }
+                }
+                ids
+            };
+            for id in ids {
+                if let Some(job) = state.jobs.get_mut(id)
+                    && job.state() == ProcState::Running
+                {
+                    job.wait();
+                    job.changed = true;
+                    if !args.is_empty() {
+                        status = job.state().code();
+                    }
+                }
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Exec, args, _) => {
+            let mut args = args.unwrap_or_default();
+            if args.is_empty() {
+                // 只有重定向：永久改写 shell 自己的文件描述符
+                io::stdout().flush()?;
+                io::stderr().flush()?;
+                for (fd, file) in io_ctx.install()? {
+                    match file {
+                        Some(f) => state.fds.insert(fd, f),
+                        None => state.fds.remove(&fd),
+                    };
+                }
+            } else {
+                // 带命令：用它替换掉 shell 进程，重定向照样生效
+                let name = args.remove(0);
+                let path = match find_executable(&name, state).into_iter().next() {
+                    Some(path) => Some(path),
+                    // 带 / 的路径直接交给 exec，由它报不存在或没有权限
+                    None if name.contains('/') => Some(PathBuf::from(&name)),
+                    None => None,
+                };
+                status = match path {
+                    Some(path) => {
+                        let mut command = Command::new(path);
+                        command.arg0(&name).args(args);
+                        command.env_clear().envs(state.exported_vars());
+                        // 标准错误交给了 command，exec 失败时的报错要写到同一个地方
+                        let mut stderr = io_ctx.stderr.try_clone()?;
+                        setup_external(&mut command, &mut io_ctx, false);
+                        io::stdout().flush()?;
+                        // exec 成功就不会返回
+                        let err = command.exec();
+                        writeln!(
+                            stderr.as_err_write(),
+                            "{}exec: {}: {}",
+                            state.error_prefix(),
+                            name,
+                            err
+                        )?;
+                        if err.kind() == io::ErrorKind::NotFound {
+                            127
+                        } else {
+                            126
+                        }
+                    }
+                    None => {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}exec: {}: not found",
+                            state.error_prefix(),
+                            name
+                        )?;
+                        127
+                    }
+                };
+                // 和 bash 一样，非交互的 shell exec 失败就直接退出
+                if !state.interactive {
+                    exit_shell(state, status);
+                }
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Source, args, _) => {
+            let mut args = args.unwrap_or_default();
+            if args.is_empty() {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "{}source: filename argument required",
+                    state.error_prefix()
+                )?;
+                return Ok(CmdStatus::Exited(2));
+            }
+            let path = find_source_file(&args.remove(0), state);
+            // 带了参数就在执行期间替换位置参数
+            let saved = (!args.is_empty()).then(|| mem::replace(&mut state.positional, args));
+            state.source_depth += 1;
+            let result = source_file(&path, state, &io_ctx);
+            state.source_depth -= 1;
+            // 文件里的 return 只结束这个文件
+            if matches!(state.flow, Some(Flow::Return)) {
+                state.flow = None;
+            }
+            if let Some(saved) = saved {
+                state.positional = saved;
+            }
+            status = result?;
+        }
+        MskCommand::Builtin(BuiltinCommand::Type, args_opt, _) => {
             let msg = {
                 if let Some(args) = args_opt {
+                    let name = args[0].clone();
                     // match parse_command(&args[0]) {
-                    match parse_command(args, None) {
-                        None => unreachable!(),
+                    match parse_command(args, None, state) {
+                        _ if state.aliases.contains_key(&name) => {
+                            format!("{} is aliased to `{}'", name, state.aliases[&name])
+                        }
+                        _ if KEYWORDS.contains(&name.as_str()) => {
+                            format!("{} is a shell keyword", name)
+                        }
+                        Some(MskCommand::Function(function, _, _)) => {
+                            format!("{} is a function\n{}", name, function.text)
+                        }
+                        // 空的名字 (type '') 什么命令都不是
+                        None
+                        | Some(
+                            MskCommand::Compound(..)
+                            | MskCommand::Define(_)
+                            | MskCommand::Assign(..),
+                        ) => {
+                            writeln!(
+                                io_ctx.stderr.as_err_write(),
+                                "{}type: {}: not found",
+                                state.error_prefix(),
+                                name
+                            )?;
+                            return Ok(CmdStatus::Exited(1));
+                        }
                         Some(MskCommand::Builtin(command_type, _, _)) => {
                             format!("{} is a shell builtin", command_type.name())
                         }
-                        Some(MskCommand::Unknown(name)) => {
+                        Some(MskCommand::Unknown(name, _)) => {
                             format!("{}: not found", name)
                         }
                         Some(MskCommand::External(name, paths, _, _)) => {
@@ -388,40 +1689,521 @@ pub fn process_single_cmd(
                 }
             };
 
-            let mut writer = io_ctx.stdout.to_write();
-            write!(writer, "{}\n", format!("{}", &msg))?;
+            let mut writer = io_ctx.stdout.as_write();
+            writeln!(writer, "{}", &msg)?;
         }
         MskCommand::External(name, _paths, args, _) => {
             // terminal.flush();
             io_ctx.flush_stdout()?;
+            io_ctx.flush_stderr()?;
             // let _ = disable_raw_mode();
             // run_command(
             //     &name,
             //     args.as_deref(),
-            //     io_ctx.stdout.to_stdio(),
-            //     io_ctx.stderr.to_stdio(),
+            //     io_ctx.stdout.into_stdio(),
+            //     io_ctx.stderr.into_stdio(),
             // );
             let mut command = Command::new(name);
             if let Some(a) = args {
                 command.args(a);
             }
-
-            let child = command
-                .stdin(io_ctx.stdin.to_stdio())
-                .stdout(io_ctx.stdout.to_stdio())
-                .stderr(io_ctx.stderr.to_stdio())
-                .spawn()?;
+            // 子进程只能看到带 export 属性的变量
+            command.env_clear().envs(state.exported_vars());
+            if let Some(pgid) = pgroup {
+                command.process_group(pgid);
+            }
+            setup_external(&mut command, &mut io_ctx, foreground);
+            let child = command.spawn()?;
 
             // let _ = enable_raw_mode();
-            return Ok(Some(child));
+            return Ok(CmdStatus::Spawned(child));
+        }
+        MskCommand::Compound(compound, _) => {
+            status = run_compound(&compound, state, &io_ctx)?;
         }
-        MskCommand::Unknown(name) => {
-            let mut writer = io_ctx.stdout.to_write();
-            write!(writer, "{}\n", format!("{}: command not found", &name))?;
+        MskCommand::Define(function) => {
+            state.functions.insert(function.name.clone(), function);
+        }
+        MskCommand::Assign(assignments, _) => {
+            // 按顺序赋值，后面的值可以用到前面刚赋的变量
+            // 值里有命令替换时退出码是最后一个命令替换的退出码
+            for (name, value) in assignments {
+                let has_command = value.iter().any(|arg| matches!(arg, Args::Command(..)));
+                let value = consolidate_args(value, state)?;
+                if state.options.xtrace {
+                    trace(&[format!("{}={}", name, quote_trace(&value))], state);
+                }
+                state.set_var(&name, &value).map_err(ExpansionError::from)?;
+                if has_command {
+                    status = state.last_status;
+                }
+            }
+        }
+        MskCommand::Function(function, args, _) => {
+            status = call_function(&function, args, state, &mut io_ctx)?;
+        }
+        MskCommand::Builtin(BuiltinCommand::Local, args, _) => {
+            let Some(frame) = state.locals.last() else {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "{}local: can only be used in a function",
+                    state.error_prefix()
+                )?;
+                return Ok(CmdStatus::Exited(1));
+            };
+            let args = args.unwrap_or_default();
+            // 不带参数时列出当前函数的 local 变量
+            if args.is_empty() {
+                let mut names: Vec<&String> = frame.keys().collect();
+                names.sort();
+                let mut writer = io_ctx.stdout.as_write();
+                for name in names {
+                    let value = state.get_var(name).unwrap_or_default();
+                    writeln!(writer, "{}={}", name, value)?;
+                }
+                return Ok(CmdStatus::Exited(0));
+            }
+            for arg in args {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !is_valid_name(name) {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}local: `{}': not a valid identifier",
+                        state.error_prefix(),
+                        arg
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                // 同一个函数里重复声明时保留最早的值
+                let Some(frame) = state.locals.last_mut() else {
+                    unreachable!()
+                };
+                if !frame.contains_key(name) {
+                    frame.insert(name.to_string(), state.vars.get(name).cloned());
+                }
+                if state.vars.get(name).is_some_and(|var| var.readonly) {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}local: {}: readonly variable",
+                        state.error_prefix(),
+                        name
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                // 局部变量是一个新变量，不继承外层的属性；没给值时为空，不再看到外层的值
+                let var = Variable {
+                    value: Some(value.unwrap_or("").to_string()),
+                    ..Variable::default()
+                };
+                state.vars.insert(name.to_string(), var);
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Alias, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let mut args = args.unwrap_or_default().into_iter().peekable();
+            // -p 或者不带参数时以可复用的形式列出所有别名
+            let mut print_all = args.peek().is_none();
+            while let Some(flag) = args.next_if(|a| a.starts_with('-') && a.len() > 1) {
+                if flag == "--" {
+                    break;
+                }
+                if flag != "-p" {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}alias: {}: invalid option",
+                        state.error_prefix(),
+                        flag
+                    )?;
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "alias: usage: alias [-p] [name[=value] ... ]"
+                    )?;
+                    return Ok(CmdStatus::Exited(2));
+                }
+                print_all = true;
+            }
+            if print_all {
+                for (name, value) in &state.aliases {
+                    writeln!(writer, "alias {}={}", name, quote_alias(value))?;
+                }
+            }
+            for arg in args {
+                match arg.split_once('=') {
+                    Some((name, value)) => {
+                        if !is_valid_alias_name(name) {
+                            writeln!(
+                                io_ctx.stderr.as_err_write(),
+                                "{}alias: `{}': invalid alias name",
+                                state.error_prefix(),
+                                name
+                            )?;
+                            status = 1;
+                            continue;
+                        }
+                        state.aliases.insert(name.to_string(), value.to_string());
+                    }
+                    // 只有名字时显示这个别名
+                    None => match state.aliases.get(&arg) {
+                        Some(value) => writeln!(writer, "alias {}={}", arg, quote_alias(value))?,
+                        None => {
+                            writeln!(
+                                io_ctx.stderr.as_err_write(),
+                                "{}alias: {}: not found",
+                                state.error_prefix(),
+                                arg
+                            )?;
+                            status = 1;
+                        }
+                    },
+                }
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Unalias, args, _) => {
+            let args = args.unwrap_or_default();
+            if args.is_empty() {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "unalias: usage: unalias [-a] name [name ...]"
+                )?;
+                return Ok(CmdStatus::Exited(2));
+            }
+            // -a 删除所有别名
+            if args[0] == "-a" {
+                state.aliases.clear();
+                return Ok(CmdStatus::Exited(0));
+            }
+            for name in args {
+                if state.aliases.remove(&name).is_none() {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}unalias: {}: not found",
+                        state.error_prefix(),
+                        name
+                    )?;
+                    status = 1;
+                }
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Export, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let mut args = args.unwrap_or_default().into_iter().peekable();
+            // -p 或者不带参数时列出所有导出的变量，-n 去掉导出属性
+            let mut print_all = args.peek().is_none();
+            let mut unexport = false;
+            while let Some(flag) = args.next_if(|a| a.starts_with('-') && a.len() > 1) {
+                if flag == "--" {
+                    break;
+                }
+                for c in flag[1..].chars() {
+                    match c {
+                        'p' => print_all = true,
+                        'n' => unexport = true,
+                        _ => {
+                            writeln!(
+                                io_ctx.stderr.as_err_write(),
+                                "{}export: -{}: invalid option",
+                                state.error_prefix(),
+                                c
+                            )?;
+                            writeln!(
+                                io_ctx.stderr.as_err_write(),
+                                "export: usage: export [-n] [-p] [name[=value] ...]"
+                            )?;
+                            return Ok(CmdStatus::Exited(2));
+                        }
+                    }
+                }
+            }
+            if print_all {
+                print_declared(&mut writer, state, |var| var.exported)?;
+            }
+            for arg in args {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !is_valid_name(name) {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}export: `{}': not a valid identifier",
+                        state.error_prefix(),
+                        arg
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                if let Some(value) = value
+                    && let Err(e) = state.set_var(name, value)
+                {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}export: {}",
+                        state.error_prefix(),
+                        e
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                // 只有名字的变量也会被标记，之后赋值时自动导出
+                state.vars.entry(name.to_string()).or_default().exported = !unexport;
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Readonly, args, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let mut args = args.unwrap_or_default().into_iter().peekable();
+            let mut print_all = args.peek().is_none();
+            while let Some(flag) = args.next_if(|a| a.starts_with('-') && a.len() > 1) {
+                if flag == "--" {
+                    break;
+                }
+                if flag != "-p" {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}readonly: {}: invalid option",
+                        state.error_prefix(),
+                        flag
+                    )?;
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "readonly: usage: readonly [-p] [name[=value] ...]"
+                    )?;
+                    return Ok(CmdStatus::Exited(2));
+                }
+                print_all = true;
+            }
+            if print_all {
+                print_declared(&mut writer, state, |var| var.readonly)?;
+            }
+            for arg in args {
+                let (name, value) = match arg.split_once('=') {
+                    Some((name, value)) => (name, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                if !is_valid_name(name) {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}readonly: `{}': not a valid identifier",
+                        state.error_prefix(),
+                        arg
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                if let Some(value) = value
+                    && let Err(e) = state.set_var(name, value)
+                {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}readonly: {}",
+                        state.error_prefix(),
+                        e
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                state.vars.entry(name.to_string()).or_default().readonly = true;
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Unset, args, _) => {
+            let mut args = args.unwrap_or_default().into_iter().peekable();
+            // -f 删除函数，-v 只删除变量；都不给时没有这个变量就删除同名函数
+            let mut functions = false;
+            let mut variables = false;
+            while let Some(flag) = args.next_if(|a| a.starts_with('-') && a.len() > 1) {
+                match flag.as_str() {
+                    "--" => break,
+                    "-f" => functions = true,
+                    "-v" => variables = true,
+                    _ => {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}unset: {}: invalid option",
+                            state.error_prefix(),
+                            flag
+                        )?;
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "unset: usage: unset [-f] [-v] [name ...]"
+                        )?;
+                        return Ok(CmdStatus::Exited(2));
+                    }
+                }
+            }
+            for name in args {
+                if functions && !variables {
+                    state.functions.remove(&name);
+                    continue;
+                }
+                if !is_valid_name(&name) {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}unset: `{}': not a valid identifier",
+                        state.error_prefix(),
+                        name
+                    )?;
+                    status = 1;
+                    continue;
+                }
+                match state.vars.get(&name) {
+                    Some(var) if var.readonly => {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}unset: {}: cannot unset: readonly variable",
+                            state.error_prefix(),
+                            name
+                        )?;
+                        status = 1;
+                    }
+                    Some(_) => {
+                        state.vars.remove(&name);
+                    }
+                    None if state.arrays.remove(&name).is_some() => {}
+                    None if !variables => {
+                        state.functions.remove(&name);
+                    }
+                    None => {}
+                }
+            }
+        }
+        MskCommand::Builtin(
+            command @ (BuiltinCommand::Test | BuiltinCommand::Bracket),
+            args,
+            _,
+        ) => {
+            let mut args = args.unwrap_or_default();
+            let name = command.name();
+            // [ 必须以 ] 结尾
+            if let BuiltinCommand::Bracket = command
+                && args.pop_if(|last| last == "]").is_none()
+            {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "{}[: missing `]'",
+                    state.error_prefix()
+                )?;
+                return Ok(CmdStatus::Exited(2));
+            }
+            status = match eval_test(&args) {
+                Ok(true) => 0,
+                Ok(false) => 1,
+                Err(e) => {
+                    writeln!(
+                        io_ctx.stderr.as_err_write(),
+                        "{}{}: {}",
+                        state.error_prefix(),
+                        name,
+                        e
+                    )?;
+                    2
+                }
+            };
+        }
+        MskCommand::Builtin(BuiltinCommand::Env, _, _) => {
+            let mut writer = io_ctx.stdout.as_write();
+            let mut vars: Vec<_> = state.exported_vars().collect();
+            vars.sort();
+            for (name, value) in vars {
+                writeln!(writer, "{}={}", name, value)?;
+            }
+        }
+        MskCommand::Builtin(BuiltinCommand::Return, args, _) => {
+            if state.locals.is_empty() && state.source_depth == 0 {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "{}return: can only `return' from a function or sourced script",
+                    state.error_prefix()
+                )?;
+                return Ok(CmdStatus::Exited(1));
+            }
+            // 不带参数时返回上一条命令的退出码，退出码只保留低 8 位
+            status = match args.unwrap_or_default().first() {
+                Some(n) => match n.parse::<i64>() {
+                    Ok(code) => code.rem_euclid(256) as i32,
+                    Err(_) => {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}return: {}: numeric argument required",
+                            state.error_prefix(),
+                            n
+                        )?;
+                        2
+                    }
+                },
+                None => state.last_status,
+            };
+            state.flow = Some(Flow::Return);
+        }
+        MskCommand::Builtin(
+            builtin @ (BuiltinCommand::Break | BuiltinCommand::Continue),
+            args,
+            _,
+        ) => {
+            let name = builtin.name();
+            // 不带参数时只跳出最内层的循环
+            let count = match args.unwrap_or_default().first() {
+                Some(n) => match n.parse::<i64>() {
+                    Ok(count) if count >= 1 => count as usize,
+                    Ok(_) => {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}{}: {}: loop count out of range",
+                            state.error_prefix(),
+                            name,
+                            n
+                        )?;
+                        return Ok(CmdStatus::Exited(1));
+                    }
+                    Err(_) => {
+                        writeln!(
+                            io_ctx.stderr.as_err_write(),
+                            "{}{}: {}: numeric argument required",
+                            state.error_prefix(),
+                            name,
+                            n
+                        )?;
+                        return Ok(CmdStatus::Exited(1));
+                    }
+                },
+                None => 1,
+            };
+            if state.loop_depth == 0 {
+                writeln!(
+                    io_ctx.stderr.as_err_write(),
+                    "{}{}: only meaningful in a `for', `while', or `until' loop",
+                    state.error_prefix(),
+                    name
+                )?;
+            } else {
+                // 层数超过实际的循环层数时跳出所有循环
+                let count = count.min(state.loop_depth);
+                state.flow = Some(match builtin {
+                    BuiltinCommand::Break => Flow::Break(count),
+                    _ => Flow::Continue(count),
+                });
+            }
+        }
+        MskCommand::Unknown(name, _) => {
+            // 交互时和原来一样不带前缀，脚本里带上文件名和行号
+            let prefix = match state.location {
+                Some(_) => state.error_prefix(),
+                None => String::new(),
+            };
+            writeln!(
+                io_ctx.stderr.as_err_write(),
+                "{}{}: command not found",
+                prefix,
+                &name
+            )?;
+            status = 127;
         }
     }
     // }
-    Ok(None)
+    Ok(CmdStatus::Exited(status))
 }
 
 // pub fn run_command(executable_file: &str, args_opt: Option<&[String]>, out: Stdio, err: Stdio) {
@@ -449,6 +2231,92 @@ pub fn process_single_cmd(
 //         }
 //     }
 // }
+// set -x：把展开后的命令加上 PS4 打印到 stderr
+fn trace_command(cmd: &MskCommand, assignments: &[String], state: &ShellState) {
+    let (name, args) = match cmd {
+        MskCommand::Builtin(builtin, args, _) => (builtin.name(), args.as_deref()),
+        MskCommand::External(name, _, args, _) => (name.as_str(), args.as_deref()),
+        MskCommand::Function(function, args, _) => (function.name.as_str(), Some(&args[..])),
+        MskCommand::Unknown(name, _) => (name.as_str(), None),
+        // 复合命令里的命令执行时各自打印
+        MskCommand::Compound(..) | MskCommand::Define(_) | MskCommand::Assign(..) => return,
+    };
+    let mut words = assignments.to_vec();
+    words.push(quote_trace(name));
+    words.extend(args.unwrap_or_default().iter().map(|arg| quote_trace(arg)));
+    trace(&words, state);
+}
+fn trace(words: &[String], state: &ShellState) {
+    let ps4 = state.get_var("PS4").unwrap_or_else(|| "+ ".to_string());
+    eprintln!("{}{}", ps4, words.join(" "));
+}
+// 含有特殊字符的参数加上单引号，让打印出来的命令可以直接复制执行
+fn quote_trace(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:,+=@%^".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        quote_alias(word)
+    }
+}
+// export -p / readonly -p 的输出，按名字排序，可以直接作为命令再执行
+fn print_declared(
+    writer: &mut impl Write,
+    state: &ShellState,
+    filter: impl Fn(&Variable) -> bool,
+) -> io::Result<()> {
+    let mut vars: Vec<_> = state.vars.iter().filter(|(_, var)| filter(var)).collect();
+    vars.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, var) in vars {
+        let mut flags = String::from("-");
+        if var.readonly {
+            flags.push('r');
+        }
+        if var.exported {
+            flags.push('x');
+        }
+        match &var.value {
+            Some(value) => {
+                let mut quoted = String::new();
+                for c in value.chars() {
+                    if matches!(c, '"' | '\\' | '$' | '`') {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c);
+                }
+                writeln!(writer, "declare {} {}=\"{}\"", flags, name, quoted)?;
+            }
+            None => writeln!(writer, "declare {} {}", flags, name)?,
+        }
+    }
+    Ok(())
+}
+// 别名的值用单引号括起来显示，里面的单引号写成 '\''
+fn quote_alias(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+// 别名里不能有引号、$、/、= 和空白这些会影响解析的字符
+fn is_valid_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || "'\"\\`$/=<>|&;()".contains(c))
+}
+// fg/bg 的作业参数，省略时取当前作业，也可以直接写作业号
+fn resolve_job(args: &[String], state: &ShellState, name: &str) -> Result<usize, ProcessCmdError> {
+    let spec = match args.first() {
+        Some(arg) if arg.starts_with('%') => arg.clone(),
+        Some(arg) => format!("%{}", arg),
+        None => "%+".to_string(),
+    };
+    state.jobs.resolve(&spec).ok_or_else(|| {
+        let shown = args.first().map_or("current", |a| a.as_str());
+        ProcessCmdError::ArgsError(format!("{}: {}: no such job", name, shown))
+    })
+}
 pub fn is_executable(path: &std::path::Path) -> bool {
     // 第一步：如果文件根本不存在，直接返回 false
     let metadata = match fs::metadata(path) {
@@ -467,14 +2335,14 @@ pub fn is_executable(path: &std::path::Path) -> bool {
         use std::os::unix::fs::PermissionsExt;
         // 检查模式位：只要 owner, group, other 任何一方有执行权限(0o111)，就算可执行
         // 如果想严谨一点，只检查 owner (0o100) 也可以
-        return metadata.permissions().mode() & 0o111 != 0;
+        metadata.permissions().mode() & 0o111 != 0
     }
 
     #[cfg(windows)]
     {
         let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
         let exec_exts = ["exe", "bat", "cmd", "com", "ps1", "msi"];
-        return exec_exts.contains(&ext.to_lowercase().as_str());
+        exec_exts.contains(&ext.to_lowercase().as_str())
     }
 
     // 对于其他非常见系统，默认返回 false
@@ -483,3 +2351,71 @@ pub fn is_executable(path: &std::path::Path) -> bool {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 逐行检查的结果要和每次解析整段输入的结果一致
+    #[test]
+    fn input_buffer_matches_full_parse() {
+        let scripts = [
+            "echo a",
+            "echo 'a\nb'",
+            "echo \"$(echo\n)\"",
+            "echo a \\\nb",
+            "a |\nb",
+            "a &&\n\nb",
+            "if true\nthen\necho\nfi",
+            "if true; then if false; then :; fi\nfi; echo",
+            "while read x; do\necho $x\ndone < f",
+            "until false\ndo break; done",
+            "for x in if fi\ndo\necho $x\ndone",
+            "for x\ndo :; done",
+            "for ((i = 0; i < 2; i++)); do\necho\ndone",
+            "case $x in\na) echo if;;\n(b|esac) if true; then :; fi ;&\n*)\nesac",
+            "case x\nin\nx) echo\nesac",
+            "f() {\necho\n}",
+            "f()\n{\n:\n}",
+            "function f {\nif :; then :; fi\n}",
+            "function f() {\n:\n}",
+            "[[ a &&\nb ]]",
+            "[[ ( a ) ]] && {\necho\n}",
+            "cat <<EOF\n$x\nif\nEOF",
+            "cat <<-EOF | wc\n\tx\n\tEOF\nwc",
+            "cat <<A <<'B'\na\nA\nb\nB",
+            "echo if then fi; echo }",
+            "{ echo; }",
+            "echo done",
+            "if true; then fi",
+            "fi",
+            "done\necho",
+            "echo >\nfile",
+            "a ;; b",
+            "while true; then\necho\nfi",
+            "echo ${a\n}",
+            "if then\nfi",
+            "{ }",
+            "while :; do done",
+            "if :; then :; else\nfi",
+            "if :; then :; else :\nfi",
+            "if :; do :; done; fi",
+        ];
+        for script in scripts {
+            let mut buffer = InputBuffer::new();
+            let mut text = String::new();
+            for line in script.split('\n') {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(line);
+                assert_eq!(
+                    buffer.push_line(line),
+                    needs_more_input(&text),
+                    "{:?}",
+                    text
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use std::ffi::CString;
+use std::fs::{self, Metadata};
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::time::SystemTime;
+
+// test / [ 和 [[ ]] 共用的条件判断
+
+// test 的参数错误，退出码是 2
+#[derive(Debug, thiserror::Error)]
+pub enum TestError {
+    #[error("{0}: unary operator expected")]
+    UnaryExpected(String),
+    #[error("{0}: binary operator expected")]
+    BinaryExpected(String),
+    #[error("{0}: integer expression expected")]
+    IntegerExpected(String),
+    #[error("`)' expected")]
+    ParenExpected,
+    #[error("argument expected")]
+    ArgumentExpected,
+    #[error("too many arguments")]
+    TooManyArgs,
+    #[error("{0}: invalid regular expression")]
+    BadRegex(String),
+}
+
+pub fn is_unary_op(op: &str) -> bool {
+    matches!(
+        op,
+        "-e" | "-f"
+            | "-d"
+            | "-r"
+            | "-w"
+            | "-x"
+            | "-s"
+            | "-L"
+            | "-h"
+            | "-b"
+            | "-c"
+            | "-p"
+            | "-S"
+            | "-z"
+            | "-n"
+    )
+}
+
+pub fn is_binary_op(op: &str) -> bool {
+    matches!(
+        op,
+        "=" | "=="
+            | "!="
+            | "<"
+            | ">"
+            | "-eq"
+            | "-ne"
+            | "-lt"
+            | "-le"
+            | "-gt"
+            | "-ge"
+            | "-nt"
+            | "-ot"
+    )
+}
+
+pub fn is_integer_op(op: &str) -> bool {
+    matches!(op, "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge")
+}
+
+// -f file 这类单目测试，op 必须满足 is_unary_op
+pub fn unary_test(op: &str, operand: &str) -> bool {
+    match op {
+        "-z" => operand.is_empty(),
+        "-n" => !operand.is_empty(),
+        // 符号链接本身，不跟随
+        "-L" | "-h" => fs::symlink_metadata(operand).is_ok_and(|m| m.file_type().is_symlink()),
+        "-r" => access(operand, libc::R_OK),
+        "-w" => access(operand, libc::W_OK),
+        "-x" => access(operand, libc::X_OK),
+        _ => {
+            let Ok(meta) = fs::metadata(operand) else {
+                return false;
+            };
+            let file_type = meta.file_type();
+            match op {
+                "-e" => true,
+                "-f" => file_type.is_file(),
+                "-d" => file_type.is_dir(),
+                "-s" => meta.len() > 0,
+                "-b" => file_type.is_block_device(),
+                "-c" => file_type.is_char_device(),
+                "-p" => file_type.is_fifo(),
+                "-S" => file_type.is_socket(),
+                _ => false,
+            }
+        }
+    }
+}
+
+// 权限按当前用户实际能否访问来判断，和 test 一样交给 access(2)
+fn access(path: &str, mode: libc::c_int) -> bool {
+    match CString::new(path) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), mode) == 0 },
+        Err(_) => false,
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m: Metadata| m.modified()).ok()
+}
+
+fn parse_integer(s: &str) -> Result<i64, TestError> {
+    s.trim()
+        .parse()
+        .map_err(|_| TestError::IntegerExpected(s.to_string()))
+}
+
+// a = b 这类双目测试，op 必须满足 is_binary_op
+pub fn binary_test(left: &str, op: &str, right: &str) -> Result<bool, TestError> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        // 文件不存在时，存在的那个算新
+        "-nt" => match (modified(left), modified(right)) {
+            (Some(l), Some(r)) => l > r,
+            (l, _) => l.is_some(),
+        },
+        "-ot" => match (modified(left), modified(right)) {
+            (Some(l), Some(r)) => l < r,
+            (_, r) => r.is_some(),
+        },
+        _ => {
+            let l = parse_integer(left)?;
+            let r = parse_integer(right)?;
+            match op {
+                "-eq" => l == r,
+                "-ne" => l != r,
+                "-lt" => l < r,
+                "-le" => l <= r,
+                "-gt" => l > r,
+                _ => l >= r,
+            }
+        }
+    })
+}
+
+// test 内建命令：参数不超过 4 个时按 POSIX 规定的参数个数来判断，
+// 这样 [ "$x" = "!" ] 这类参数碰巧长得像操作符的情况也能得到正确结果
+pub fn eval_test(args: &[String]) -> Result<bool, TestError> {
+    match args {
+        [] => Ok(false),
+        [arg] => Ok(!arg.is_empty()),
+        [_, _] => two_args(args),
+        [_, _, _] => three_args(args),
+        [not, rest @ ..] if args.len() == 4 && not == "!" => three_args(rest).map(|r| !r),
+        [open, inner @ .., close] if args.len() == 4 && open == "(" && close == ")" => {
+            two_args(inner)
+        }
+        _ => {
+            let mut parser = TestParser { args, pos: 0 };
+            let result = parser.parse_or()?;
+            if parser.pos < args.len() {
+                return Err(TestError::TooManyArgs);
+            }
+            Ok(result)
+        }
+    }
+}
+
+fn two_args(args: &[String]) -> Result<bool, TestError> {
+    let (first, second) = (args[0].as_str(), args[1].as_str());
+    if first == "!" {
+        Ok(second.is_empty())
+    } else if is_unary_op(first) {
+        Ok(unary_test(first, second))
+    } else {
+        Err(TestError::UnaryExpected(first.to_string()))
+    }
+}
+
+fn three_args(args: &[String]) -> Result<bool, TestError> {
+    let (first, op, last) = (args[0].as_str(), args[1].as_str(), args[2].as_str());
+    if is_binary_op(op) {
+        binary_test(first, op, last)
+    } else if op == "-a" {
+        Ok(!first.is_empty() && !last.is_empty())
+    } else if op == "-o" {
+        Ok(!first.is_empty() || !last.is_empty())
+    } else if first == "!" {
+        two_args(&args[1..]).map(|r| !r)
+    } else if first == "(" && last == ")" {
+        Ok(!op.is_empty())
+    } else {
+        Err(TestError::BinaryExpected(op.to_string()))
+    }
+}
+
+// 参数更多时按优先级解析：-o 最低，然后是 -a、!，最后是括号和单个测试
+struct TestParser<'a> {
+    args: &'a [String],
+    pos: usize,
+}
+
+impl<'a> TestParser<'a> {
+    fn peek(&self, offset: usize) -> Option<&'a str> {
+        self.args.get(self.pos + offset).map(|s| s.as_str())
+    }
+
+    fn eat(&mut self, word: &str) -> bool {
+        if self.peek(0) == Some(word) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<bool, TestError> {
+        let mut result = self.parse_and()?;
+        while self.eat("-o") {
+            let right = self.parse_and()?;
+            result = result || right;
+        }
+        Ok(result)
+    }
+
+    fn parse_and(&mut self) -> Result<bool, TestError> {
+        let mut result = self.parse_not()?;
+        while self.eat("-a") {
+            let right = self.parse_not()?;
+            result = result && right;
+        }
+        Ok(result)
+    }
+
+    fn parse_not(&mut self) -> Result<bool, TestError> {
+        if self.eat("!") {
+            return self.parse_not().map(|r| !r);
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<bool, TestError> {
+        let Some(first) = self.peek(0) else {
+            return Err(TestError::ArgumentExpected);
+        };
+        if first == "(" {
+            self.pos += 1;
+            let result = self.parse_or()?;
+            if !self.eat(")") {
+                return Err(TestError::ParenExpected);
+            }
+            return Ok(result);
+        }
+        if let Some(op) = self.peek(1)
+            && is_binary_op(op)
+            && let Some(right) = self.peek(2)
+        {
+            let result = binary_test(first, op, right)?;
+            self.pos += 3;
+            return Ok(result);
+        }
+        if is_unary_op(first)
+            && let Some(operand) = self.peek(1)
+        {
+            let result = unary_test(first, operand);
+            self.pos += 2;
+            return Ok(result);
+        }
+        self.pos += 1;
+        Ok(!first.is_empty())
+    }
+}
+
+// 正则里的特殊字符加上反斜杠，按字面匹配
+pub fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.[]()*+?{}|^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// [[ =~ ]]：POSIX 扩展正则，和 bash 一样交给 libc 的 regcomp/regexec
+// 匹配时返回整体和各个分组匹配到的内容，没参与匹配的分组是空串
+pub fn regex_match(pattern: &str, text: &str) -> Result<Option<Vec<String>>, TestError> {
+    let bad = || TestError::BadRegex(pattern.to_string());
+    let c_pattern = CString::new(pattern).map_err(|_| bad())?;
+    let Ok(c_text) = CString::new(text) else {
+        return Ok(None);
+    };
+    let matches = unsafe {
+        let mut regex: libc::regex_t = mem::zeroed();
+        if libc::regcomp(&mut regex, c_pattern.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(bad());
+        }
+        // 分组个数以 regcomp 的结果为准，每个分组一个位置，再加上整体匹配
+        let empty = libc::regmatch_t {
+            rm_so: -1,
+            rm_eo: -1,
+        };
+        let mut matches = vec![empty; group_count(&regex) + 1];
+        let rc = libc::regexec(
+            &regex,
+            c_text.as_ptr(),
+            matches.len(),
+            matches.as_mut_ptr(),
+            0,
+        );
+        libc::regfree(&mut regex);
+        if rc != 0 {
+            return Ok(None);
+        }
+        matches
+    };
+    let bytes = text.as_bytes();
+    Ok(Some(
+        matches
+            .iter()
+            .map(|m| {
+                if m.rm_so < 0 {
+                    String::new()
+                } else {
+                    String::from_utf8_lossy(&bytes[m.rm_so as usize..m.rm_eo as usize]).into_owned()
+                }
+            })
+            .collect(),
+    ))
+}
+
+// regcomp 之后的 re_nsub，即正则里分组的个数
+// libc crate 没有公开这个字段，只能按各个 C 库里 regex_t 的布局读出来
+fn group_count(regex: &libc::regex_t) -> usize {
+    let words = regex as *const libc::regex_t as *const libc::size_t;
+    // glibc：前面是 buffer、allocated、used、syntax、fastmap、translate 六个字长的字段
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    let index = 6;
+    // musl：re_nsub 是第一个字段
+    #[cfg(all(target_os = "linux", target_env = "musl"))]
+    let index = 0;
+    // BSD 和 macOS：int re_magic 之后按字长对齐
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    ))]
+    let index = 1;
+    #[cfg(not(any(
+        all(target_os = "linux", any(target_env = "gnu", target_env = "musl")),
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly"
+    )))]
+    compile_error!("regex_t layout unknown on this target");
+    unsafe { *words.add(index) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(args: &[&str]) -> Result<bool, TestError> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        eval_test(&args)
+    }
+
+    #[test]
+    fn posix_argument_counts() {
+        assert!(!test(&[]).unwrap());
+        assert!(test(&["x"]).unwrap());
+        assert!(!test(&[""]).unwrap());
+        // 单个参数即使长得像操作符也只看是否为空
+        assert!(test(&["-n"]).unwrap());
+        assert!(test(&["!"]).unwrap());
+        assert!(test(&["!", ""]).unwrap());
+        assert!(!test(&["-z", "x"]).unwrap());
+        assert!(matches!(
+            test(&["x", "y"]),
+            Err(TestError::UnaryExpected(_))
+        ));
+        assert!(test(&["!", "=", "!"]).unwrap());
+        assert!(test(&["(", "-n", ")"]).unwrap());
+        assert!(!test(&["!", "-n", "x"]).unwrap());
+        assert!(matches!(
+            test(&["a", "b", "c"]),
+            Err(TestError::BinaryExpected(_))
+        ));
+        assert!(test(&["!", "a", "=", "b"]).unwrap());
+        assert!(test(&["(", "-z", "", ")"]).unwrap());
+    }
+
+    #[test]
+    fn operator_precedence() {
+        // -a 比 -o 优先：a -o (b -a c)
+        assert!(test(&["x", "-o", "", "-a", ""]).unwrap());
+        assert!(!test(&["", "-a", "x", "-o", ""]).unwrap());
+        // ! 只作用于紧跟的一项
+        assert!(test(&["!", "", "-a", "x"]).unwrap());
+        assert!(!test(&["!", "(", "x", "-o", "", ")"]).unwrap());
+        assert!(!test(&["(", "x", "-o", "", ")", "-a", ""]).unwrap());
+        assert!(matches!(
+            test(&["(", "x", "-a", "y"]),
+            Err(TestError::ParenExpected)
+        ));
+        assert!(matches!(
+            test(&["x", "-a"]),
+            Err(TestError::UnaryExpected(_))
+        ));
+        assert!(matches!(
+            test(&["x", "-a", "y", "-o"]),
+            Err(TestError::ArgumentExpected)
+        ));
+        assert!(matches!(
+            test(&["a", "=", "a", "b"]),
+            Err(TestError::TooManyArgs)
+        ));
+    }
+
+    #[test]
+    fn integer_comparisons() {
+        assert!(test(&["10", "-gt", "9"]).unwrap());
+        assert!(test(&[" 3 ", "-eq", "3"]).unwrap());
+        assert!(test(&["-1", "-lt", "0"]).unwrap());
+        // 字符串比较按字典序
+        assert!(test(&["10", "<", "9"]).unwrap());
+        assert!(matches!(
+            test(&["abc", "-eq", "1"]),
+            Err(TestError::IntegerExpected(s)) if s == "abc"
+        ));
+        assert!(matches!(
+            test(&["1", "-le", ""]),
+            Err(TestError::IntegerExpected(s)) if s.is_empty()
+        ));
+    }
+
+    #[test]
+    fn regex_groups() {
+        let groups = regex_match("^([a-z]+)([0-9]+)(x)?(bar)$", "foo123bar")
+            .unwrap()
+            .unwrap();
+        assert_eq!(groups, ["foo123bar", "foo", "123", "", "bar"]);
+        // 方括号里的括号不算分组
+        let groups = regex_match("[(]([[:alpha:]]+)[)]([^)]*)", "(ab)c)")
+            .unwrap()
+            .unwrap();
+        assert_eq!(groups, ["(ab)c", "ab", "c"]);
+        let groups = regex_match("^[]a]+$", "]a]").unwrap().unwrap();
+        assert_eq!(groups.len(), 1);
+        assert!(regex_match("^a$", "b").unwrap().is_none());
+        assert!(matches!(regex_match("(", "x"), Err(TestError::BadRegex(_))));
+    }
+
+    #[test]
+    fn escaped_regex_is_literal() {
+        let pattern = escape_regex("a.b(c)");
+        assert!(regex_match(&pattern, "a.b(c)").unwrap().is_some());
+        assert!(regex_match(&pattern, "axbc").unwrap().is_none());
+    }
+}
@@ -0,0 +1,106 @@
+// 文件名展开：把 *.rs、src/*/mod.rs、**/*.toml 这样的模式展开成实际存在的路径
+
+use std::fs;
+use std::path::Path;
+
+use crate::pattern::{has_glob_chars, pattern_matches, unescape_pattern};
+use crate::state::ShellOptions;
+
+// 返回排好序的匹配结果，没有匹配时返回空列表，由调用方决定怎么处理
+pub fn glob_expand(pattern: &str, options: &ShellOptions) -> Vec<String> {
+    let absolute = pattern.starts_with('/');
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    // 以 / 结尾的模式只匹配目录
+    let dirs_only = pattern.ends_with('/');
+
+    let mut paths = vec![if absolute {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+    for (i, component) in components.iter().enumerate() {
+        let is_last = i == components.len() - 1;
+        let mut next = Vec::new();
+        for base in &paths {
+            if *component == "**" && options.globstar {
+                // ** 匹配零层或多层目录，最后一段时还要匹配其中的文件
+                if !is_last {
+                    next.push(base.clone());
+                }
+                walk_recursive(base, options, is_last, &mut next);
+            } else if !has_glob_chars(component) {
+                // 普通的一段路径直接拼上去，最后统一检查是否存在
+                next.push(join(base, &unescape_pattern(component)));
+            } else {
+                for name in read_names(base) {
+                    if is_hidden_skipped(&name, component, options) {
+                        continue;
+                    }
+                    if pattern_matches(component, &name) {
+                        next.push(join(base, &name));
+                    }
+                }
+            }
+        }
+        // 中间的每一段都必须是目录
+        if !is_last {
+            next.retain(|p| p.is_empty() || Path::new(p).is_dir());
+        }
+        paths = next;
+    }
+
+    let mut matches: Vec<String> = paths
+        .into_iter()
+        .filter(|p| !p.is_empty() && fs::symlink_metadata(p).is_ok())
+        .filter(|p| !dirs_only || Path::new(p).is_dir())
+        .map(|p| if dirs_only { format!("{}/", p) } else { p })
+        .collect();
+    matches.sort();
+    matches.dedup();
+    matches
+}
+
+fn join(base: &str, name: &str) -> String {
+    if base.is_empty() {
+        name.to_string()
+    } else if base.ends_with('/') {
+        format!("{}{}", base, name)
+    } else {
+        format!("{}/{}", base, name)
+    }
+}
+
+fn read_names(base: &str) -> Vec<String> {
+    let dir = if base.is_empty() { "." } else { base };
+    match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// . 开头的文件只有在模式也以 . 开头或者打开了 dotglob 时才参与匹配
+fn is_hidden_skipped(name: &str, component: &str, options: &ShellOptions) -> bool {
+    name.starts_with('.') && !component.starts_with('.') && !options.dotglob
+}
+
+// ** 的递归遍历，不跟随符号链接，避免死循环
+fn walk_recursive(base: &str, options: &ShellOptions, include_files: bool, out: &mut Vec<String>) {
+    for name in read_names(base) {
+        if name.starts_with('.') && !options.dotglob {
+            continue;
+        }
+        let path = join(base, &name);
+        let is_dir = fs::symlink_metadata(&path)
+            .map(|m| m.is_dir())
+            .unwrap_or(false);
+        if is_dir {
+            out.push(path.clone());
+            walk_recursive(&path, options, include_files, out);
+        } else if include_files {
+            out.push(path);
+        }
+    }
+}
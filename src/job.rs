@@ -0,0 +1,452 @@
+// 作业控制：记录后台运行和被暂停的管道，按 bash 的格式报告它们的状态
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// 交互式 shell 自己忽略、子进程里要恢复成默认处理的信号
+const JOB_CONTROL_SIGNALS: [i32; 4] = [libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+// 执行内建命令时 shell 在前台，Ctrl-C 不能把 shell 杀掉，但要能打断只由内建命令组成的循环
+// 所以 SIGINT 不忽略，只记下收到过，由执行命令的地方检查
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// 上次检查之后是否收到过 SIGINT
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+// 作业控制需要的终端信息，只有标准输入是终端时才启用
+pub struct JobControl {
+    pub shell_pgid: i32,
+    // shell 自己的终端设置，作业结束或暂停后恢复
+    shell_tmodes: libc::termios,
+}
+
+impl JobControl {
+    pub fn init() -> Option<Self> {
+        unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return None;
+            }
+            // 在后台被启动时先等自己回到前台，否则一读终端就会被暂停
+            loop {
+                let pgid = libc::getpgrp();
+                if libc::tcgetpgrp(libc::STDIN_FILENO) == pgid {
+                    break;
+                }
+                libc::kill(-pgid, libc::SIGTTIN);
+            }
+            for sig in JOB_CONTROL_SIGNALS {
+                libc::signal(sig, libc::SIG_IGN);
+            }
+            libc::signal(
+                libc::SIGINT,
+                on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+            // 自己单独成为一个进程组并占有终端 (已经是会话首进程时 setpgid 会失败，不影响)
+            let pid = libc::getpid();
+            libc::setpgid(pid, pid);
+            let shell_pgid = libc::getpgrp();
+            libc::tcsetpgrp(libc::STDIN_FILENO, shell_pgid);
+            let mut shell_tmodes: libc::termios = mem::zeroed();
+            libc::tcgetattr(libc::STDIN_FILENO, &mut shell_tmodes);
+            Some(Self {
+                shell_pgid,
+                shell_tmodes,
+            })
+        }
+    }
+
+    // 把终端交给前台作业，作业之前被暂停过的话恢复它当时的终端设置
+    pub fn give_terminal(&self, pgid: i32, tmodes: Option<libc::termios>) {
+        unsafe {
+            libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+            if let Some(tmodes) = tmodes {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &tmodes);
+            }
+        }
+    }
+
+    // 收回终端并恢复 shell 的终端设置，返回作业留下的终端设置
+    pub fn reclaim_terminal(&self) -> Option<libc::termios> {
+        unsafe {
+            let mut job_tmodes: libc::termios = mem::zeroed();
+            let saved = libc::tcgetattr(libc::STDIN_FILENO, &mut job_tmodes) == 0;
+            libc::tcsetpgrp(libc::STDIN_FILENO, self.shell_pgid);
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.shell_tmodes);
+            saved.then_some(job_tmodes)
+        }
+    }
+}
+
+// 在子进程 exec 之前调用：前台作业自己先拿到终端，避免和父进程抢时间
+// 再把 shell 忽略或者自己处理的信号恢复成默认处理
+// 这里只能用 async-signal-safe 的系统调用
+pub fn prepare_child(foreground: bool) {
+    unsafe {
+        if foreground {
+            libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+        }
+        for sig in JOB_CONTROL_SIGNALS.into_iter().chain([libc::SIGINT]) {
+            libc::signal(sig, libc::SIG_DFL);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProcState {
+    Running,
+    Stopped,
+    // 正常退出，带退出码
+    Exited(i32),
+    // 被信号杀死，带信号值
+    Signaled(i32),
+}
+
+impl ProcState {
+    // 换算成 $? 的值
+    pub fn code(self) -> i32 {
+        match self {
+            ProcState::Running => 0,
+            ProcState::Stopped => 128 + libc::SIGTSTP,
+            ProcState::Exited(code) => code,
+            ProcState::Signaled(sig) => 128 + sig,
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        matches!(self, ProcState::Exited(_) | ProcState::Signaled(_))
+    }
+
+    fn describe(self) -> String {
+        match self {
+            ProcState::Running => "Running".to_string(),
+            ProcState::Stopped => "Stopped".to_string(),
+            ProcState::Exited(0) => "Done".to_string(),
+            ProcState::Exited(code) => format!("Exit {}", code),
+            ProcState::Signaled(sig) => signal_name(sig),
+        }
+    }
+}
+
+fn signal_name(sig: i32) -> String {
+    match sig {
+        libc::SIGHUP => "Hangup".to_string(),
+        libc::SIGINT => "Interrupt".to_string(),
+        libc::SIGQUIT => "Quit".to_string(),
+        libc::SIGKILL => "Killed".to_string(),
+        libc::SIGSEGV => "Segmentation fault".to_string(),
+        libc::SIGPIPE => "Broken pipe".to_string(),
+        libc::SIGTERM => "Terminated".to_string(),
+        other => format!("Signal {}", other),
+    }
+}
+
+// 把 waitpid 拿到的状态翻译成 ProcState
+fn decode_status(status: i32) -> ProcState {
+    if libc::WIFEXITED(status) {
+        ProcState::Exited(libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        ProcState::Signaled(libc::WTERMSIG(status))
+    } else if libc::WIFSTOPPED(status) {
+        ProcState::Stopped
+    } else {
+        ProcState::Running
+    }
+}
+
+// 对单个进程调用 waitpid，被信号打断时重试
+// 返回 None 说明非阻塞模式下进程状态没有变化
+pub fn wait_pid(pid: i32, flags: i32) -> Option<ProcState> {
+    loop {
+        let mut status = 0;
+        let ret = unsafe { libc::waitpid(pid, &mut status, flags) };
+        if ret == pid {
+            return Some(decode_status(status));
+        }
+        if ret == 0 {
+            return None;
+        }
+        match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // 进程已经不是我们的子进程了 (被别处回收)，只能当作已经结束
+            _ => return Some(ProcState::Exited(0)),
+        }
+    }
+}
+
+pub struct JobProcess {
+    pub pid: i32,
+    pub state: ProcState,
+}
+
+// 一个作业就是一条管道里所有的外部命令
+pub struct Job {
+    pub pgid: i32,
+    pub procs: Vec<JobProcess>,
+    pub command: String,
+    // 状态发生了变化，还没有报告给用户
+    pub changed: bool,
+    // 被暂停时的终端设置，fg 时恢复
+    pub tmodes: Option<libc::termios>,
+}
+
+impl Job {
+    pub fn new(pgid: i32, pids: Vec<i32>, command: String) -> Self {
+        Self {
+            pgid,
+            procs: pids
+                .into_iter()
+                .map(|pid| JobProcess {
+                    pid,
+                    state: ProcState::Running,
+                })
+                .collect(),
+            command,
+            changed: false,
+            tmodes: None,
+        }
+    }
+
+    // 有进程在跑就是 Running，否则有进程被暂停就是 Stopped
+    // 全部结束时以最后一个进程的结果为准
+    pub fn state(&self) -> ProcState {
+        if self.procs.iter().any(|p| p.state == ProcState::Running) {
+            ProcState::Running
+        } else if self.procs.iter().any(|p| p.state == ProcState::Stopped) {
+            ProcState::Stopped
+        } else {
+            self.procs.last().map_or(ProcState::Exited(0), |p| p.state)
+        }
+    }
+
+    // 前台作业被 Ctrl-C 打断，终端上只回显了 ^C，需要补一个换行
+    pub fn interrupted(&self) -> bool {
+        self.procs
+            .iter()
+            .any(|p| p.state == ProcState::Signaled(libc::SIGINT))
+    }
+
+    pub fn last_pid(&self) -> Option<i32> {
+        self.procs.last().map(|p| p.pid)
+    }
+
+    // 阻塞等待，直到所有进程都结束或者被暂停
+    pub fn wait(&mut self) {
+        for proc in &mut self.procs {
+            if proc.state == ProcState::Running
+                && let Some(state) = wait_pid(proc.pid, libc::WUNTRACED)
+            {
+                proc.state = state;
+            }
+        }
+    }
+
+    // 非阻塞地收集状态变化
+    fn poll(&mut self) {
+        for proc in &mut self.procs {
+            if proc.state.is_finished() {
+                continue;
+            }
+            let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+            if let Some(state) = wait_pid(proc.pid, flags)
+                && state != proc.state
+            {
+                proc.state = state;
+                self.changed = true;
+            }
+        }
+    }
+
+    // 让被暂停的作业继续运行
+    pub fn resume(&mut self) {
+        unsafe {
+            libc::kill(-self.pgid, libc::SIGCONT);
+        }
+        for proc in &mut self.procs {
+            if proc.state == ProcState::Stopped {
+                proc.state = ProcState::Running;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct JobTable {
+    jobs: BTreeMap<usize, Job>,
+    // 按使用先后排列的作业号，最后一个是当前作业 (+)，倒数第二个是上一个作业 (-)
+    recent: Vec<usize>,
+}
+
+impl JobTable {
+    // 作业号取当前最大的加一
+    pub fn add(&mut self, job: Job) -> usize {
+        let id = self.jobs.keys().next_back().map_or(1, |id| id + 1);
+        self.jobs.insert(id, job);
+        self.touch(id);
+        id
+    }
+
+    // 把作业设为当前作业
+    pub fn touch(&mut self, id: usize) {
+        self.recent.retain(|&i| i != id);
+        self.recent.push(id);
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        self.recent.retain(|&i| i != id);
+        self.jobs.remove(&id)
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.jobs.keys().copied().collect()
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.recent.last().copied()
+    }
+
+    fn mark(&self, id: usize) -> char {
+        let mut recent = self.recent.iter().rev();
+        if recent.next() == Some(&id) {
+            '+'
+        } else if recent.next() == Some(&id) {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    // 解析 %n、%+、%%、%-、%前缀、%?子串
+    pub fn resolve(&self, spec: &str) -> Option<usize> {
+        let spec = spec.strip_prefix('%')?;
+        match spec {
+            "" | "+" | "%" => self.current(),
+            "-" => {
+                let mut recent = self.recent.iter().rev();
+                recent.next();
+                recent.next().copied().or(self.current())
+            }
+            _ => {
+                if let Ok(id) = spec.parse::<usize>() {
+                    return self.jobs.contains_key(&id).then_some(id);
+                }
+                let found: Vec<usize> = match spec.strip_prefix('?') {
+                    Some(sub) => self
+                        .jobs
+                        .iter()
+                        .filter(|(_, job)| job.command.contains(sub))
+                        .map(|(id, _)| *id)
+                        .collect(),
+                    None => self
+                        .jobs
+                        .iter()
+                        .filter(|(_, job)| job.command.starts_with(spec))
+                        .map(|(id, _)| *id)
+                        .collect(),
+                };
+                // 匹配到多个作业时有歧义，不予处理
+                match found.as_slice() {
+                    [id] => Some(*id),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    // 找到包含这个进程的作业
+    pub fn find_pid(&self, pid: i32) -> Option<usize> {
+        self.jobs
+            .iter()
+            .find(|(_, job)| job.procs.iter().any(|p| p.pid == pid))
+            .map(|(id, _)| *id)
+    }
+
+    pub fn poll(&mut self) {
+        for job in self.jobs.values_mut() {
+            job.poll();
+        }
+    }
+
+    // [1]+  Running                 sleep 10 &
+    pub fn format(&self, id: usize, with_pid: bool) -> String {
+        let Some(job) = self.jobs.get(&id) else {
+            return String::new();
+        };
+        let state = job.state();
+        let pid = if with_pid {
+            format!("{} ", job.pgid)
+        } else {
+            " ".to_string()
+        };
+        let suffix = if state == ProcState::Running {
+            " &"
+        } else {
+            ""
+        };
+        format!(
+            "[{}]{} {}{:<24}{}{}",
+            id,
+            self.mark(id),
+            pid,
+            state.describe(),
+            job.command,
+            suffix
+        )
+    }
+
+    // bg 恢复作业时的提示：[1]+ sleep 10 &
+    pub fn format_resumed(&self, id: usize) -> String {
+        match self.jobs.get(&id) {
+            Some(job) => format!("[{}]{} {} &", id, self.mark(id), job.command),
+            None => String::new(),
+        }
+    }
+
+    // 收集需要报告的作业状态，已经结束的作业报告后从表里移除
+    pub fn take_notifications(&mut self) -> Vec<String> {
+        self.poll();
+        let mut lines = Vec::new();
+        for id in self.ids() {
+            let Some(job) = self.jobs.get_mut(&id) else {
+                continue;
+            };
+            if !job.changed {
+                continue;
+            }
+            job.changed = false;
+            let finished = job.state().is_finished();
+            lines.push(self.format(id, false));
+            if finished {
+                self.remove(id);
+            }
+        }
+        lines
+    }
+
+    // jobs 命令列出作业后，这些状态就不用再报告了，已经结束的作业也一并移除
+    pub fn acknowledge(&mut self) {
+        for id in self.ids() {
+            let finished = self.jobs.get_mut(&id).is_some_and(|job| {
+                job.changed = false;
+                job.state().is_finished()
+            });
+            if finished {
+                self.remove(id);
+            }
+        }
+    }
+}
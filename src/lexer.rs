@@ -1,16 +1,127 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter::Peekable;
 use std::mem;
+use std::ops::Range;
+use std::str::Chars;
 
-#[derive(Debug, PartialEq, Eq)]
+use crate::parser::ParseError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Token {
     Op(String),
     Word(Vec<Args>),
+    // 算术命令 ((expr)) 里的表达式
+    Arith(String),
+    // << 后面的 here-document，正文在遇到换行时从后续的行里读出来
+    HereDoc(HereDoc),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HereDoc {
+    pub delimiter: String,
+    pub body: String,
+    // 结束符没有加引号时，正文里的 $ 和 ` 要展开
+    pub expand: bool,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Args {
     Raw(String),
     SingleQuotes(String),
     DoubleQuotes(String),
+    // 参数展开：$NAME / ${...} 中的内容，bool 表示是否位于双引号内
+    // 比如 $HOME -> Param("HOME", false)，"${1}" -> Param("1", true)
+    Param(String, bool),
+    // 命令替换：$(...) 或 `...` 里的命令文本，bool 同上
+    Command(String, bool),
+    // 算术展开：$((...)) 里的表达式，bool 同上
+    Arith(String, bool),
+}
+
+// 还原成命令行上的写法，jobs 列出作业时用
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Op(op) => write!(f, "{}", op),
+            Token::Arith(expr) => write!(f, "(({}))", expr),
+            Token::HereDoc(doc) if doc.expand => write!(f, "{}", doc.delimiter),
+            Token::HereDoc(doc) => write!(f, "'{}'", doc.delimiter),
+            Token::Word(args) => {
+                // 连续的双引号内片段合在一对引号里输出
+                let mut in_quotes = false;
+                for arg in args {
+                    let quoted = match arg {
+                        Args::DoubleQuotes(_) => true,
+                        Args::Param(_, q) | Args::Command(_, q) | Args::Arith(_, q) => *q,
+                        _ => false,
+                    };
+                    if quoted != in_quotes {
+                        write!(f, "\"")?;
+                        in_quotes = quoted;
+                    }
+                    match arg {
+                        Args::Raw(s) | Args::DoubleQuotes(s) => write!(f, "{}", s)?,
+                        Args::SingleQuotes(s) => write!(f, "'{}'", s)?,
+                        Args::Param(name, _) => {
+                            let simple =
+                                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                                    || (name.len() == 1 && name.chars().all(char_is_special_param));
+                            if simple {
+                                write!(f, "${}", name)?;
+                            } else {
+                                write!(f, "${{{}}}", name)?;
+                            }
+                        }
+                        Args::Command(cmd, _) => write!(f, "$({})", cmd)?,
+                        Args::Arith(expr, _) => write!(f, "$(({}))", expr)?,
+                    }
+                }
+                if in_quotes {
+                    write!(f, "\"")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// 逐个读取字符，同时记下下一个字符在输入里的字节位置
+#[derive(Clone)]
+struct Cursor<'a> {
+    chars: Peekable<Chars<'a>>,
+    pos: usize,
+    // $( ${ ` 读到输入结束还没闭合时，记下缺少的那个字符
+    unclosed: Option<char>,
+}
+impl<'a> Cursor<'a> {
+    fn new(input: &'a str) -> Self {
+        Cursor {
+            chars: input.chars().peekable(),
+            pos: 0,
+            unclosed: None,
+        }
+    }
+    fn peek(&mut self) -> Option<&char> {
+        self.chars.peek()
+    }
+    fn next_if(&mut self, f: impl FnOnce(&char) -> bool) -> Option<char> {
+        match self.peek() {
+            Some(c) if f(c) => self.next(),
+            _ => None,
+        }
+    }
+    fn next_if_eq(&mut self, expected: &char) -> Option<char> {
+        self.next_if(|c| c == expected)
+    }
+}
+impl Iterator for Cursor<'_> {
+    type Item = char;
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
 }
 
 #[derive(PartialEq)]
@@ -20,11 +131,326 @@ enum ParseState {
     InDoubleQuotes,
 }
 fn char_is_op(c: char) -> bool {
-    matches!(c, '>' | '|')
+    matches!(c, '>' | '<' | '|' | ';' | '(' | ')')
+}
+// 读取 > 或 < 之后的部分：>> >& <> <& << <<- <<<
+fn read_redirect_op(first: char, chars: &mut Cursor) -> String {
+    let mut op = first.to_string();
+    match (first, chars.peek()) {
+        ('>', Some(&'>'))
+        | ('>', Some(&'&'))
+        | ('>', Some(&'|'))
+        | ('<', Some(&'>'))
+        | ('<', Some(&'&')) => {
+            op.push(chars.next().unwrap());
+        }
+        ('<', Some(&'<')) => {
+            op.push(chars.next().unwrap());
+            if let Some(&next) = chars.peek()
+                && (next == '<' || next == '-')
+            {
+                op.push(next);
+                chars.next();
+            }
+        }
+        _ => {}
+    }
+    op
+}
+// here-document 的结束符：去掉引号后的字面文本，以及是否带了引号
+fn heredoc_delimiter(args: &[Args]) -> (String, bool) {
+    let mut delimiter = String::new();
+    let mut quoted = false;
+    for arg in args {
+        match arg {
+            Args::Raw(s) => delimiter.push_str(s),
+            Args::SingleQuotes(s) | Args::DoubleQuotes(s) => {
+                quoted = true;
+                delimiter.push_str(s);
+            }
+            other => delimiter.push_str(&Token::Word(vec![other.clone()]).to_string()),
+        }
+    }
+    (delimiter, quoted)
+}
+// 从输入里一行行读取 here-document 的正文，直到遇到单独一行的结束符
+// <<- 会去掉每一行开头的 tab；返回 None 说明输入结束了还没看到结束符
+fn read_heredoc_body(chars: &mut Cursor, delimiter: &str, strip_tabs: bool) -> Option<String> {
+    let mut body = String::new();
+    while chars.peek().is_some() {
+        let mut line = String::new();
+        for c in chars.by_ref() {
+            if c == '\n' {
+                break;
+            }
+            line.push(c);
+        }
+        let line = if strip_tabs {
+            line.trim_start_matches('\t')
+        } else {
+            &line
+        };
+        if line == delimiter {
+            return Some(body);
+        }
+        body.push_str(line);
+        body.push('\n');
+    }
+    None
+}
+// 特殊参数：$? $$ $! $# $@ $* $-
+fn char_is_special_param(c: char) -> bool {
+    matches!(c, '?' | '$' | '!' | '#' | '@' | '*' | '-')
+}
+// 在已经吃掉 $ 之后读取展开，返回 None 说明这个 $ 只是普通字符
+fn read_dollar(chars: &mut Cursor, quoted: bool) -> Option<Args> {
+    if chars.peek() == Some(&'(') {
+        chars.next();
+        let body = read_command_sub(chars);
+        if let Some(expr) = arith_body(&body) {
+            return Some(Args::Arith(expr, quoted));
+        }
+        return Some(Args::Command(body, quoted));
+    }
+    read_param(chars).map(|name| Args::Param(name, quoted))
+}
+// 在已经吃掉 $( 之后读取到匹配的 )，括号可以嵌套，引号里的括号不算
+fn read_command_sub(chars: &mut Cursor) -> String {
+    let mut depth = 1;
+    let mut body = String::new();
+    let mut in_single = false;
+    let mut in_double = false;
+    while let Some(c) = chars.next() {
+        if in_single {
+            in_single = c != '\'';
+        } else if c == '\\' {
+            body.push(c);
+            if let Some(next) = chars.next() {
+                body.push(next);
+            }
+            continue;
+        } else if in_double {
+            in_double = c != '"';
+        } else {
+            match c {
+                '\'' => in_single = true,
+                '"' => in_double = true,
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return body;
+                    }
+                }
+                _ => {}
+            }
+        }
+        body.push(c);
+    }
+    chars.unclosed = Some(')');
+    body
+}
+// $((expr)) 按命令替换读出来是 (expr)，要求第一个 ( 和最后一个 ) 配对
+// 这样 $((a); (b)) 这种两个子 shell 的写法不会被误认成算术
+fn arith_body(body: &str) -> Option<String> {
+    let inner = body.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    (depth == 0).then(|| inner.to_string())
+}
+// 在已经吃掉 ` 之后读取到下一个 `，其中 \` \$ \\ 会去掉反斜杠
+fn read_backquote(chars: &mut Cursor) -> String {
+    let mut body = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '`' => return body,
+            '\\' => match chars.peek() {
+                Some(&'`') | Some(&'$') | Some(&'\\') => body.push(chars.next().unwrap()),
+                _ => body.push(c),
+            },
+            _ => body.push(c),
+        }
+    }
+    chars.unclosed = Some('`');
+    body
+}
+// 在已经吃掉 $ 之后读取参数名
+fn read_param(chars: &mut Cursor) -> Option<String> {
+    match chars.peek() {
+        Some(&'{') => {
+            chars.next();
+            // 找到与之匹配的 }，允许 ${a:-${b}} 这种嵌套
+            let mut depth = 1;
+            let mut body = String::new();
+            for c in chars.by_ref() {
+                match c {
+                    '{' => depth += 1,
+                    '}' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(body);
+                        }
+                    }
+                    _ => {}
+                }
+                body.push(c);
+            }
+            chars.unclosed = Some('}');
+            Some(body)
+        }
+        Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+            let mut name = String::new();
+            while let Some(&n) = chars.peek() {
+                if n.is_ascii_alphanumeric() || n == '_' {
+                    name.push(n);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            Some(name)
+        }
+        // $0 ~ $9 只取一位，$10 要写成 ${10}
+        Some(&c) if c.is_ascii_digit() || char_is_special_param(c) => {
+            chars.next();
+            Some(c.to_string())
+        }
+        _ => None,
+    }
+}
+// 切分命令行，同时返回每个 token 在输入里的字节范围
+// 引号、$( ${ ` 没有闭合时报错
+pub fn tokens_generate(input: &str) -> Result<(Vec<Token>, Vec<Range<usize>>), ParseError> {
+    let lexed = lex(input, true);
+    if let Some((close, pos)) = lexed.unterminated {
+        return Err(ParseError::Unterminated(close, pos..input.len()));
+    }
+    Ok((lexed.tokens, lexed.spans))
+}
+// 输入还没有结束，比如 here-document 还没等到结束符，需要继续读下一行
+pub fn input_incomplete(input: &str) -> bool {
+    let lexed = lex(input, true);
+    lexed.incomplete || !lexed.heredocs.is_empty()
+}
+// 逐行读入的输入：每读一行只切分上次没切分完的那一段，here-document 的正文只和结束符比较
+// 这样检查输入是否完整的开销只和新读的这一行有关
+#[derive(Default)]
+pub struct LineLexer {
+    // 引号、替换还没闭合或者行尾是反斜杠时，攒下来的几行
+    tail: String,
+    // 还在等正文的 here-document 的结束符，以及是不是 <<-
+    heredocs: VecDeque<(String, bool)>,
+}
+impl LineLexer {
+    // 读入一行，这一段切分完整时返回它的 token，否则返回 None
+    pub fn push_line(&mut self, line: &str) -> Option<Vec<Token>> {
+        if let Some((delimiter, strip_tabs)) = self.heredocs.front() {
+            let line = if *strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                line
+            };
+            if line == delimiter {
+                self.heredocs.pop_front();
+            }
+            return None;
+        }
+        if !self.tail.is_empty() {
+            self.tail.push('\n');
+        }
+        self.tail.push_str(line);
+        let lexed = lex(&self.tail, true);
+        if lexed.incomplete || lexed.unterminated.is_some() {
+            return None;
+        }
+        self.tail.clear();
+        self.heredocs.extend(lexed.heredocs);
+        Some(lexed.tokens)
+    }
+
+    // 读过的行都已经切分完，也没有 here-document 在等正文
+    pub fn is_complete(&self) -> bool {
+        self.tail.is_empty() && self.heredocs.is_empty()
+    }
+}
+// here-document 正文的切分：引号是普通字符，只有 $、` 和反斜杠有特殊含义
+// 结果都按双引号内处理，不会再被分词或通配
+pub fn heredoc_generate(body: &str) -> Vec<Args> {
+    let mut args = Vec::new();
+    let mut text = String::new();
+    let mut chars = Cursor::new(body);
+    while let Some(c) = chars.next() {
+        let expansion = match c {
+            '\\' => {
+                match chars.peek() {
+                    Some(&'$') | Some(&'`') | Some(&'\\') => text.push(chars.next().unwrap()),
+                    // 反斜杠加换行是续行
+                    Some(&'\n') => {
+                        chars.next();
+                    }
+                    _ => text.push(c),
+                }
+                None
+            }
+            '$' => {
+                let arg = read_dollar(&mut chars, true);
+                if arg.is_none() {
+                    text.push(c);
+                }
+                arg
+            }
+            '`' => Some(Args::Command(read_backquote(&mut chars), true)),
+            _ => {
+                text.push(c);
+                None
+            }
+        };
+        if let Some(arg) = expansion {
+            if !text.is_empty() {
+                args.push(Args::DoubleQuotes(mem::take(&mut text)));
+            }
+            args.push(arg);
+        }
+    }
+    if !text.is_empty() {
+        args.push(Args::DoubleQuotes(text));
+    }
+    args
+}
+// 把一段文本当成单个单词来切分 (空格和操作符都是普通字符)
+// 用于 ${var:-word} 里的 word 这类需要二次展开的片段
+pub fn word_generate(input: &str) -> Vec<Args> {
+    match lex(input, false).tokens.pop() {
+        Some(Token::Word(args)) => args,
+        _ => Vec::new(),
+    }
+}
+struct Lexed {
+    tokens: Vec<Token>,
+    // 每个 token 在输入里的字节范围
+    spans: Vec<Range<usize>>,
+    // 输入是否还不完整：行尾是反斜杠，或者 here-document 的正文没读完
+    incomplete: bool,
+    // 这一行的 here-document 还没开始读正文：结束符，以及是不是 <<-
+    heredocs: Vec<(String, bool)>,
+    // 没有闭合的引号或 $( ${ `：缺少的结束字符，以及开头的位置
+    unterminated: Option<(char, usize)>,
 }
-pub fn tokens_generate(input: &str) -> Vec<Token> {
+fn lex(input: &str, split_words: bool) -> Lexed {
     let mut tokens: Vec<Token> = Vec::new();
-    let mut chars = input.chars().peekable();
+    // 每个 token 在 input 里的字节范围，报语法错误时用
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    // 这一行里还没读正文的 << 操作符在 tokens 里的位置，以及是不是 <<-
+    let mut pending_heredocs: Vec<(usize, bool)> = Vec::new();
+    let mut incomplete = false;
+    let mut chars = Cursor::new(input);
 
     // 当前单词的组件列表
     let mut current_word_args: Vec<Args> = Vec::new();
@@ -32,6 +458,14 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
     let mut current_string = String::new();
 
     let mut state = ParseState::Normal;
+    // 进入引号时 current_word_args 的长度，用来识别 "" 这种空引号
+    let mut quote_start = 0;
+    // 最近一个左引号的位置，引号没有闭合时报告它
+    let mut quote_pos = 0;
+    // 当前单词从哪里开始
+    let mut word_start = 0;
+    // 最先读到输入结束的 $( ${ `
+    let mut unclosed = None;
 
     let flush_string_to_args = |args_vec: &mut Vec<Args>, s: &mut String, state: &ParseState| {
         if !s.is_empty() {
@@ -51,52 +485,177 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
         }
     };
 
-    while let Some(c) = chars.next() {
+    loop {
+        let pos = chars.pos;
+        let Some(c) = chars.next() else {
+            break;
+        };
+        if matches!(state, ParseState::Normal)
+            && current_string.is_empty()
+            && current_word_args.is_empty()
+        {
+            word_start = pos;
+        }
+        let before = tokens.len();
         match state {
             // === 状态 1: 普通模式 (Raw) ===
             ParseState::Normal => {
                 match c {
-                    // 转义
-                    '\\' => {
-                        if let Some(next_char) = chars.next() {
-                            current_string.push(next_char);
+                    // 转义：被转义的字符和单引号里的一样按字面处理 (比如 \* 不做通配)
+                    // 反斜杠加换行是续行，两个字符都去掉；行尾的反斜杠说明还有下一行
+                    '\\' => match chars.next() {
+                        Some('\n') => {}
+                        Some(next_char) => {
+                            flush_string_to_args(
+                                &mut current_word_args,
+                                &mut current_string,
+                                &state,
+                            );
+                            current_word_args.push(Args::SingleQuotes(next_char.to_string()));
                         }
-                    }
+                        None => incomplete = true,
+                    },
                     // 单引号：先结算之前的 Raw，再切换状态
                     '\'' => {
                         flush_string_to_args(&mut current_word_args, &mut current_string, &state);
+                        quote_start = current_word_args.len();
+                        quote_pos = pos;
                         state = ParseState::InSingleQuotes;
                     }
                     // 双引号：同上
                     '\"' => {
                         flush_string_to_args(&mut current_word_args, &mut current_string, &state);
+                        quote_start = current_word_args.len();
+                        quote_pos = pos;
                         state = ParseState::InDoubleQuotes;
                     }
+                    // 参数展开/命令替换：先结算之前的 Raw，再放入展开
+                    '$' => match read_dollar(&mut chars, false) {
+                        Some(arg) => {
+                            flush_string_to_args(
+                                &mut current_word_args,
+                                &mut current_string,
+                                &state,
+                            );
+                            current_word_args.push(arg);
+                        }
+                        None => current_string.push(c),
+                    },
+                    '`' => {
+                        flush_string_to_args(&mut current_word_args, &mut current_string, &state);
+                        current_word_args.push(Args::Command(read_backquote(&mut chars), false));
+                    }
+                    // 算术命令 ((expr))，只在单词开头识别
+                    '(' if split_words
+                        && chars.peek() == Some(&'(')
+                        && current_string.is_empty()
+                        && current_word_args.is_empty() =>
+                    {
+                        let body = read_command_sub(&mut chars);
+                        match arith_body(&body) {
+                            Some(expr) => tokens.push(Token::Arith(expr)),
+                            None => {
+                                current_string.push(c);
+                                current_string.push_str(&body);
+                                current_string.push(')');
+                            }
+                        }
+                    }
+                    // 换行：结束当前单词，读出这一行里 here-document 的正文
+                    // 前面是完整的命令时，换行和 ; 一样分隔命令
+                    '\n' if split_words => {
+                        flush_string_to_args(&mut current_word_args, &mut current_string, &state);
+                        flush_args_to_token(&mut tokens, &mut current_word_args);
+                        for (idx, strip_tabs) in pending_heredocs.drain(..) {
+                            let Some(Token::Word(args)) = tokens.get(idx + 1) else {
+                                continue;
+                            };
+                            let (delimiter, quoted) = heredoc_delimiter(args);
+                            let body = read_heredoc_body(&mut chars, &delimiter, strip_tabs)
+                                .unwrap_or_else(|| {
+                                    incomplete = true;
+                                    String::new()
+                                });
+                            tokens[idx + 1] = Token::HereDoc(HereDoc {
+                                delimiter,
+                                body,
+                                expand: !quoted,
+                            });
+                        }
+                        if matches!(
+                            tokens.last(),
+                            Some(Token::Word(_) | Token::Arith(_) | Token::HereDoc(_))
+                        ) {
+                            tokens.push(Token::Op(";".to_string()));
+                        }
+                    }
+                    // 注释：单词开头的 # 到行尾都忽略，换行留给上面的分支处理
+                    '#' if split_words
+                        && current_string.is_empty()
+                        && current_word_args.is_empty() =>
+                    {
+                        while chars.next_if(|&n| n != '\n').is_some() {}
+                    }
                     // 空格：这是单词的分界线
-                    c if c.is_whitespace() => {
+                    c if split_words && c.is_whitespace() => {
                         // 1. 先把手里剩下的字符存入 args
                         flush_string_to_args(&mut current_word_args, &mut current_string, &state);
                         // 2. 再把 args 打包成 Token::Word
                         flush_args_to_token(&mut tokens, &mut current_word_args);
                     }
-                    // 操作符 (>, |)：这也是单词的分界线！
-                    c if char_is_op(c) => {
+                    // && 连接两条管道，单独的 & 把管道放到后台
+                    '&' if split_words => {
+                        flush_string_to_args(&mut current_word_args, &mut current_string, &state);
+                        flush_args_to_token(&mut tokens, &mut current_word_args);
+                        if chars.peek() == Some(&'&') {
+                            chars.next();
+                            tokens.push(Token::Op("&&".to_string()));
+                        } else if chars.peek() == Some(&'>') {
+                            // &> / &>>：标准输出和标准错误一起重定向
+                            chars.next();
+                            let mut op = "&>".to_string();
+                            if chars.peek() == Some(&'>') {
+                                chars.next();
+                                op.push('>');
+                            }
+                            tokens.push(Token::Op(op));
+                        } else {
+                            tokens.push(Token::Op("&".to_string()));
+                        }
+                    }
+                    // 操作符 (>, |, ;, 括号)：这也是单词的分界线！
+                    c if split_words && char_is_op(c) => {
                         // 1. 类似空格，先结算前面的单词 (比如 ls>file 中的 ls)
                         flush_string_to_args(&mut current_word_args, &mut current_string, &state);
                         flush_args_to_token(&mut tokens, &mut current_word_args);
 
                         // 2. 处理操作符本身
                         let mut op = c.to_string();
-                        if c == '>' && chars.peek() == Some(&'>') {
-                            chars.next(); // 吃掉第二个 >
-                            op.push('>');
+                        if c == '>' || c == '<' {
+                            op = read_redirect_op(c, &mut chars);
+                        } else if c == '|' && chars.peek() == Some(&'|') {
+                            chars.next(); // 吃掉第二个 |
+                            op.push(c);
+                        } else if c == ';' {
+                            // case 分支的结尾：;; ;& ;;&
+                            if chars.next_if_eq(&';').is_some() {
+                                op.push(';');
+                                if chars.next_if_eq(&'&').is_some() {
+                                    op.push('&');
+                                }
+                            } else if chars.next_if_eq(&'&').is_some() {
+                                op.push('&');
+                            }
+                        }
+                        if op.starts_with("<<") && op != "<<<" {
+                            pending_heredocs.push((tokens.len(), op == "<<-"));
                         }
 
                         // 3. 生成 Op Token
                         tokens.push(Token::Op(op));
                     }
                     // 数字：可能是 1> 或 2>，也可能是普通字符 123
-                    c if c.is_digit(10) => {
+                    c if split_words && c.is_ascii_digit() => {
                         // 1. 创建一个克隆的迭代器用于“侦察”
                         // 这不会消耗原本的 chars
                         let mut lookahead = chars.clone();
@@ -104,7 +663,7 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
                         // 2. 统计连续数字的个数，除了c
                         let mut digit_count = 0;
                         while let Some(&n) = lookahead.peek() {
-                            if n.is_digit(10) {
+                            if n.is_ascii_digit() {
                                 digit_count += 1;
                                 lookahead.next();
                             } else {
@@ -113,13 +672,14 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
                         }
 
                         // 3. 检查数字跑完之后，紧接着的是不是 >
-                        let is_redirect = matches!(lookahead.peek(), Some(&'>'));
+                        let is_redirect = matches!(lookahead.peek(), Some(&'>') | Some(&'<'));
 
                         // 4. 【关键判断】只有当 current_string 为空时，这才是 FD 重定向！
                         // 比如：
                         // "echo 1>" -> current_string 空，1> 是重定向
                         // "echo file1>" -> current_string 是 "file"，1> 只是文件名的一部分
-                        if is_redirect && current_string.is_empty() {
+                        if is_redirect && current_string.is_empty() && current_word_args.is_empty()
+                        {
                             // === 是重定向符 (如 10>) ===
 
                             // A. 收集所有的数字
@@ -128,14 +688,13 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
                                 fd_str.push(chars.next().unwrap()); // 真的消耗掉
                             }
 
-                            // B. 消耗掉那个 >
-                            chars.next();
+                            // B. 消耗掉那个 > 或 <
+                            let first = chars.next().unwrap();
 
-                            // C. 检查是不是 >> (追加)
-                            let mut op = format!("{}>", fd_str);
-                            if let Some(&'>') = chars.peek() {
-                                chars.next();
-                                op.push('>'); // 变成 10>>
+                            // C. 读出完整的操作符，比如 10>> (追加)、2>& (复制)、0<<EOF
+                            let op = format!("{}{}", fd_str, read_redirect_op(first, &mut chars));
+                            if op[fd_str.len()..].starts_with("<<") && !op.ends_with("<<<") {
+                                pending_heredocs.push((tokens.len(), op.ends_with("<<-")));
                             }
 
                             // D. 结算之前的并在 tokens 里加入 Op
@@ -167,7 +726,10 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
             ParseState::InSingleQuotes => {
                 match c {
                     '\'' => {
-                        // 结算单引号内容
+                        // 结算单引号内容，'' 本身也是一个 (空) 参数
+                        if current_word_args.len() == quote_start && current_string.is_empty() {
+                            current_word_args.push(Args::SingleQuotes(String::new()));
+                        }
                         flush_string_to_args(&mut current_word_args, &mut current_string, &state);
                         state = ParseState::Normal; // 回到普通模式
                     }
@@ -179,28 +741,157 @@ pub fn tokens_generate(input: &str) -> Vec<Token> {
             ParseState::InDoubleQuotes => {
                 match c {
                     '\"' => {
-                        // 结算双引号内容
+                        // 结算双引号内容，"" 本身也是一个 (空) 参数
+                        if current_word_args.len() == quote_start && current_string.is_empty() {
+                            current_word_args.push(Args::DoubleQuotes(String::new()));
+                        }
                         flush_string_to_args(&mut current_word_args, &mut current_string, &state);
                         state = ParseState::Normal;
                     }
                     '\\' => {
                         // 双引号内的转义逻辑 (同之前)
                         match chars.peek() {
-                            Some(&'\\') | Some(&'\"') | Some(&'$') | Some(&'\n') | Some(&'`') => {
+                            // 双引号里的续行同样去掉
+                            Some(&'\n') => {
+                                chars.next();
+                            }
+                            Some(&'\\') | Some(&'\"') | Some(&'$') | Some(&'`') => {
                                 current_string.push(chars.next().unwrap());
                             }
                             _ => current_string.push('\\'),
                         }
                     }
+                    // 双引号内同样展开参数，但要标记为 quoted
+                    '$' => match read_dollar(&mut chars, true) {
+                        Some(arg) => {
+                            flush_string_to_args(
+                                &mut current_word_args,
+                                &mut current_string,
+                                &state,
+                            );
+                            current_word_args.push(arg);
+                        }
+                        None => current_string.push(c),
+                    },
+                    '`' => {
+                        flush_string_to_args(&mut current_word_args, &mut current_string, &state);
+                        current_word_args.push(Args::Command(read_backquote(&mut chars), true));
+                    }
                     _ => current_string.push(c),
                 }
             }
         }
+        if let Some(close) = chars.unclosed.take() {
+            unclosed.get_or_insert((close, pos));
+        }
+        // 这一轮新生成的 token：单词从 word_start 开始，操作符就是这一轮读过的字符
+        let end = chars.pos;
+        for token in &tokens[before..] {
+            spans.push(match token {
+                Token::Word(_) => word_start..pos,
+                _ => pos..end,
+            });
+        }
     }
 
     // 循环结束后的最后一次结算
     flush_string_to_args(&mut current_word_args, &mut current_string, &state);
     flush_args_to_token(&mut tokens, &mut current_word_args);
+    if spans.len() < tokens.len() {
+        spans.push(word_start..input.len());
+    }
+
+    // 还有 here-document 没读到正文
+    let mut heredocs = Vec::new();
+    for (idx, strip_tabs) in pending_heredocs {
+        match tokens.get(idx + 1) {
+            Some(Token::Word(args)) => heredocs.push((heredoc_delimiter(args).0, strip_tabs)),
+            _ => incomplete = true,
+        }
+    }
+    // 替换在引号里没闭合时，后面的引号都被它吃掉了，报告替换本身
+    let unterminated = match state {
+        _ if unclosed.is_some() => unclosed,
+        ParseState::Normal => None,
+        ParseState::InSingleQuotes => Some(('\'', quote_pos)),
+        ParseState::InDoubleQuotes => Some(('"', quote_pos)),
+    };
+    Lexed {
+        tokens,
+        spans,
+        incomplete,
+        heredocs,
+        unterminated,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spans(input: &str) -> Vec<&str> {
+        let (_, spans) = tokens_generate(input).unwrap();
+        spans.into_iter().map(|span| &input[span]).collect()
+    }
 
-    tokens
+    fn error(input: &str) -> ParseError {
+        tokens_generate(input).unwrap_err()
+    }
+
+    #[test]
+    fn token_spans() {
+        assert_eq!(spans("echo 'a b' >out"), ["echo", "'a b'", ">", "out"]);
+        assert_eq!(spans("a&&b 2>&1"), ["a", "&&", "b", "2>&", "1"]);
+        // 换行转成的 ; 对应换行符本身
+        assert_eq!(spans("a\nb"), ["a", "\n", "b"]);
+        // 按字节计算位置，前面有多字节字符也不会错位
+        assert_eq!(spans("echo 你好 | wc"), ["echo", "你好", "|", "wc"]);
+    }
+
+    #[test]
+    fn unterminated_quotes() {
+        let err = error("echo 'abc");
+        assert!(matches!(err, ParseError::Unterminated('\'', _)));
+        assert_eq!(err.span(), &(5..9));
+        assert_eq!(
+            err.to_string(),
+            "unexpected EOF while looking for matching `''"
+        );
+        let err = error("echo a \"b\nc");
+        assert!(matches!(err, ParseError::Unterminated('"', _)));
+        assert_eq!(err.span(), &(7..11));
+        assert!(err.is_incomplete());
+    }
+
+    #[test]
+    fn unterminated_parameter() {
+        let err = error("echo ${HOME");
+        assert!(matches!(err, ParseError::Unterminated('}', _)));
+        assert_eq!(err.span(), &(5..11));
+        assert!(err.is_incomplete());
+        assert_eq!(err.caret("echo ${HOME"), "echo ${HOME\n     ^^^^^^");
+        // 在双引号里也报告 ${ 而不是引号
+        let err = error("echo \"${a:-x\"");
+        assert!(matches!(err, ParseError::Unterminated('}', _)));
+        assert_eq!(err.span().start, 6);
+        assert!(tokens_generate("echo ${a:-${b}}").is_ok());
+    }
+
+    #[test]
+    fn unterminated_substitutions() {
+        let err = error("echo $(date");
+        assert!(matches!(err, ParseError::Unterminated(')', _)));
+        assert_eq!(err.span(), &(5..11));
+        assert!(err.is_incomplete());
+        assert!(matches!(
+            error("echo `date"),
+            ParseError::Unterminated('`', _)
+        ));
+        assert!(matches!(
+            error("((1 + 2)"),
+            ParseError::Unterminated(')', _)
+        ));
+        // 替换里的引号和括号不影响配对
+        assert!(tokens_generate("echo $(echo ')'; (true))").is_ok());
+    }
 }
@@ -1,15 +1,23 @@
 use crate::{
     autocompletion::longest_common_prefix,
-    terminal_io::{MskEvent, MskKeyCode},
+    command::InputBuffer,
+    terminal_io::{MskEvent, MskKeyCode, terminal},
     trie::Trie,
 };
-use std::io::{self, Write};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+const PS1: &str = "$ ";
 
 pub struct LineEditor {
     buffer: Vec<char>, // 存的是完整的字符
     cursor: usize,
     has_tab: bool,
     // utf8_buf: Vec<u8>, // 暂存还没收全的字节
+    // 已经输入、但还不是完整命令的那几行 (比如引号没闭合)
+    pending: InputBuffer,
+    // 当前显示的提示符，续行时是 PS2
+    prompt: String,
 }
 
 impl LineEditor {
@@ -19,6 +27,8 @@ impl LineEditor {
             cursor: 0,
             has_tab: false,
             // utf8_buf: Vec::with_capacity(4), // UTF-8 最多4个字节
+            pending: InputBuffer::new(),
+            prompt: PS1.to_string(),
         }
     }
 
@@ -56,51 +66,86 @@ impl LineEditor {
         &mut self,
         event: MskEvent,
         all_commands: &Trie,
-        history: &Vec<String>,
+        aliases: &BTreeMap<String, String>,
+        history: &[String],
         history_cursor: &mut usize,
+        ps2: &str,
     ) -> Option<String> {
         match event {
             MskEvent::Key(msk_key_code) => match msk_key_code {
                 MskKeyCode::Char(c) => self.handle_char(c),
                 MskKeyCode::Backspace => self.handle_backsapce(),
-                MskKeyCode::Enter => self.handle_return(history_cursor, history.len()),
+                MskKeyCode::Enter => self.handle_return(history_cursor, history.len(), ps2),
                 MskKeyCode::ArrowRight => self.handle_arrow_right(),
                 MskKeyCode::ArrowLeft => self.handle_arrow_left(),
-                MskKeyCode::Tab => self.handle_tab(all_commands),
+                MskKeyCode::Tab => self.handle_tab(all_commands, aliases),
                 MskKeyCode::Up => self.handle_up(history, history_cursor),
                 MskKeyCode::Down => self.handle_down(history, history_cursor),
+                MskKeyCode::Interrupt => self.handle_interrupt(history_cursor, history.len()),
             },
         }
     }
+    // 从历史里取出的多行命令带着换行，光标不跨行移动
     fn handle_arrow_right(&mut self) -> Option<String> {
-        if self.cursor < self.buffer.len() {
+        if self.cursor < self.buffer.len() && self.buffer[self.cursor] != '\n' {
             self.cursor += 1;
-            let _ = write!(io::stdout(), "\x1b[C");
+            let _ = write!(terminal(), "\x1b[C");
         }
         None
     }
     fn handle_arrow_left(&mut self) -> Option<String> {
-        if self.cursor > 0 {
+        if self.cursor > 0 && self.buffer[self.cursor - 1] != '\n' {
             self.cursor -= 1;
-            let _ = write!(io::stdout(), "\x1b[D");
+            let _ = write!(terminal(), "\x1b[D");
         }
         None
     }
     fn handle_backsapce(&mut self) -> Option<String> {
-        if self.cursor > 0 {
+        if self.cursor > 0 && self.buffer[self.cursor - 1] == '\n' {
+            // 删掉换行要把两行合起来，只在行尾时支持，整个重画
+            if self.cursor == self.buffer.len() {
+                let mut content: String = self.buffer.iter().collect();
+                content.pop();
+                self.replace_buffer(&content);
+            }
+        } else if self.cursor > 0 {
             self.buffer.remove(self.cursor - 1);
             self.cursor -= 1;
-            let _ = write!(io::stdout(), "\x08\x1b[P");
+            let _ = write!(terminal(), "\x08\x1b[P");
         }
         None
     }
-    fn handle_return(&mut self, history_cursor: &mut usize, len: usize) -> Option<String> {
+    // 输入还不完整时 (引号没闭合、行尾是 \ 或 | && || 等) 显示 PS2 继续读下一行
+    // 完整之后把所有行合起来返回
+    fn handle_return(
+        &mut self,
+        history_cursor: &mut usize,
+        len: usize,
+        ps2: &str,
+    ) -> Option<String> {
         *history_cursor = len + 1;
         let line: String = self.buffer.iter().collect();
         self.buffer.clear();
         self.cursor = 0;
-        let _ = write!(io::stdout(), "\r\n");
-        Some(line)
+        let _ = write!(terminal(), "\r\n");
+        if self.pending.push_line(&line) {
+            self.prompt = ps2.to_string();
+            let _ = write!(terminal(), "{}", self.prompt);
+            return None;
+        }
+        self.prompt = PS1.to_string();
+        Some(self.pending.take())
+    }
+    // Ctrl-C：丢掉正在编辑的内容，换行后重新显示提示符
+    fn handle_interrupt(&mut self, history_cursor: &mut usize, len: usize) -> Option<String> {
+        *history_cursor = len + 1;
+        self.buffer.clear();
+        self.cursor = 0;
+        self.has_tab = false;
+        self.pending = InputBuffer::new();
+        self.prompt = PS1.to_string();
+        let _ = write!(terminal(), "^C\r\n{}", self.prompt);
+        None
     }
     // TODO: 以后支持中文逻辑
     fn retype_buffer(&mut self) -> Option<String> {
@@ -110,13 +155,13 @@ impl LineEditor {
                 // 注意：这里回显不能只 write_byte，要 write_str
                 let mut temp_buf = [0u8; 4];
                 let s = c.encode_utf8(&mut temp_buf);
-                let _ = write!(io::stdout(), "{}", s);
+                let _ = write!(terminal(), "{}", s);
             } else {
-                let _ = write!(io::stdout(), "\x1b[@");
+                let _ = write!(terminal(), "\x1b[@");
 
                 let mut temp_buf = [0u8; 4];
                 let s = c.encode_utf8(&mut temp_buf);
-                let _ = write!(io::stdout(), "{}", s);
+                let _ = write!(terminal(), "{}", s);
             }
         }
         None
@@ -131,24 +176,36 @@ impl LineEditor {
             // 注意：这里回显不能只 write_byte，要 write_str
             let mut temp_buf = [0u8; 4];
             let s = c.encode_utf8(&mut temp_buf);
-            let _ = write!(io::stdout(), "{}", s);
+            let _ = write!(terminal(), "{}", s);
         } else {
-            let _ = write!(io::stdout(), "\x1b[@");
+            let _ = write!(terminal(), "\x1b[@");
             self.buffer.insert(self.cursor, c);
             self.cursor += 1;
 
             let mut temp_buf = [0u8; 4];
             let s = c.encode_utf8(&mut temp_buf);
-            let _ = write!(io::stdout(), "{}", s);
+            let _ = write!(terminal(), "{}", s);
         }
         None
     }
     /// TODO: 未来应该在这个构建
-    fn handle_tab(&mut self, all_commands: &Trie) -> Option<String> {
+    fn handle_tab(
+        &mut self,
+        all_commands: &Trie,
+        aliases: &BTreeMap<String, String>,
+    ) -> Option<String> {
         let prefix: String = self.buffer.iter().collect();
         // println!("1{}\r", prefix);
         // println!("{:?}/r", all_commands);
-        if let Some(mut commands) = all_commands.search_prefix(&prefix) {
+        // 别名随时可能增删，不放进 Trie，每次补全时再合进来
+        let mut candidates = all_commands.search_prefix(&prefix).unwrap_or_default();
+        for name in aliases.keys() {
+            if name.starts_with(&prefix) && !candidates.contains(name) {
+                candidates.push(name.clone());
+            }
+        }
+        if !candidates.is_empty() {
+            let mut commands = candidates;
             // println!("2{:?}\r", commands);
             let longest_prefix_opt = longest_common_prefix(&commands);
             // println!("3{:?}\r", longest_prefix_opt);
@@ -158,7 +215,7 @@ impl LineEditor {
                 if suffix.is_empty() {
                     // 如果第一次按下tab就响铃
                     if !self.has_tab {
-                        let _ = write!(io::stdout(), "{}", '\x07');
+                        let _ = write!(terminal(), "\x07");
                         self.has_tab = true;
                     } else {
                         // 重新设置tab状态
@@ -166,8 +223,8 @@ impl LineEditor {
                         // TODO: 以后不用换行来补全，直接在命令下方展示
                         commands.sort();
                         let tips = commands.join("  ");
-                        let _ = write!(io::stdout(), "\r\n{}\r\n", tips);
-                        let _ = write!(io::stdout(), "$ ");
+                        let _ = write!(terminal(), "\r\n{}\r\n", tips);
+                        let _ = write!(terminal(), "{}", self.prompt);
                         self.retype_buffer();
                     }
                     return None;
@@ -181,14 +238,19 @@ impl LineEditor {
                 }
             }
         } else {
-            let _ = write!(io::stdout(), "{}", '\x07');
+            let _ = write!(terminal(), "\x07");
         }
         None
     }
     fn replace_buffer(&mut self, new_content: &str) {
         // 清空视觉层：先把光标移到行首，然后清除整行
-        // \r: 回行首, \x1b[K: 清除光标后所有内容
-        let _ = write!(io::stdout(), "\r\x1b[K$ ");
+        // \r: 回行首, \x1b[J: 清除光标后所有内容
+        // 多行的内容占了好几行，先回到第一行 (光标总在最后一行)
+        let rows = self.buffer.iter().filter(|&&c| c == '\n').count();
+        if rows > 0 {
+            let _ = write!(terminal(), "\x1b[{}A", rows);
+        }
+        let _ = write!(terminal(), "\r\x1b[J{}", self.prompt);
 
         // 更新内存层
         self.buffer.clear();
@@ -197,11 +259,13 @@ impl LineEditor {
         }
         self.cursor = self.buffer.len(); // 光标置于末尾
 
-        // 重新绘制新内容
-        let _ = write!(io::stdout(), "{}", new_content);
-        let _ = io::stdout().flush();
+        // 重新绘制新内容，raw mode 下换行要自己回到行首
+        let _ = write!(terminal(), "{}", new_content.replace('\n', "\r\n"));
+        let _ = terminal().flush();
     }
-    fn handle_up(&mut self, history: &Vec<String>, history_cursor: &mut usize) -> Option<String> {
+    fn handle_up(&mut self, history: &[String], history_cursor: &mut usize) -> Option<String> {
+        // 空行和续行不进历史，游标可能比历史记录还多出一个
+        *history_cursor = (*history_cursor).min(history.len());
         if *history_cursor > 0 {
             *history_cursor -= 1;
             let command = &history[*history_cursor];
@@ -210,8 +274,8 @@ impl LineEditor {
         None
     }
 
-    fn handle_down(&mut self, history: &Vec<String>, history_cursor: &mut usize) -> Option<String> {
-        if *history_cursor < history.len() - 1 {
+    fn handle_down(&mut self, history: &[String], history_cursor: &mut usize) -> Option<String> {
+        if *history_cursor + 1 < history.len() {
             *history_cursor += 1;
             let command = &history[*history_cursor];
             self.replace_buffer(command);
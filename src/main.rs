@@ -1,65 +1,205 @@
 use crate::autocompletion::collect_all_commands;
-use crate::command::{parse_input, run_pipeline};
+use crate::command::{parse_input, run_list};
+use crate::job::take_interrupt;
+use crate::script::{run_source, source_file};
 use crate::state::ShellState;
-use crate::terminal_io::get_event;
+use crate::terminal_io::{IoContext, MskEvent, MskKeyCode, get_event, terminal};
 use crate::{line_editor::LineEditor, raw_mode_guard::RawModeGuard};
 
-use std::io::{self, Write};
+use std::env;
+use std::fs::File;
+use std::io::{self, IsTerminal, Write};
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::process::exit;
+use std::thread;
 
+mod arithmetic;
+mod ast;
 mod autocompletion;
+mod brace;
 mod command;
+mod conditional;
+mod glob;
+mod job;
 mod lexer;
 mod line_editor;
 mod navigation;
 mod parser;
+mod pattern;
 mod raw_mode_guard;
+mod script;
 mod state;
 mod terminal_io;
 mod trie;
 
+// 执行命令是递归的 (函数、嵌套的复合命令)，默认的 8MB 栈撑不了几层递归调用
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+
 fn main() -> Result<(), io::Error> {
-    let _raw_guard = RawModeGuard::new()?;
+    // 信号处理和终端设置都是整个进程共享的，换到另一个线程里执行没有区别
+    let shell = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run_shell)?;
+    match shell.join() {
+        Ok(result) => result,
+        Err(_) => exit(1),
+    }
+}
+
+fn run_shell() -> Result<(), io::Error> {
+    let mut state = ShellState::new();
+    let mut args = env::args().skip(1).peekable();
+    // msksh [-l] [--norc] [-c 命令 [名字 [参数...]]] [脚本 [参数...]]
+    let mut command = None;
+    // argv[0] 以 - 开头说明是被 login 启动的
+    let mut login = state.shell_name.starts_with('-');
+    let mut norc = false;
+    while let Some(arg) = args.next_if(|a| a.starts_with('-') && a.len() > 1) {
+        match arg.as_str() {
+            "--" => break,
+            "-c" => match args.next() {
+                Some(c) => command = Some(c),
+                None => {
+                    eprintln!("msksh: -c: option requires an argument");
+                    exit(2);
+                }
+            },
+            "-l" | "--login" => login = true,
+            "--norc" => norc = true,
+            _ => {
+                eprintln!("msksh: {}: invalid option", arg);
+                exit(2);
+            }
+        }
+    }
+    let script = match command {
+        Some(_) => None,
+        None => args.next(),
+    };
+    // -c 后面的第一个参数是 $0，脚本的 $0 就是脚本路径，剩下的都是位置参数
+    let name = match command {
+        Some(_) => args.next(),
+        None => script.clone(),
+    };
+    if let Some(name) = name {
+        state.shell_name = name;
+    }
+    state.positional = args.collect();
+    let interactive = command.is_none() && script.is_none() && io::stdin().is_terminal();
+    // 交互时先接管终端再进入 raw mode，这样记下的才是 shell 正常的终端设置
+    state.init(interactive)?;
+    if login {
+        load_startup_file(".msksh_profile", &mut state);
+    }
+    if interactive && !norc {
+        load_startup_file(".mskshrc", &mut state);
+    }
+    if let Some(command) = command {
+        let status = run_source(
+            &mut command.as_bytes(),
+            "msksh",
+            &mut state,
+            &IoContext::new(),
+        )?;
+        exit(status);
+    }
+    if let Some(path) = script {
+        match source_file(Path::new(&path), &mut state, &IoContext::new()) {
+            Ok(status) => exit(status),
+            Err(e) => {
+                eprintln!("msksh: {}", e);
+                exit(127);
+            }
+        }
+    }
+    // 标准输入不是终端 (比如 echo ls | msksh)：不进 raw mode，直接按行读
+    if !interactive {
+        // 不经过 io::stdin() 的缓冲区，读了多少就消耗多少
+        let mut stdin = File::from(io::stdin().as_fd().try_clone_to_owned()?);
+        exit(run_source(
+            &mut stdin,
+            "msksh",
+            &mut state,
+            &IoContext::new(),
+        )?);
+    }
+    run_interactive(state)
+}
+
+// 执行家目录下的启动文件：不存在就跳过，里面的错误只报告，不影响启动
+fn load_startup_file(name: &str, state: &mut ShellState) {
+    let Some(home) = env::var_os("HOME") else {
+        return;
+    };
+    let path = Path::new(&home).join(name);
+    if !path.is_file() {
+        return;
+    }
+    if let Err(e) = source_file(&path, state, &IoContext::new()) {
+        eprintln!("msksh: {}", e);
+    }
+}
+
+fn run_interactive(mut state: ShellState) -> Result<(), io::Error> {
+    let raw_guard = RawModeGuard::new()?;
     let all_commands = collect_all_commands();
     let mut editor = LineEditor::new();
-    let mut state = ShellState::new();
-    state.init()?;
-    write!(io::stdout(), "$ ")?;
-    io::stdout().flush()?;
+    write!(terminal(), "$ ")?;
+    terminal().flush()?;
     loop {
         if let Some(event) = get_event() {
+            // 提示符下按 Ctrl-C 放弃当前输入，和 bash 一样把 $? 设成 130
+            if matches!(event, MskEvent::Key(MskKeyCode::Interrupt)) {
+                state.last_status = 130;
+            }
+            // 输入不完整时用 PS2 提示继续输入，多行合成一条命令返回
+            let ps2 = state.get_var("PS2").unwrap_or_else(|| "> ".to_string());
             if let Some(input) = editor.handle_event(
                 event,
                 &all_commands,
+                &state.aliases,
                 &state.history,
                 &mut state.history_cursor,
+                &ps2,
             ) {
-                let cmd_opt = parse_input(&input);
-                let cmd;
-                match cmd_opt {
-                    None => {
-                        // 空行，继续读取下一行vv
-                        write!(io::stdout(), "\r")?;
-                        write!(io::stdout(), "$ ")?;
-                        io::stdout().flush()?;
-                        continue;
+                // 执行期间关闭 raw mode，子进程和命令替换都需要正常的终端
+                let cooked = raw_guard.suspend();
+                // 空行不执行也不记入历史
+                match parse_input(&input, &state.aliases) {
+                    Ok(Some(list)) => {
+                        state.add_history(input);
+                        // 上一行被 Ctrl-C 打断留下的标记不影响这一行
+                        state.flow = None;
+                        take_interrupt();
+                        if let Err(e) = run_list(&list, &mut state, &IoContext::new()) {
+                            // 展开出错：报告错误，这一行剩下的命令不再执行
+                            eprintln!("msksh: {}", e);
+                            state.last_status = 1;
+                        }
                     }
-                    Some(c) => {
-                        cmd = c;
+                    Ok(None) => {}
+                    // 语法错误：指出出错的位置，整行都不执行，但照样记入历史方便修改
+                    Err(e) => {
+                        eprintln!("msksh: {}", e);
+                        eprintln!("{}", e.caret(&input));
                         state.add_history(input);
+                        state.last_status = 2;
                     }
-                };
-                if let Err(_) =
-                    run_pipeline(cmd, &mut state.history, &mut state.history_written_count)
-                {
-                    break;
                 }
-                write!(io::stdout(), "\r")?;
-                write!(io::stdout(), "$ ")?;
+                // 在下一个提示符之前报告后台作业的状态变化
+                for line in state.jobs.take_notifications() {
+                    eprintln!("{}", line);
+                }
+                drop(cooked);
+                // 命令的输出可能还在 stdout 的缓冲区里，要先于提示符写出去
+                io::stdout().flush()?;
+                write!(terminal(), "\r")?;
+                write!(terminal(), "$ ")?;
             }
 
             // 每处理完一个字节，刷新一下缓冲区,保证回显输出
-            io::stdout().flush()?;
+            terminal().flush()?;
         }
     }
-    Ok(())
 }
@@ -1,4 +1,6 @@
-use std::{env, path::PathBuf};
+use std::{env, io, path::PathBuf};
+
+use crate::state::ShellState;
 
 pub fn get_current_working_dir() -> String {
     match env::current_dir() {
@@ -9,7 +11,9 @@ pub fn get_current_working_dir() -> String {
         }
     }
 }
-pub fn change_directory(new_dir: &str) {
+// 失败时由调用者报错，cd 的退出码是 1
+// 成功时更新 PWD 和 OLDPWD，并且导出给子进程
+pub fn change_directory(new_dir: &str, state: &mut ShellState) -> io::Result<()> {
     // let path = if new_dir == "~" {
     //     match env::var("HOME") {
     //         Ok(path) => PathBuf::from(path),
@@ -30,11 +34,13 @@ pub fn change_directory(new_dir: &str) {
     // } else {
     //     PathBuf::from(new_dir)
     // };
+    let old_dir = get_current_working_dir();
     let path = PathBuf::from(new_dir);
-    match env::set_current_dir(&path) {
-        Ok(_) => {}
-        Err(_e) => {
-            eprintln!("cd: {}: No such file or directory", new_dir);
-        }
+    env::set_current_dir(&path)?;
+    for (name, value) in [("OLDPWD", old_dir), ("PWD", get_current_working_dir())] {
+        // readonly 的变量保持原样，目录照样切换
+        let _ = state.set_var(name, &value);
+        state.vars.entry(name.to_string()).or_default().exported = true;
     }
+    Ok(())
 }
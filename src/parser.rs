@@ -1,84 +1,1059 @@
-use std::{env, panic, path::PathBuf};
+use std::ops::Range;
+use std::path::PathBuf;
 
-use crate::lexer::{Args, Token};
+use crate::arithmetic::{ArithError, evaluate};
+use crate::brace::brace_expand;
+use crate::command::capture_output;
+use crate::conditional::escape_regex;
+use crate::glob::glob_expand;
+use crate::lexer::{Args, Token, heredoc_generate, word_generate};
+use crate::pattern::{escape_pattern, pattern_matches};
+use crate::state::{ReadonlyError, ShellState};
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RedirectionMode {
-    Overwrite, // >  (O_TRUNC)
+    Overwrite, // >  (O_TRUNC)，noclobber 时不覆盖已有文件
+    Clobber,   // >| 无视 noclobber 强制覆盖
     Append,    // >> (O_APPEND)
+    Read,      // <
+    ReadWrite, // <>
 }
 
-// 2. 重定向的目标 (将来可以支持 &1 这种 FD 重定向)
+// 2. 重定向的目标
 #[derive(Debug, Clone, PartialEq)]
 pub enum RedirectionTarget {
     File(PathBuf), // 文件路径
-    Fd(i32),       // 文件描述符 (比如 2>&1) -> 这一步你可以先不实现，但位置留好
+    Fd(i32),       // 复制另一个文件描述符 (比如 2>&1)
+    Close,         // 关闭 (比如 2>&-)
+    Text(String),  // here-document / here-string 展开后的内容
 }
 
 // 3. 单个重定向动作描述符
 #[derive(Debug, Clone)]
 pub struct Redirection {
-    pub source_fd: i32,            // 谁要被重定向？(1=stdout, 2=stderr)
+    pub source_fd: i32,            // 谁要被重定向？(0=stdin, 1=stdout, 2=stderr)
     pub target: RedirectionTarget, // 去哪里？
     pub mode: RedirectionMode,     // 怎么去？(覆盖还是追加)
 }
-pub fn parse_tokens_to_args(tokens: Vec<Token>) -> (Vec<String>, Option<Vec<Redirection>>) {
+// 展开阶段的错误，比如 ${x:?msg}
+#[derive(Debug, thiserror::Error)]
+pub enum ExpansionError {
+    #[error("{0}: {1}")]
+    ParamUnset(String, String),
+    #[error("${{{0}}}: bad substitution")]
+    BadSubstitution(String),
+    #[error("${0}: cannot assign in this way")]
+    CannotAssign(String),
+    #[error("{0}: {1}")]
+    Arith(String, ArithError),
+    #[error("no match: {0}")]
+    NoMatch(String),
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+    #[error("{0}")]
+    Syntax(#[from] ParseError),
+    #[error("{0}")]
+    Readonly(#[from] ReadonlyError),
+    #[error("{0}: unbound variable")]
+    Unbound(String),
+    #[error("{0}: substring expression < 0")]
+    NegativeSubstring(String),
+}
+
+// 语法错误，范围是出错位置在输入里的字节范围
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("syntax error near unexpected token `{0}'")]
+    UnexpectedToken(String, Range<usize>),
+    #[error("syntax error: unexpected end of file")]
+    UnexpectedEof(Range<usize>),
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    Unterminated(char, Range<usize>),
+}
+
+impl ParseError {
+    // 输入提前结束导致的错误，交互时可以继续读下一行补全
+    pub fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            ParseError::UnexpectedEof(_) | ParseError::Unterminated(..)
+        )
+    }
+
+    pub fn span(&self) -> &Range<usize> {
+        match self {
+            ParseError::UnexpectedToken(_, span)
+            | ParseError::UnexpectedEof(span)
+            | ParseError::Unterminated(_, span) => span,
+        }
+    }
+
+    // 出错的那一行，下面用 ^ 标出位置，比如
+    // echo > |
+    //        ^
+    pub fn caret(&self, input: &str) -> String {
+        let span = self.span();
+        let start = span.start.min(input.len());
+        let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+        // tab 原样保留，其它字符换成空格，这样 ^ 才能对齐
+        let indent: String = input[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end = span.end.clamp(start, line_end);
+        let width = input[start..end].chars().count().max(1);
+        format!(
+            "{}\n{}{}",
+            &input[line_start..line_end],
+            indent,
+            "^".repeat(width)
+        )
+    }
+}
+
+// ${...} 的结构化表示
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamExpansion {
+    pub name: String,
+    pub op: ParamOp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamOp {
+    Plain,  // ${var}
+    Length, // ${#var}
+    // check_null 为 true 时对应带冒号的写法，空串也视为未设置
+    Default {
+        word: String,
+        check_null: bool,
+    }, // ${var:-word}
+    Assign {
+        word: String,
+        check_null: bool,
+    }, // ${var:=word}
+    Error {
+        word: String,
+        check_null: bool,
+    }, // ${var:?word}
+    Alternative {
+        word: String,
+        check_null: bool,
+    }, // ${var:+word}
+    RemovePrefix {
+        pattern: String,
+        longest: bool,
+    }, // ${var#pat} ${var##pat}
+    RemoveSuffix {
+        pattern: String,
+        longest: bool,
+    }, // ${var%pat} ${var%%pat}
+    Replace {
+        pattern: String,
+        replacement: String,
+        mode: ReplaceMode,
+    }, // ${var/pat/rep}
+    Substring {
+        offset: String,
+        length: Option<String>,
+    }, // ${var:off:len}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaceMode {
+    First,  // ${var/pat/rep}
+    All,    // ${var//pat/rep}
+    Prefix, // ${var/#pat/rep}
+    Suffix, // ${var/%pat/rep}
+}
+
+pub fn parse_tokens_to_args(
+    tokens: Vec<Token>,
+    state: &mut ShellState,
+) -> Result<(Vec<String>, Option<Vec<Redirection>>), ExpansionError> {
     let mut args = Vec::new();
     let mut redirections = Vec::new();
     let mut tokens_iter = tokens.into_iter();
     while let Some(token) = tokens_iter.next() {
         match token {
-            // 这里以后要用正则或其他办法匹配形如100>的op
+            // 重定向操作符：可选的 fd 数字加上 > >| >> >& &> &>> < <> <& << <<- <<<
             Token::Op(op) => {
-                let (source_fd, mode) = match op.as_str() {
-                    ">" | "1>" => (1, RedirectionMode::Overwrite),
-                    ">>" | "1>>" => (1, RedirectionMode::Append),
-                    "2>" => (2, RedirectionMode::Overwrite),
-                    "2>>" => (2, RedirectionMode::Append),
-                    _ => todo!(),
+                let digits = op.chars().take_while(|c| c.is_ascii_digit()).count();
+                let fd = op[..digits].parse::<i32>().ok();
+                // parse_input 已经检查过语法，这里只是以防万一
+                let word = match tokens_iter.next() {
+                    None => return Err(ParseError::UnexpectedToken("newline".into(), 0..0).into()),
+                    Some(token @ (Token::Op(_) | Token::Arith(_))) => {
+                        return Err(ParseError::UnexpectedToken(token.to_string(), 0..0).into());
+                    }
+                    // here-document 的正文只有在结束符没加引号时才展开
+                    Some(Token::HereDoc(doc)) => {
+                        let body = if doc.expand {
+                            consolidate_args(heredoc_generate(&doc.body), state)?
+                        } else {
+                            doc.body
+                        };
+                        redirections.push(Redirection {
+                            source_fd: fd.unwrap_or(0),
+                            target: RedirectionTarget::Text(body),
+                            mode: RedirectionMode::Read,
+                        });
+                        continue;
+                    }
+                    Some(Token::Word(items)) => consolidate_args(items, state)?,
+                };
+                let file = |source_fd, mode| Redirection {
+                    source_fd,
+                    target: RedirectionTarget::File(PathBuf::from(&word)),
+                    mode,
                 };
-                let target_token = tokens_iter.next().expect("Redirect后面必须有参数");
-                match target_token {
-                    Token::Op(_) => panic!("Redirect后面参数只能是文件路径或文件描述符"),
-                    Token::Word(items) => {
-                        // TODO: target不会只是pathbuf
-                        let redirection = Redirection {
-                            source_fd,
-                            target: RedirectionTarget::File(consolidate_args(items).into()),
+                // &>file 等价于 >file 2>&1
+                let both = |mode| {
+                    [
+                        file(1, mode),
+                        Redirection {
+                            source_fd: 2,
+                            target: RedirectionTarget::Fd(1),
                             mode,
+                        },
+                    ]
+                };
+                match &op[digits..] {
+                    "<" => redirections.push(file(fd.unwrap_or(0), RedirectionMode::Read)),
+                    "<>" => redirections.push(file(fd.unwrap_or(0), RedirectionMode::ReadWrite)),
+                    // 没有读到正文的 here-document (输入在结束符之前就结束了)
+                    "<<" | "<<-" => redirections.push(Redirection {
+                        source_fd: fd.unwrap_or(0),
+                        target: RedirectionTarget::Text(String::new()),
+                        mode: RedirectionMode::Read,
+                    }),
+                    "<<<" => redirections.push(Redirection {
+                        source_fd: fd.unwrap_or(0),
+                        target: RedirectionTarget::Text(format!("{}\n", word)),
+                        mode: RedirectionMode::Read,
+                    }),
+                    ">" => redirections.push(file(fd.unwrap_or(1), RedirectionMode::Overwrite)),
+                    ">|" => redirections.push(file(fd.unwrap_or(1), RedirectionMode::Clobber)),
+                    ">>" => redirections.push(file(fd.unwrap_or(1), RedirectionMode::Append)),
+                    "&>" => redirections.extend(both(RedirectionMode::Overwrite)),
+                    "&>>" => redirections.extend(both(RedirectionMode::Append)),
+                    ">&" | "<&" => {
+                        let default_fd = if op.ends_with("<&") { 0 } else { 1 };
+                        let target = if word == "-" {
+                            RedirectionTarget::Close
+                        } else if let Ok(target_fd) = word.parse::<i32>() {
+                            RedirectionTarget::Fd(target_fd)
+                        } else if fd.is_none() && default_fd == 1 {
+                            // >&file 和 &>file 一样
+                            redirections.extend(both(RedirectionMode::Overwrite));
+                            continue;
+                        } else {
+                            return Err(ExpansionError::AmbiguousRedirect(word));
                         };
-                        redirections.push(redirection);
+                        redirections.push(Redirection {
+                            source_fd: fd.unwrap_or(default_fd),
+                            target,
+                            mode: RedirectionMode::Overwrite,
+                        });
                     }
-                };
+                    other => {
+                        return Err(ParseError::UnexpectedToken(other.to_string(), 0..0).into());
+                    }
+                }
             }
-            Token::Word(items) => args.push(consolidate_args(items)),
+            // 先做花括号展开，每个结果再各自展开、分词、通配
+            Token::Word(items) => {
+                for word in brace_expand(items) {
+                    args.extend(expand_fields(word, state)?);
+                }
+            }
+            // ((expr)) 等价于 let "expr"
+            Token::Arith(expr) if args.is_empty() => {
+                args.push("let".to_string());
+                args.push(consolidate_args(word_generate(&expr), state)?);
+            }
+            Token::Arith(expr) => args.push(format!("(({}))", expr)),
+            // here-document 只会跟在 << 后面，上面已经处理过
+            Token::HereDoc(_) => {}
         }
     }
     if redirections.is_empty() {
-        (args, None)
+        Ok((args, None))
+    } else {
+        Ok((args, Some(redirections)))
+    }
+}
+// 展开成单个字符串，不做分词 (重定向目标、${var:-word} 里的 word)
+pub fn consolidate_args(args: Vec<Args>, state: &mut ShellState) -> Result<String, ExpansionError> {
+    args.into_iter().map(|arg| expand_arg(arg, state)).collect()
+}
+// 展开命令行里的一个单词：未加引号的展开结果要按 IFS 分词，可能得到 0 个或多个参数
+// 分词后再做文件名展开
+fn expand_fields(args: Vec<Args>, state: &mut ShellState) -> Result<Vec<String>, ExpansionError> {
+    let ifs = state.get_var("IFS").unwrap_or_else(|| " \t\n".to_string());
+    let mut fields = FieldBuilder::default();
+    for arg in args {
+        match arg {
+            Args::Raw(s) => match tilde_prefix(&s, state) {
+                Some((home, rest)) => {
+                    fields.push_quoted(&home);
+                    fields.push_unquoted(rest);
+                }
+                None => fields.push_unquoted(&s),
+            },
+            // "$@" 每个位置参数都是独立的参数
+            Args::Param(body, true) if body == "@" => {
+                let params = state.positional.clone();
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        fields.finish();
+                    }
+                    fields.push_quoted(param);
+                }
+            }
+            arg @ (Args::Param(_, false) | Args::Command(_, false) | Args::Arith(_, false)) => {
+                let value = expand_arg(arg, state)?;
+                fields.push_split(&value, &ifs);
+            }
+            arg => {
+                let value = expand_arg(arg, state)?;
+                fields.push_quoted(&value);
+            }
+        }
+    }
+    fields.finish();
+
+    let mut result = Vec::new();
+    for field in fields.fields {
+        let Some(pattern) = field.pattern else {
+            result.push(field.text);
+            continue;
+        };
+        let matches = glob_expand(&pattern, &state.options);
+        if !matches.is_empty() {
+            result.extend(matches);
+        } else if state.options.nullglob {
+            // nullglob：没有匹配的模式直接消失
+        } else if state.options.failglob {
+            return Err(ExpansionError::NoMatch(field.text));
+        } else {
+            // 默认保留模式原样
+            result.push(field.text);
+        }
+    }
+    Ok(result)
+}
+
+struct Field {
+    text: String,
+    // 含有未加引号的通配符时，用于文件名展开的模式
+    pattern: Option<String>,
+}
+
+#[derive(Default)]
+struct FieldBuilder {
+    fields: Vec<Field>,
+    current: String,
+    // 与 current 对应的模式串，加了引号的部分已经转义
+    pattern: String,
+    has_glob: bool,
+    // 当前参数是否已经存在 (哪怕是 "" 这样的空串)
+    has_content: bool,
+}
+impl FieldBuilder {
+    fn push_quoted(&mut self, s: &str) {
+        self.current.push_str(s);
+        self.pattern.push_str(&escape_pattern(s));
+        self.has_content = true;
+    }
+    fn push_unquoted(&mut self, s: &str) {
+        self.current.push_str(s);
+        self.pattern.push_str(s);
+        self.has_glob |= s.contains(['*', '?', '[']);
+        self.has_content |= !s.is_empty();
+    }
+    fn finish(&mut self) {
+        if self.has_content {
+            let pattern = std::mem::take(&mut self.pattern);
+            self.fields.push(Field {
+                text: std::mem::take(&mut self.current),
+                pattern: self.has_glob.then_some(pattern),
+            });
+            self.has_content = false;
+        }
+        self.pattern.clear();
+        self.has_glob = false;
+    }
+    // IFS 里的空白字符连续出现只算一次分隔，其它字符每个都是一次分隔
+    fn push_split(&mut self, value: &str, ifs: &str) {
+        if ifs.is_empty() {
+            self.push_unquoted(value);
+            return;
+        }
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            if !ifs.contains(c) {
+                self.push_unquoted(&c.to_string());
+                continue;
+            }
+            // 吃掉这一段连续的 IFS 空白
+            let mut hard_sep = !c.is_whitespace();
+            while let Some(&n) = chars.peek() {
+                if !ifs.contains(n) || (!n.is_whitespace() && hard_sep) {
+                    break;
+                }
+                hard_sep |= !n.is_whitespace();
+                chars.next();
+            }
+            if hard_sep {
+                // 非空白分隔符两侧都会产生参数，哪怕是空的
+                self.has_content = true;
+            }
+            self.finish();
+        }
+    }
+}
+// ~ 和 ~/ 开头的片段替换成 HOME，返回 (HOME, 剩余部分)
+fn tilde_prefix<'a>(s: &'a str, state: &ShellState) -> Option<(String, &'a str)> {
+    if s == "~" || s.starts_with("~/") {
+        // 拼接: /home/user + /Downloads
+        state.get_var("HOME").map(|home| (home, &s[1..]))
     } else {
-        (args, Some(redirections))
+        None
     }
 }
-fn consolidate_args(args: Vec<Args>) -> String {
-    args.into_iter().map(|arg| expand_arg(arg)).collect()
+// 表达式里先做参数展开和命令替换，再交给算术求值
+pub fn expand_arith(expr: &str, state: &mut ShellState) -> Result<i64, ExpansionError> {
+    let expanded = expand_word(expr, state)?;
+    evaluate(&expanded, state).map_err(|e| ExpansionError::Arith(expanded.trim().to_string(), e))
 }
-fn expand_arg(arg: Args) -> String {
+// 命令替换出错时只报告错误，结果按空串处理
+fn expand_command(cmd: &str, state: &mut ShellState) -> String {
+    match capture_output(cmd, state) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("{}{}", state.error_prefix(), e);
+            String::new()
+        }
+    }
+}
+fn expand_arg(arg: Args, state: &mut ShellState) -> Result<String, ExpansionError> {
     match arg {
-        Args::Raw(s) => {
-            if s == "~" {
-                env::var("HOME").unwrap_or_else(|_| s.clone())
-            } else if s.starts_with("~/") {
-                if let Ok(home) = env::var("HOME") {
-                    // 拼接: /home/user + /Downloads
-                    format!("{}{}", home, &s[1..])
+        Args::Raw(s) => match tilde_prefix(&s, state) {
+            Some((home, rest)) => Ok(format!("{}{}", home, rest)),
+            None => Ok(s),
+        },
+        Args::SingleQuotes(s) => Ok(s),
+        Args::DoubleQuotes(s) => Ok(s),
+        Args::Param(body, _) => expand_param(&body, state),
+        Args::Command(cmd, _) => Ok(expand_command(&cmd, state)),
+        Args::Arith(expr, _) => Ok(expand_arith(&expr, state)?.to_string()),
+    }
+}
+// 展开 ${var:-word} 这类操作符里的 word
+fn expand_word(word: &str, state: &mut ShellState) -> Result<String, ExpansionError> {
+    consolidate_args(word_generate(word), state)
+}
+// 展开模式串：引号里的内容要按字面匹配，所以给通配符加上转义
+fn expand_pattern(word: &str, state: &mut ShellState) -> Result<String, ExpansionError> {
+    expand_pattern_args(word_generate(word), state)
+}
+// 同上，case 的模式已经切分好了
+pub fn expand_pattern_args(
+    args: Vec<Args>,
+    state: &mut ShellState,
+) -> Result<String, ExpansionError> {
+    let mut pattern = String::new();
+    for arg in args {
+        match arg {
+            Args::Raw(s) => pattern.push_str(&s),
+            Args::SingleQuotes(s) | Args::DoubleQuotes(s) => pattern.push_str(&escape_pattern(&s)),
+            arg @ (Args::Param(_, quoted) | Args::Command(_, quoted) | Args::Arith(_, quoted)) => {
+                let value = expand_arg(arg, state)?;
+                if quoted {
+                    pattern.push_str(&escape_pattern(&value));
                 } else {
-                    s
+                    pattern.push_str(&value);
                 }
+            }
+        }
+    }
+    Ok(pattern)
+}
+// 同上，[[ =~ ]] 右边的正则：引号里的内容按字面匹配
+pub fn expand_regex_args(
+    args: Vec<Args>,
+    state: &mut ShellState,
+) -> Result<String, ExpansionError> {
+    let mut regex = String::new();
+    for arg in args {
+        match arg {
+            Args::Raw(s) => regex.push_str(&s),
+            Args::SingleQuotes(s) | Args::DoubleQuotes(s) => regex.push_str(&escape_regex(&s)),
+            arg @ (Args::Param(_, quoted) | Args::Command(_, quoted) | Args::Arith(_, quoted)) => {
+                let value = expand_arg(arg, state)?;
+                if quoted {
+                    regex.push_str(&escape_regex(&value));
+                } else {
+                    regex.push_str(&value);
+                }
+            }
+        }
+    }
+    Ok(regex)
+}
+
+fn is_special_param(name: &str) -> bool {
+    matches!(name, "?" | "$" | "!" | "#" | "@" | "*" | "-" | "0")
+}
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+// 从 ${} 的内容里切出参数名，剩下的部分交给操作符解析
+fn split_param_name(body: &str) -> (&str, &str) {
+    let end = match body.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => body
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(body.len()),
+        Some(c) if c.is_ascii_digit() => body
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(body.len()),
+        Some(c) if is_special_param(&c.to_string()) => c.len_utf8(),
+        _ => 0,
+    };
+    // 数组下标 NAME[i]
+    let end = match body[end..].find(']') {
+        Some(close) if end > 0 && body[end..].starts_with('[') => end + close + 1,
+        _ => end,
+    };
+    body.split_at(end)
+}
+// NAME[i] 拆成 (NAME, i)，目前只支持数字下标和 @、*
+fn split_subscript(name: &str) -> Option<(&str, &str)> {
+    let (base, index) = name.strip_suffix(']')?.split_once('[')?;
+    let valid = index == "@" || index == "*" || index.parse::<usize>().is_ok();
+    (is_valid_name(base) && valid).then_some((base, index))
+}
+// 找到第一个没被反斜杠转义的 /，用于切分 ${var/pat/rep}
+fn split_unescaped_slash(s: &str) -> (String, Option<String>) {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '/' if !escaped => return (s[..i].to_string(), Some(s[i + 1..].to_string())),
+            _ => escaped = false,
+        }
+    }
+    (s.to_string(), None)
+}
+
+pub fn parse_param_expansion(body: &str) -> Result<ParamExpansion, ExpansionError> {
+    let bad = || ExpansionError::BadSubstitution(body.to_string());
+    // ${#var}：注意 ${#} 本身是 $#
+    if let Some(name) = body.strip_prefix('#')
+        && (is_valid_name(name)
+            || is_special_param(name)
+            || name.parse::<usize>().is_ok()
+            || split_subscript(name).is_some())
+    {
+        return Ok(ParamExpansion {
+            name: name.to_string(),
+            op: ParamOp::Length,
+        });
+    }
+    let (name, rest) = split_param_name(body);
+    if name.is_empty() || name.contains('[') && split_subscript(name).is_none() {
+        return Err(bad());
+    }
+    let word = |w: &str| w.to_string();
+    let op = if rest.is_empty() {
+        ParamOp::Plain
+    } else if let Some(w) = rest.strip_prefix(":-") {
+        ParamOp::Default {
+            word: word(w),
+            check_null: true,
+        }
+    } else if let Some(w) = rest.strip_prefix(":=") {
+        ParamOp::Assign {
+            word: word(w),
+            check_null: true,
+        }
+    } else if let Some(w) = rest.strip_prefix(":?") {
+        ParamOp::Error {
+            word: word(w),
+            check_null: true,
+        }
+    } else if let Some(w) = rest.strip_prefix(":+") {
+        ParamOp::Alternative {
+            word: word(w),
+            check_null: true,
+        }
+    } else if let Some(w) = rest.strip_prefix(':') {
+        // ${var:off:len}
+        match w.split_once(':') {
+            Some((offset, length)) => ParamOp::Substring {
+                offset: word(offset),
+                length: Some(word(length)),
+            },
+            None => ParamOp::Substring {
+                offset: word(w),
+                length: None,
+            },
+        }
+    } else if let Some(w) = rest.strip_prefix('-') {
+        ParamOp::Default {
+            word: word(w),
+            check_null: false,
+        }
+    } else if let Some(w) = rest.strip_prefix('=') {
+        ParamOp::Assign {
+            word: word(w),
+            check_null: false,
+        }
+    } else if let Some(w) = rest.strip_prefix('?') {
+        ParamOp::Error {
+            word: word(w),
+            check_null: false,
+        }
+    } else if let Some(w) = rest.strip_prefix('+') {
+        ParamOp::Alternative {
+            word: word(w),
+            check_null: false,
+        }
+    } else if let Some(p) = rest.strip_prefix("##") {
+        ParamOp::RemovePrefix {
+            pattern: word(p),
+            longest: true,
+        }
+    } else if let Some(p) = rest.strip_prefix('#') {
+        ParamOp::RemovePrefix {
+            pattern: word(p),
+            longest: false,
+        }
+    } else if let Some(p) = rest.strip_prefix("%%") {
+        ParamOp::RemoveSuffix {
+            pattern: word(p),
+            longest: true,
+        }
+    } else if let Some(p) = rest.strip_prefix('%') {
+        ParamOp::RemoveSuffix {
+            pattern: word(p),
+            longest: false,
+        }
+    } else if let Some(r) = rest.strip_prefix('/') {
+        let (mode, r) = if let Some(r) = r.strip_prefix('/') {
+            (ReplaceMode::All, r)
+        } else if let Some(r) = r.strip_prefix('#') {
+            (ReplaceMode::Prefix, r)
+        } else if let Some(r) = r.strip_prefix('%') {
+            (ReplaceMode::Suffix, r)
+        } else {
+            (ReplaceMode::First, r)
+        };
+        let (pattern, replacement) = split_unescaped_slash(r);
+        ParamOp::Replace {
+            pattern,
+            replacement: replacement.unwrap_or_default(),
+            mode,
+        }
+    } else {
+        return Err(bad());
+    };
+    Ok(ParamExpansion {
+        name: name.to_string(),
+        op,
+    })
+}
+
+// 查找单个参数的值，None 表示未设置
+fn lookup_param(name: &str, state: &ShellState) -> Option<String> {
+    match name {
+        "?" => Some(state.last_status.to_string()),
+        "$" => Some(state.pid.to_string()),
+        "!" => state.last_bg_pid.map(|pid| pid.to_string()),
+        "#" => Some(state.positional.len().to_string()),
+        "0" => Some(state.shell_name.clone()),
+        // 还没有分词，$@ 和 $* 暂时都用空格拼成一个参数
+        "@" | "*" if state.positional.is_empty() => None,
+        "@" | "*" => Some(state.positional.join(" ")),
+        "-" => Some(state.options.flags()),
+        n if n.chars().all(|c| c.is_ascii_digit()) => n
+            .parse::<usize>()
+            .ok()
+            .and_then(|idx| idx.checked_sub(1))
+            .and_then(|idx| state.positional.get(idx))
+            .cloned(),
+        n => match split_subscript(n) {
+            Some((base, "@" | "*")) => {
+                let array = state.arrays.get(base)?;
+                (!array.is_empty()).then(|| array.join(" "))
+            }
+            Some((base, index)) => state
+                .arrays
+                .get(base)?
+                .get(index.parse::<usize>().ok()?)
+                .cloned(),
+            // 不带下标的数组名就是第 0 个元素
+            None => state
+                .get_var(n)
+                .or_else(|| state.arrays.get(n)?.first().cloned()),
+        },
+    }
+}
+// 是否应该使用操作符里的 word
+fn is_unset(value: &Option<String>, check_null: bool) -> bool {
+    match value {
+        None => true,
+        Some(v) => check_null && v.is_empty(),
+    }
+}
+fn expand_param(body: &str, state: &mut ShellState) -> Result<String, ExpansionError> {
+    let expansion = parse_param_expansion(body)?;
+    let name = expansion.name;
+    let value = lookup_param(&name, state);
+    // set -u：没有给默认值的未设置变量是错误，$@ 和 $* 除外
+    if value.is_none()
+        && state.options.nounset
+        && name != "@"
+        && name != "*"
+        && matches!(
+            expansion.op,
+            ParamOp::Plain
+                | ParamOp::Length
+                | ParamOp::RemovePrefix { .. }
+                | ParamOp::RemoveSuffix { .. }
+                | ParamOp::Replace { .. }
+                | ParamOp::Substring { .. }
+        )
+    {
+        return Err(ExpansionError::Unbound(name));
+    }
+    match expansion.op {
+        ParamOp::Plain => Ok(value.unwrap_or_default()),
+        ParamOp::Length => {
+            if name == "@" || name == "*" {
+                Ok(state.positional.len().to_string())
+            } else if let Some((base, "@" | "*")) = split_subscript(&name) {
+                Ok(state.arrays.get(base).map_or(0, |a| a.len()).to_string())
+            } else {
+                Ok(value.map_or(0, |v| v.chars().count()).to_string())
+            }
+        }
+        ParamOp::Default { word, check_null } => {
+            if is_unset(&value, check_null) {
+                expand_word(&word, state)
+            } else {
+                Ok(value.unwrap_or_default())
+            }
+        }
+        ParamOp::Assign { word, check_null } => {
+            if is_unset(&value, check_null) {
+                if !is_valid_name(&name) {
+                    return Err(ExpansionError::CannotAssign(name));
+                }
+                let new_value = expand_word(&word, state)?;
+                state.set_var(&name, &new_value)?;
+                Ok(new_value)
+            } else {
+                Ok(value.unwrap_or_default())
+            }
+        }
+        ParamOp::Error { word, check_null } => {
+            if is_unset(&value, check_null) {
+                let message = if word.is_empty() {
+                    "parameter null or not set".to_string()
+                } else {
+                    expand_word(&word, state)?
+                };
+                Err(ExpansionError::ParamUnset(name, message))
+            } else {
+                Ok(value.unwrap_or_default())
+            }
+        }
+        ParamOp::Alternative { word, check_null } => {
+            if is_unset(&value, check_null) {
+                Ok(String::new())
             } else {
-                s
+                expand_word(&word, state)
             }
         }
-        Args::SingleQuotes(s) => s,
-        Args::DoubleQuotes(s) => s,
+        ParamOp::RemovePrefix { pattern, longest } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(&pattern, state)?;
+            Ok(remove_prefix(&value, &pattern, longest))
+        }
+        ParamOp::RemoveSuffix { pattern, longest } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(&pattern, state)?;
+            Ok(remove_suffix(&value, &pattern, longest))
+        }
+        ParamOp::Replace {
+            pattern,
+            replacement,
+            mode,
+        } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(&pattern, state)?;
+            let replacement = expand_word(&replacement, state)?;
+            Ok(replace_pattern(&value, &pattern, &replacement, mode))
+        }
+        ParamOp::Substring { offset, length } => {
+            let value: Vec<char> = value.unwrap_or_default().chars().collect();
+            let len = value.len() as i64;
+            let offset = expand_arith(&offset, state)?;
+            let length = match length {
+                Some(length) => {
+                    let expr = expand_word(&length, state)?;
+                    let length = evaluate(&expr, state)
+                        .map_err(|e| ExpansionError::Arith(expr.trim().to_string(), e))?;
+                    Some((length, expr))
+                }
+                None => None,
+            };
+            // 偏移超出两端或者值为空时结果为空，这时不再检查长度
+            if len == 0 || offset > len || offset < -len {
+                return Ok(String::new());
+            }
+            // 负数偏移从末尾往前数
+            let start = if offset < 0 { len + offset } else { offset };
+            let end = match length {
+                // 负数长度表示距离末尾的位置，不能落在起点之前
+                Some((length, expr)) if length < 0 => {
+                    if len + length < start {
+                        return Err(ExpansionError::NegativeSubstring(expr.trim().to_string()));
+                    }
+                    len + length
+                }
+                Some((length, _)) => start.saturating_add(length),
+                None => len,
+            };
+            // 长度可以大到超出末尾，截到值的长度为止
+            let end = end.min(len);
+            Ok(value[start as usize..end as usize].iter().collect())
+        }
+    }
+}
+
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let candidates: Box<dyn Iterator<Item = usize>> = if longest {
+        Box::new((0..=chars.len()).rev())
+    } else {
+        Box::new(0..=chars.len())
+    };
+    for end in candidates {
+        let prefix: String = chars[..end].iter().collect();
+        if pattern_matches(pattern, &prefix) {
+            return chars[end..].iter().collect();
+        }
+    }
+    value.to_string()
+}
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    let candidates: Box<dyn Iterator<Item = usize>> = if longest {
+        Box::new(0..=chars.len())
+    } else {
+        Box::new((0..=chars.len()).rev())
+    };
+    for start in candidates {
+        let suffix: String = chars[start..].iter().collect();
+        if pattern_matches(pattern, &suffix) {
+            return chars[..start].iter().collect();
+        }
+    }
+    value.to_string()
+}
+// 从 start 开始找最长的匹配，返回匹配的结束位置
+fn longest_match_at(chars: &[char], start: usize, pattern: &str) -> Option<usize> {
+    (start..=chars.len()).rev().find(|&end| {
+        let candidate: String = chars[start..end].iter().collect();
+        pattern_matches(pattern, &candidate)
+    })
+}
+fn replace_pattern(value: &str, pattern: &str, replacement: &str, mode: ReplaceMode) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    match mode {
+        ReplaceMode::Prefix => match longest_match_at(&chars, 0, pattern) {
+            Some(end) => format!("{}{}", replacement, chars[end..].iter().collect::<String>()),
+            None => value.to_string(),
+        },
+        ReplaceMode::Suffix => {
+            for start in 0..=chars.len() {
+                let suffix: String = chars[start..].iter().collect();
+                if pattern_matches(pattern, &suffix) {
+                    return format!(
+                        "{}{}",
+                        chars[..start].iter().collect::<String>(),
+                        replacement
+                    );
+                }
+            }
+            value.to_string()
+        }
+        ReplaceMode::First | ReplaceMode::All => {
+            if pattern.is_empty() {
+                return value.to_string();
+            }
+            let mut result = String::new();
+            let mut i = 0;
+            let mut replaced = false;
+            while i < chars.len() {
+                if !(replaced && mode == ReplaceMode::First)
+                    && let Some(end) = longest_match_at(&chars, i, pattern)
+                    && end > i
+                {
+                    result.push_str(replacement);
+                    replaced = true;
+                    i = end;
+                    continue;
+                }
+                result.push(chars[i]);
+                i += 1;
+            }
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(body: &str) -> ParamExpansion {
+        parse_param_expansion(body).unwrap()
+    }
+
+    fn expand(body: &str, value: &str) -> Result<String, ExpansionError> {
+        let mut state = ShellState::new();
+        state.set_var("x", value).unwrap();
+        expand_param(body, &mut state)
+    }
+
+    #[test]
+    fn parses_operators() {
+        assert_eq!(param("x").op, ParamOp::Plain);
+        assert_eq!(param("#x").op, ParamOp::Length);
+        // ${#} 是 $#，${#-} 是对 $# 的默认值
+        assert_eq!(param("#").name, "#");
+        assert_eq!(param("#-").op, ParamOp::Length);
+        assert_eq!(
+            param("x:-a b").op,
+            ParamOp::Default {
+                word: "a b".into(),
+                check_null: true
+            }
+        );
+        assert_eq!(
+            param("x=v").op,
+            ParamOp::Assign {
+                word: "v".into(),
+                check_null: false
+            }
+        );
+        assert_eq!(
+            param("x##*/").op,
+            ParamOp::RemovePrefix {
+                pattern: "*/".into(),
+                longest: true
+            }
+        );
+        assert_eq!(
+            param("x%.*").op,
+            ParamOp::RemoveSuffix {
+                pattern: ".*".into(),
+                longest: false
+            }
+        );
+        assert_eq!(
+            param("x//a\\/b/c").op,
+            ParamOp::Replace {
+                pattern: "a\\/b".into(),
+                replacement: "c".into(),
+                mode: ReplaceMode::All
+            }
+        );
+        assert_eq!(
+            param("x/#a").op,
+            ParamOp::Replace {
+                pattern: "a".into(),
+                replacement: String::new(),
+                mode: ReplaceMode::Prefix
+            }
+        );
+        assert!(matches!(
+            parse_param_expansion(""),
+            Err(ExpansionError::BadSubstitution(_))
+        ));
+        assert!(matches!(
+            parse_param_expansion("x^"),
+            Err(ExpansionError::BadSubstitution(_))
+        ));
+    }
+
+    #[test]
+    fn parses_substrings() {
+        assert_eq!(
+            param("x:1:2").op,
+            ParamOp::Substring {
+                offset: "1".into(),
+                length: Some("2".into())
+            }
+        );
+        // 冒号后面跟空格才是负数偏移，:- 是默认值
+        assert_eq!(
+            param("x: -1").op,
+            ParamOp::Substring {
+                offset: " -1".into(),
+                length: None
+            }
+        );
+        assert!(matches!(param("x:-1").op, ParamOp::Default { .. }));
+    }
+
+    #[test]
+    fn substring_offsets() {
+        assert_eq!(expand("x:1", "abcdef").unwrap(), "bcdef");
+        assert_eq!(expand("x:1:3", "abcdef").unwrap(), "bcd");
+        assert_eq!(expand("x:2*2", "abcdef").unwrap(), "ef");
+        assert_eq!(expand("x:10", "abcdef").unwrap(), "");
+        assert_eq!(expand("x: -2", "abcdef").unwrap(), "ef");
+        assert_eq!(expand("x:(-6)", "abcdef").unwrap(), "abcdef");
+        // 负数偏移超出开头时为空，而不是从头开始
+        assert_eq!(expand("x: -10", "abc").unwrap(), "");
+        assert_eq!(expand("x: -10:2", "abc").unwrap(), "");
+        // 按字符而不是字节计算
+        assert_eq!(expand("x:1:2", "héllo").unwrap(), "él");
+    }
+
+    #[test]
+    fn substring_lengths() {
+        assert_eq!(expand("x:0:100", "abc").unwrap(), "abc");
+        assert_eq!(expand("x:1:0", "abc").unwrap(), "");
+        assert_eq!(expand("x:1:-1", "abcdef").unwrap(), "bcde");
+        assert_eq!(expand("x: -3:-1", "abcdef").unwrap(), "de");
+        assert_eq!(expand("x:2:-4", "abcdef").unwrap(), "");
+        assert!(matches!(
+            expand("x:3:-4", "abcdef"),
+            Err(ExpansionError::NegativeSubstring(expr)) if expr == "-4"
+        ));
+        assert_eq!(
+            expand("x:1:-5", "abc").unwrap_err().to_string(),
+            "-5: substring expression < 0"
+        );
+        // 偏移超出末尾或者值为空时直接为空，不检查长度
+        assert_eq!(expand("x:5:-1", "abc").unwrap(), "");
+        assert_eq!(expand("x:1:-1", "").unwrap(), "");
+        let mut state = ShellState::new();
+        assert_eq!(expand_param("msksh_unset:1:-1", &mut state).unwrap(), "");
+        // 很大的偏移和长度不能溢出
+        assert_eq!(expand("x:1:9223372036854775807", "abc").unwrap(), "bc");
+        assert_eq!(expand("x:9223372036854775807:1", "abc").unwrap(), "");
+        assert_eq!(
+            expand("x:9223372036854775807:9223372036854775807", "abc").unwrap(),
+            ""
+        );
+        assert_eq!(expand("x: -9223372036854775807:1", "abc").unwrap(), "");
     }
 }
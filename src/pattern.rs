@@ -0,0 +1,214 @@
+// shell 通配模式匹配：* ? [...]，反斜杠转义
+// ${var#pat}、case 分支、文件名展开都会用到
+
+// 模式里被引号包住的部分要按字面匹配，展开时会给元字符加上反斜杠
+pub fn escape_pattern(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// 模式里是否含有未转义的通配符
+pub fn has_glob_chars(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+// 去掉模式里的转义反斜杠，得到字面字符串
+pub fn unescape_pattern(pattern: &str) -> String {
+    let mut result = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                result.push(next);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// 整串匹配
+pub fn pattern_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // 最近一次 * 的位置，用于回溯
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                '*' => {
+                    // 连续的 * 等价于一个
+                    while p < pattern.len() && pattern[p] == '*' {
+                        p += 1;
+                    }
+                    star = Some((p, t));
+                    continue;
+                }
+                '?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                '[' => match match_bracket(pattern, p, text[t]) {
+                    Some((true, next_p)) => {
+                        p = next_p;
+                        t += 1;
+                        continue;
+                    }
+                    Some((false, _)) => {}
+                    // [ 没有闭合时按普通字符处理
+                    None if text[t] == '[' => {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                    None => {}
+                },
+                '\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // 当前字符匹配失败：回到上一个 *，让它多吃一个字符
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    // 文本用完了，剩下的模式只能全是 *
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// 匹配 [...] 字符类，返回 (是否匹配, ] 之后的位置)
+// 返回 None 说明这个 [ 没有闭合
+fn match_bracket(pattern: &[char], start: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let mut lo = pattern[i];
+        // 紧跟在 [ 或 [! 后面的 ] 是普通字符
+        if lo == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if lo == '\\' && i + 1 < pattern.len() {
+            i += 1;
+            lo = pattern[i];
+        }
+        // 范围 a-z
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            let hi = pattern[i + 2];
+            if lo <= c && c <= hi {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if lo == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(pattern_matches("*.rs", "main.rs"));
+        assert!(!pattern_matches("*.rs", "main.rsx"));
+        assert!(pattern_matches("a*b*c", "aXbYbZc"));
+        assert!(pattern_matches("?", "é"));
+        assert!(!pattern_matches("?", ""));
+        assert!(pattern_matches("**", ""));
+    }
+
+    #[test]
+    fn bracket_classes() {
+        assert!(pattern_matches("[abc]", "b"));
+        assert!(!pattern_matches("[abc]", "d"));
+        assert!(pattern_matches("[a-c][0-9]", "c7"));
+        assert!(!pattern_matches("[a-c]", "B"));
+        assert!(pattern_matches("[!a-c]", "d"));
+        assert!(!pattern_matches("[!a-c]", "a"));
+        assert!(pattern_matches("[^x]", "y"));
+        // 开头的 ] 和结尾的 - 是普通字符
+        assert!(pattern_matches("[]x]", "]"));
+        assert!(pattern_matches("[!]]", "a"));
+        assert!(!pattern_matches("[!]]", "]"));
+        assert!(pattern_matches("[a-]", "-"));
+        // 没有闭合的 [ 按字面匹配
+        assert!(pattern_matches("[ab", "[ab"));
+        assert!(!pattern_matches("[ab", "a"));
+    }
+
+    #[test]
+    fn escaped_metacharacters() {
+        assert!(pattern_matches("\\*", "*"));
+        assert!(!pattern_matches("\\*", "x"));
+        assert!(pattern_matches("a\\?", "a?"));
+        assert!(!pattern_matches("a\\?", "ab"));
+        assert!(pattern_matches("\\[a]", "[a]"));
+        assert!(pattern_matches("[\\]]", "]"));
+        assert!(pattern_matches("[\\!a]", "!"));
+        assert!(pattern_matches("\\\\", "\\"));
+        assert!(!has_glob_chars("\\*\\?\\["));
+        assert!(has_glob_chars("\\\\*"));
+        assert_eq!(unescape_pattern("a\\*b\\\\"), "a*b\\");
+    }
+
+    #[test]
+    fn escape_round_trip() {
+        let literal = "a*b?[c]\\d";
+        let pattern = escape_pattern(literal);
+        assert!(!has_glob_chars(&pattern));
+        assert!(pattern_matches(&pattern, literal));
+        assert!(!pattern_matches(&pattern, "aXb?[c]\\d"));
+        assert_eq!(unescape_pattern(&pattern), literal);
+    }
+}
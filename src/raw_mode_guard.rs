@@ -8,6 +8,13 @@ impl RawModeGuard {
         enable_raw_mode()?;
         Ok(Self)
     }
+
+    // 执行命令期间临时回到普通模式：子进程需要正常的终端，Ctrl-C/Ctrl-Z 也要能产生信号
+    // 返回的守卫离开作用域时重新进入 raw mode
+    pub fn suspend(&self) -> SuspendGuard<'_> {
+        let _ = disable_raw_mode();
+        SuspendGuard { _raw: self }
+    }
 }
 
 impl Drop for RawModeGuard {
@@ -16,3 +23,13 @@ impl Drop for RawModeGuard {
         let _ = disable_raw_mode();
     }
 }
+
+pub struct SuspendGuard<'a> {
+    _raw: &'a RawModeGuard,
+}
+
+impl Drop for SuspendGuard<'_> {
+    fn drop(&mut self) {
+        let _ = enable_raw_mode();
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::command::{InputBuffer, parse_input, run_list};
+use crate::state::ShellState;
+use crate::terminal_io::{IoContext, path_error};
+
+// 非交互模式：从脚本文件、-c 的参数或者管道里一行行读命令执行
+// 和交互模式一样，攒够一条完整的命令 (比如 here-document 读到结束符) 才交给解析器
+// name 是报错时显示的来源，命令的输入输出来自 io_ctx
+pub fn run_source(
+    reader: &mut impl Read,
+    name: &str,
+    state: &mut ShellState,
+    io_ctx: &IoContext,
+) -> io::Result<i32> {
+    // source 可能嵌套，结束后要恢复外层的位置
+    let outer = state.location.take();
+    let mut pending = InputBuffer::new();
+    let mut line_no = 0;
+    // 当前命令从第几行开始
+    let mut start = 1;
+    let mut result = Ok(());
+    loop {
+        let line = match read_line(reader) {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                result = Err(e);
+                break;
+            }
+        };
+        line_no += 1;
+        if pending.is_empty() {
+            start = line_no;
+        }
+        if pending.push_line(&line) {
+            continue;
+        }
+        state.location = Some((name.to_string(), start));
+        // 和 bash 一样，非交互模式下遇到语法错误就不再往下执行，return 也结束整个文件
+        if !run_input(&pending.take(), state, io_ctx) || state.flow.is_some() {
+            break;
+        }
+    }
+    // 输入结束时还没写完的命令也照样执行
+    if !pending.is_empty() {
+        state.location = Some((name.to_string(), start));
+        run_input(&pending.take(), state, io_ctx);
+    }
+    state.location = outer;
+    result.map(|_| state.last_status)
+}
+
+// 有语法错误时返回 false
+fn run_input(input: &str, state: &mut ShellState, io_ctx: &IoContext) -> bool {
+    let list = match parse_input(input, &state.aliases) {
+        Ok(Some(list)) => list,
+        Ok(None) => return true,
+        Err(e) => {
+            // 行号换成出错位置所在的行
+            if let Some((_, line)) = &mut state.location {
+                *line += input[..e.span().start].matches('\n').count();
+            }
+            eprintln!("{}{}", state.error_prefix(), e);
+            eprintln!("{}", e.caret(input));
+            state.last_status = 2;
+            return false;
+        }
+    };
+    if let Err(e) = run_list(&list, state, io_ctx) {
+        eprintln!("{}{}", state.error_prefix(), e);
+        state.last_status = 1;
+    }
+    true
+}
+
+// 执行一个文件，rc 文件和 source 都用它
+pub fn source_file(path: &Path, state: &mut ShellState, io_ctx: &IoContext) -> io::Result<i32> {
+    let file = File::open(path).map_err(|e| path_error(path, e))?;
+    let name = path.display().to_string();
+    run_source(&mut BufReader::new(file), &name, state, io_ctx)
+}
+
+// 逐字节读取一行 (不含换行符)，输入结束时返回 None
+// 不能多读：从标准输入读命令时，剩下的内容还要留给后面的命令读
+fn read_line(reader: &mut impl Read) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) if line.is_empty() => return Ok(None),
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
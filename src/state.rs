@@ -1,9 +1,154 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, fs};
 
+use crate::ast::FunctionDef;
+use crate::command::InputBuffer;
+use crate::job::{JobControl, JobTable};
+
+// shopt 和 set 控制的开关
+#[derive(Default)]
+pub struct ShellOptions {
+    pub dotglob: bool,  // 通配符也匹配 . 开头的文件
+    pub failglob: bool, // 没有匹配时报错
+    pub globstar: bool, // ** 递归匹配子目录
+    pub nullglob: bool, // 没有匹配时展开为空
+    // 以下由 set 控制
+    pub errexit: bool,   // set -e：命令失败时退出 shell
+    pub noclobber: bool, // set -C：> 不覆盖已有文件
+    pub nounset: bool,   // set -u：展开未设置的变量时报错
+    pub pipefail: bool,  // 管道的退出码取最右边一个失败的命令
+    pub xtrace: bool,    // set -x：执行前把展开后的命令打印到 stderr
+}
+
+impl ShellOptions {
+    // shopt 能识别的选项名，按字母顺序
+    pub const SHOPT_NAMES: &'static [&'static str] =
+        &["dotglob", "failglob", "globstar", "nullglob"];
+
+    pub fn shopt_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "failglob" => Some(&mut self.failglob),
+            "globstar" => Some(&mut self.globstar),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+
+    // set -o 能识别的选项名，按字母顺序
+    pub const SET_NAMES: &'static [&'static str] =
+        &["errexit", "noclobber", "nounset", "pipefail", "xtrace"];
+
+    pub fn set_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "noclobber" => Some(&mut self.noclobber),
+            "nounset" => Some(&mut self.nounset),
+            "pipefail" => Some(&mut self.pipefail),
+            "xtrace" => Some(&mut self.xtrace),
+            _ => None,
+        }
+    }
+
+    // set 的单字母选项对应的长名字，比如 -C 就是 -o noclobber
+    pub fn set_flag_name(flag: char) -> Option<&'static str> {
+        match flag {
+            'C' => Some("noclobber"),
+            'e' => Some("errexit"),
+            'u' => Some("nounset"),
+            'x' => Some("xtrace"),
+            _ => None,
+        }
+    }
+
+    // $- 的值：打开的单字母选项
+    pub fn flags(&self) -> String {
+        [
+            ('C', self.noclobber),
+            ('e', self.errexit),
+            ('u', self.nounset),
+            ('x', self.xtrace),
+        ]
+        .into_iter()
+        .filter_map(|(flag, on)| on.then_some(flag))
+        .collect()
+    }
+}
+
+// break/continue 要跳出的循环层数，由外层的循环逐层处理
+#[derive(Clone, Copy)]
+pub enum Flow {
+    Break(usize),
+    Continue(usize),
+    // return：结束当前函数或者正在 source 的文件
+    Return,
+    // 前台命令被 Ctrl-C 打断，放弃整行剩下的命令
+    Interrupt,
+}
+
+// shell 变量：值和 export、readonly 属性
+#[derive(Clone, Default)]
+pub struct Variable {
+    // None 表示只设置了属性还没有值，比如 export FOO
+    pub value: Option<String>,
+    // 传给外部命令的环境变量
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+// 给 readonly 变量赋值
+#[derive(Debug, thiserror::Error)]
+#[error("{0}: readonly variable")]
+pub struct ReadonlyError(pub String);
+
 pub struct ShellState {
     pub history: Vec<String>,
     pub history_cursor: usize,
     pub history_written_count: usize,
+    // 上一条命令的退出码，即 $?
+    pub last_status: i32,
+    // 最近一个后台任务的 pid，即 $!
+    pub last_bg_pid: Option<u32>,
+    // 位置参数 $1..$N
+    pub positional: Vec<String>,
+    // $0
+    pub shell_name: String,
+    // $$：子 shell 里仍然是原来 shell 的 pid
+    pub pid: u32,
+    // 正在子 shell 里执行 (命令替换、管道里的内建命令和复合命令)
+    pub subshell: bool,
+    // shell 变量，启动时从环境变量导入的都带着 export 属性
+    pub vars: HashMap<String, Variable>,
+    pub options: ShellOptions,
+    // 后台运行和被暂停的作业
+    pub jobs: JobTable,
+    // 交互式运行时启用作业控制，命令替换期间暂时关闭
+    pub job_control: Option<JobControl>,
+    // exec 打开的 3 号及以上的 fd
+    pub fds: BTreeMap<i32, File>,
+    // 正在执行的脚本名和命令所在的行号，报错时用
+    pub location: Option<(String, usize)>,
+    // 正在执行的循环层数，以及 break/continue 留下的跳转
+    pub loop_depth: usize,
+    pub flow: Option<Flow>,
+    // 定义过的函数
+    pub functions: HashMap<String, Rc<FunctionDef>>,
+    // 每层函数调用一帧，记下 local 变量进入函数前的值，返回时恢复
+    pub locals: Vec<HashMap<String, Option<Variable>>>,
+    // 正在 source 的文件层数，return 也可以结束它们
+    pub source_depth: usize,
+    // alias 定义的别名，按名字排序
+    pub aliases: BTreeMap<String, String>,
+    // 数组变量，目前只有 [[ =~ ]] 设置的 BASH_REMATCH
+    pub arrays: HashMap<String, Vec<String>>,
+    // 交互式运行，非交互时 set -u 的错误会退出 shell
+    pub interactive: bool,
+    // 大于 0 时 set -e 不生效：if/while 的条件、&& || 左边的命令、命令替换里
+    pub errexit_ignored: usize,
 }
 
 impl ShellState {
@@ -12,26 +157,171 @@ impl ShellState {
             history: Vec::new(),
             history_cursor: 0,
             history_written_count: 0,
+            last_status: 0,
+            last_bg_pid: None,
+            positional: Vec::new(),
+            shell_name: env::args().next().unwrap_or_else(|| "msksh".to_string()),
+            pid: std::process::id(),
+            subshell: false,
+            vars: env::vars_os()
+                .filter_map(|(name, value)| {
+                    Some((name.into_string().ok()?, value.into_string().ok()?))
+                })
+                .map(|(name, value)| {
+                    let var = Variable {
+                        value: Some(value),
+                        exported: true,
+                        readonly: false,
+                    };
+                    (name, var)
+                })
+                .collect(),
+            options: ShellOptions::default(),
+            jobs: JobTable::default(),
+            job_control: None,
+            fds: BTreeMap::new(),
+            location: None,
+            loop_depth: 0,
+            flow: None,
+            functions: HashMap::new(),
+            locals: Vec::new(),
+            source_depth: 0,
+            aliases: BTreeMap::new(),
+            arrays: HashMap::new(),
+            interactive: false,
+            errexit_ignored: 0,
         }
     }
 
-    pub fn init(&mut self) -> Result<(), std::io::Error> {
-        if let Ok(path) = env::var("HISTFILE") {
-            if let Ok(history_commands) = fs::read_to_string(path) {
-                let mut cmds: Vec<String> = history_commands
-                    .lines()
-                    .map(|line| line.trim().to_string())
-                    .filter(|line| !line.is_empty())
-                    .collect();
-                self.history.append(&mut cmds);
+    pub fn get_var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).and_then(|var| var.value.clone())
+    }
+
+    // 赋值保留原有的属性
+    pub fn set_var(&mut self, name: &str, value: &str) -> Result<(), ReadonlyError> {
+        let var = self.vars.entry(name.to_string()).or_default();
+        if var.readonly {
+            return Err(ReadonlyError(name.to_string()));
+        }
+        var.value = Some(value.to_string());
+        Ok(())
+    }
+
+    // 恢复之前保存的变量，None 表示原来没有这个变量
+    pub fn restore_var(&mut self, name: String, saved: Option<Variable>) {
+        match saved {
+            Some(var) => {
+                self.vars.insert(name, var);
+            }
+            None => {
+                self.vars.remove(&name);
             }
         }
+    }
+
+    // 外部命令的环境：所有带 export 属性并且有值的变量
+    pub fn exported_vars(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.vars
+            .iter()
+            .filter(|(_, var)| var.exported)
+            .filter_map(|(name, var)| Some((name, var.value.as_ref()?)))
+    }
+
+    // 只有交互式运行时才启用作业控制和读取历史记录
+    pub fn init(&mut self, interactive: bool) -> Result<(), std::io::Error> {
+        self.interactive = interactive;
+        if !interactive {
+            return Ok(());
+        }
+        self.job_control = JobControl::init();
+        if let Some(path) = self.get_var("HISTFILE")
+            && let Ok(history_commands) = fs::read_to_string(path)
+        {
+            self.history.append(&mut parse_history(&history_commands));
+        }
+        Ok(())
+    }
+
+    // fork 出来的子进程变成子 shell：不做作业控制，也看不到父 shell 的作业
+    pub fn enter_subshell(&mut self) {
+        self.subshell = true;
+        self.interactive = false;
+        self.job_control = None;
+        self.jobs = JobTable::default();
+    }
+
+    // exit 时把历史记录写回 HISTFILE，子 shell 退出时不写
+    pub fn save_history(&self) -> Result<(), std::io::Error> {
+        if self.subshell {
+            return Ok(());
+        }
+        if let Some(path) = self.get_var("HISTFILE") {
+            let file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)?;
+            let mut writer = BufWriter::new(file);
+            for item in &self.history {
+                writeln!(writer, "{}", format_history(item))?;
+            }
+            writer.flush()?;
+        }
         Ok(())
     }
 
+    // 报错信息的前缀：执行脚本或 rc 文件时换成文件名和行号
+    pub fn error_prefix(&self) -> String {
+        match &self.location {
+            Some((name, line)) => format!("{}: line {}: ", name, line),
+            None => "msksh: ".to_string(),
+        }
+    }
+
     pub fn add_history(&mut self, command: String) {
         if !command.trim().is_empty() {
             self.history.push(command.to_string());
         }
     }
 }
+
+// 历史文件一行一条，命令原样写入
+// 多行的命令前面加一行 #时间戳 (和 bash 设置了 HISTTIMEFORMAT 时的格式一样)，各行也原样写入
+pub fn format_history(entry: &str) -> String {
+    if !entry.contains('\n') {
+        return entry.to_string();
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    format!("#{}\n{}", time, entry)
+}
+
+fn is_history_marker(line: &str) -> bool {
+    line.strip_prefix('#')
+        .is_some_and(|time| !time.is_empty() && time.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// 读历史文件：没有标记的每一行是一条命令
+// #时间戳 后面的几行合成一条，直到命令完整或者遇到下一个标记
+pub fn parse_history(content: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut lines = content.lines().peekable();
+    while let Some(line) = lines.next() {
+        if is_history_marker(line.trim()) {
+            let mut entry = InputBuffer::new();
+            while let Some(line) = lines.next_if(|line| !is_history_marker(line.trim())) {
+                if !entry.push_line(line) {
+                    break;
+                }
+            }
+            let entry = entry.take();
+            if !entry.trim().is_empty() {
+                entries.push(entry);
+            }
+        } else if !line.trim().is_empty() {
+            entries.push(line.trim().to_string());
+        }
+    }
+    entries
+}
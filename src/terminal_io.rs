@@ -10,6 +10,8 @@ pub enum MskKeyCode {
     Tab,
     Up,
     Down,
+    // Ctrl-C
+    Interrupt,
 }
 
 pub fn get_event() -> Option<MskEvent> {
@@ -17,30 +19,37 @@ pub fn get_event() -> Option<MskEvent> {
     match read() {
         Ok(Event::Key(key_event)) => match key_event.code {
             KeyCode::Char('j') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
-                return Some(MskEvent::Key(MskKeyCode::Enter));
+                Some(MskEvent::Key(MskKeyCode::Enter))
             }
-            KeyCode::Char(c) => {
-                return Some(MskEvent::Key(MskKeyCode::Char(c)));
-            }
-            KeyCode::Backspace => return Some(MskEvent::Key(MskKeyCode::Backspace)),
-            KeyCode::Enter => return Some(MskEvent::Key(MskKeyCode::Enter)),
-            KeyCode::Right => return Some(MskEvent::Key(MskKeyCode::ArrowRight)),
-            KeyCode::Left => return Some(MskEvent::Key(MskKeyCode::ArrowLeft)),
-            KeyCode::Tab => return Some(MskEvent::Key(MskKeyCode::Tab)),
-            KeyCode::Up => return Some(MskEvent::Key(MskKeyCode::Up)),
-            KeyCode::Down => return Some(MskEvent::Key(MskKeyCode::Down)),
-            _ => {
-                return None;
+            KeyCode::Char('c') if key_event.modifiers.contains(KeyModifiers::CONTROL) => {
+                Some(MskEvent::Key(MskKeyCode::Interrupt))
             }
+            // 其它控制键 (比如 raw mode 下的 Ctrl-Z) 不当作普通字符输入
+            KeyCode::Char(_) if key_event.modifiers.contains(KeyModifiers::CONTROL) => None,
+            KeyCode::Char(c) => Some(MskEvent::Key(MskKeyCode::Char(c))),
+            KeyCode::Backspace => Some(MskEvent::Key(MskKeyCode::Backspace)),
+            KeyCode::Enter => Some(MskEvent::Key(MskKeyCode::Enter)),
+            KeyCode::Right => Some(MskEvent::Key(MskKeyCode::ArrowRight)),
+            KeyCode::Left => Some(MskEvent::Key(MskKeyCode::ArrowLeft)),
+            KeyCode::Tab => Some(MskEvent::Key(MskKeyCode::Tab)),
+            KeyCode::Up => Some(MskEvent::Key(MskKeyCode::Up)),
+            KeyCode::Down => Some(MskEvent::Key(MskKeyCode::Down)),
+            _ => None,
         },
         // 处理 Resize 等其他事件，忽略并继续等
-        Ok(_) => return None,
-        Err(_) => return None,
+        Ok(_) => None,
+        Err(_) => None,
     }
 }
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
 use std::fs::{File, OpenOptions};
 use std::io::{self, PipeReader, PipeWriter, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
 use std::process::Stdio;
+use std::sync::OnceLock;
+use std::thread;
 
 use crate::parser::{Redirection, RedirectionMode, RedirectionTarget};
 
@@ -50,42 +59,203 @@ pub enum OutputStream {
     Inherit,    // 默认：屏幕
     File(File), // 重定向：文件
     Pipe(PipeWriter),
+    Closed, // 被 >&- 关闭
 }
 
 impl OutputStream {
-    pub fn to_stdio(self) -> Stdio {
+    pub fn into_stdio(self) -> Stdio {
         match self {
             OutputStream::Inherit => Stdio::inherit(),
             OutputStream::File(f) => Stdio::from(f.try_clone().unwrap()),
             OutputStream::Pipe(stdio) => stdio.into(),
+            // 子进程里真正的关闭在 pre_exec 里做，这里先占个位
+            OutputStream::Closed => Stdio::null(),
+        }
+    }
+
+    // 命令列表里每条管道都要写到同一个目标
+    pub fn try_clone(&self) -> io::Result<OutputStream> {
+        Ok(match self {
+            OutputStream::Inherit => OutputStream::Inherit,
+            OutputStream::File(f) => OutputStream::File(f.try_clone()?),
+            OutputStream::Pipe(p) => OutputStream::Pipe(p.try_clone()?),
+            OutputStream::Closed => OutputStream::Closed,
+        })
+    }
+
+    // N>&M 时复制 fd M 当前指向的目标
+    // Inherit 只表示"沿用 shell 的同号 fd"，复制到别的 fd 上时要换成真正的句柄
+    fn duplicate(&self, own_fd: i32) -> io::Result<File> {
+        match self {
+            OutputStream::Inherit if own_fd == 1 => dup_std(io::stdout().as_fd()),
+            OutputStream::Inherit => dup_std(io::stderr().as_fd()),
+            OutputStream::File(f) => f.try_clone(),
+            OutputStream::Pipe(p) => Ok(File::from(OwnedFd::from(p.try_clone()?))),
+            OutputStream::Closed => Err(bad_fd(own_fd)),
         }
     }
 
-    pub fn to_write(&mut self) -> Box<dyn Write + '_> {
+    pub fn as_write(&mut self) -> Box<dyn Write + '_> {
         match self {
             OutputStream::Inherit => Box::new(io::stdout()),
             OutputStream::File(f) => Box::new(f),
             OutputStream::Pipe(stdio) => Box::new(stdio),
+            OutputStream::Closed => Box::new(ClosedWriter),
+        }
+    }
+
+    // 内建命令的错误信息写到这里：Inherit 时是 shell 自己的标准错误
+    pub fn as_err_write(&mut self) -> Box<dyn Write + '_> {
+        match self {
+            OutputStream::Inherit => Box::new(io::stderr()),
+            stream => stream.as_write(),
+        }
+    }
+}
+
+// 提示符和行编辑的回显写到这里
+// exec >file 之后 1 号 fd 不再是终端，所以启动时先复制一份留着，编号放到 10 以上避开用户的 fd
+static TERMINAL: OnceLock<File> = OnceLock::new();
+
+pub fn terminal() -> &'static File {
+    TERMINAL.get_or_init(|| {
+        let fd = unsafe { libc::fcntl(libc::STDOUT_FILENO, libc::F_DUPFD_CLOEXEC, 10) };
+        if fd < 0 {
+            // 复制失败就只能直接用 1 号 fd 了，exec 重定向后提示符会跟着跑
+            dup_std(io::stdout().as_fd()).expect("stdout is not available")
+        } else {
+            unsafe { File::from_raw_fd(fd) }
         }
+    })
+}
+
+fn dup_std(fd: BorrowedFd<'_>) -> io::Result<File> {
+    Ok(File::from(fd.try_clone_to_owned()?))
+}
+
+// here-document 的内容通过管道交给命令，单独开线程写入，避免内容超过管道缓冲区时卡住
+fn text_pipe(text: String) -> io::Result<File> {
+    let (reader, mut writer) = io::pipe()?;
+    thread::spawn(move || {
+        // 读端提前关闭时写入会失败，直接放弃即可
+        let _ = writer.write_all(text.as_bytes());
+    });
+    Ok(File::from(OwnedFd::from(reader)))
+}
+
+// 打开失败时和 bash 一样带上文件名：msksh: nofile: No such file or directory
+pub fn path_error(path: &Path, e: io::Error) -> io::Error {
+    let msg = e.to_string();
+    let msg = match msg.find(" (os error") {
+        Some(pos) => &msg[..pos],
+        None => &msg,
+    };
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), msg))
+}
+
+fn bad_fd(fd: i32) -> io::Error {
+    io::Error::other(format!("{}: Bad file descriptor", fd))
+}
+
+// 往已经关闭的 fd 写东西总是失败
+struct ClosedWriter;
+
+impl Write for ClosedWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("write error: Bad file descriptor"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+// 输出流对应的 fd，None 表示不用动，-1 表示关闭
+fn output_raw_fd(stream: &OutputStream) -> Option<RawFd> {
+    match stream {
+        OutputStream::Inherit => None,
+        OutputStream::File(f) => Some(f.as_raw_fd()),
+        OutputStream::Pipe(p) => Some(p.as_raw_fd()),
+        OutputStream::Closed => Some(-1),
     }
 }
 pub enum InputStream {
     Inherit,
     Pipe(PipeReader),
+    File(File), // <file、<>file 和 here-document
+    Closed,     // 被 <&- 关闭
 }
 impl InputStream {
-    pub fn to_stdio(self) -> Stdio {
+    pub fn into_stdio(self) -> Stdio {
         match self {
             InputStream::Inherit => Stdio::inherit(),
             InputStream::Pipe(stdio) => stdio.into(),
+            InputStream::File(f) => f.into(),
+            InputStream::Closed => Stdio::null(),
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<InputStream> {
+        Ok(match self {
+            InputStream::Inherit => InputStream::Inherit,
+            InputStream::Pipe(p) => InputStream::Pipe(p.try_clone()?),
+            InputStream::File(f) => InputStream::File(f.try_clone()?),
+            InputStream::Closed => InputStream::Closed,
+        })
+    }
+
+    fn duplicate(&self) -> io::Result<File> {
+        match self {
+            InputStream::Inherit => dup_std(io::stdin().as_fd()),
+            InputStream::Pipe(p) => Ok(File::from(OwnedFd::from(p.try_clone()?))),
+            InputStream::File(f) => f.try_clone(),
+            InputStream::Closed => Err(bad_fd(0)),
         }
     }
 }
-// I/O 上下文：管理当前命令的 stdin/stdout/stderr
+// I/O 上下文：管理当前命令的 stdin/stdout/stderr 以及其它文件描述符
 pub struct IoContext {
     pub stdout: OutputStream,
     pub stderr: OutputStream,
     pub stdin: InputStream,
+    // 3 号及以上的文件描述符，None 表示被 N>&- 关闭
+    // 表里没有的编号沿用 shell 自己的同号 fd
+    pub extra_fds: BTreeMap<i32, Option<File>>,
+}
+
+// 子进程 exec 之前要做的 fd 调整
+pub struct ChildFds {
+    // (父进程里的 fd, 子进程里的编号)
+    moves: Vec<(RawFd, RawFd)>,
+    closed: Vec<RawFd>,
+    // 比所有目标编号都大的 fd，搬运时的中转区
+    floor: RawFd,
+}
+
+impl ChildFds {
+    // 在 pre_exec 里调用：只能用 async-signal-safe 的系统调用，也不能分配内存
+    pub fn apply(&mut self) -> io::Result<()> {
+        unsafe {
+            // 先把来源都挪到目标编号之外，避免 dup2 时互相覆盖
+            for (src, _) in self.moves.iter_mut() {
+                let fd = libc::fcntl(*src, libc::F_DUPFD, self.floor);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                *src = fd;
+            }
+            // dup2 出来的 fd 不带 CLOEXEC，exec 之后还在
+            for &(src, target) in &self.moves {
+                if libc::dup2(src, target) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                libc::close(src);
+            }
+            for &fd in &self.closed {
+                libc::close(fd);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl IoContext {
@@ -94,6 +264,7 @@ impl IoContext {
             stdout: OutputStream::Inherit,
             stderr: OutputStream::Inherit,
             stdin: InputStream::Inherit,
+            extra_fds: BTreeMap::new(),
         }
     }
     pub fn flush_stdout(&mut self) -> io::Result<()> {
@@ -103,6 +274,7 @@ impl IoContext {
             // 如果是 File，调用 File 的 flush (系统调用 fsync 或类似)
             OutputStream::File(f) => f.flush()?,
             OutputStream::Pipe(stdio) => stdio.flush()?,
+            OutputStream::Closed => {}
         }
 
         Ok(())
@@ -113,34 +285,196 @@ impl IoContext {
             OutputStream::Inherit => io::stderr().flush()?,
             OutputStream::File(f) => f.flush()?,
             OutputStream::Pipe(stdio) => stdio.flush()?,
+            OutputStream::Closed => {}
         }
 
         Ok(())
     }
-    // 核心逻辑：根据重定向列表，修改上下文
-    // 这一步是把 "Configuration" 变成 "Runtime Resources"
-    pub fn apply_redirections(&mut self, redirections: &[Redirection]) -> io::Result<()> {
-        for r in redirections {
-            // 1. 打开文件
-            let mut opts = OpenOptions::new();
-            opts.write(true).create(true);
-            match r.mode {
-                RedirectionMode::Overwrite => {
-                    opts.truncate(true);
+    // 子进程要继承的 fd：extra_fds 里的文件放到对应编号上，被关闭的 fd 关掉
+    // 0/1/2 由 Command 的 stdin/stdout/stderr 负责，这里只处理关闭
+    pub fn child_fds(&self) -> ChildFds {
+        let mut moves = Vec::new();
+        let mut closed = Vec::new();
+        if matches!(self.stdin, InputStream::Closed) {
+            closed.push(0);
+        }
+        if matches!(self.stdout, OutputStream::Closed) {
+            closed.push(1);
+        }
+        if matches!(self.stderr, OutputStream::Closed) {
+            closed.push(2);
+        }
+        for (&fd, file) in &self.extra_fds {
+            match file {
+                Some(f) => moves.push((f.as_raw_fd(), fd)),
+                None => closed.push(fd),
+            }
+        }
+        let floor = self.extra_fds.keys().next_back().map_or(3, |fd| fd + 1);
+        ChildFds {
+            moves,
+            closed,
+            floor,
+        }
+    }
+
+    // exec 打开过的 fd 对之后的每条命令都可见，复合命令自己的重定向优先
+    pub fn inherit_fds(&mut self, fds: &BTreeMap<i32, File>) -> io::Result<()> {
+        for (&fd, f) in fds {
+            if let Entry::Vacant(entry) = self.extra_fds.entry(fd) {
+                entry.insert(Some(f.try_clone()?));
+            }
+        }
+        Ok(())
+    }
+
+    // 复合命令里的每条命令都从同一个上下文出发
+    pub fn try_clone(&self) -> io::Result<IoContext> {
+        let mut extra_fds = BTreeMap::new();
+        for (&fd, file) in &self.extra_fds {
+            extra_fds.insert(fd, file.as_ref().map(File::try_clone).transpose()?);
+        }
+        Ok(IoContext {
+            stdout: self.stdout.try_clone()?,
+            stderr: self.stderr.try_clone()?,
+            stdin: self.stdin.try_clone()?,
+            extra_fds,
+        })
+    }
+
+    // exec 只带重定向时调用：把重定向结果装到 shell 自己身上
+    // 0/1/2 直接 dup2 到真正的 fd 上；3 号及以上的 fd 返回给调用者保存，
+    // 因为 shell 内部也在用这些编号，不能直接覆盖
+    pub fn install(self) -> io::Result<BTreeMap<i32, Option<File>>> {
+        let std_fds = [
+            (
+                0,
+                match &self.stdin {
+                    InputStream::Inherit => None,
+                    InputStream::Pipe(p) => Some(p.as_raw_fd()),
+                    InputStream::File(f) => Some(f.as_raw_fd()),
+                    InputStream::Closed => Some(-1),
+                },
+            ),
+            (1, output_raw_fd(&self.stdout)),
+            (2, output_raw_fd(&self.stderr)),
+        ];
+        for (target, src) in std_fds {
+            unsafe {
+                match src {
+                    None => {}
+                    Some(-1) => {
+                        libc::close(target);
+                    }
+                    Some(src) if libc::dup2(src, target) < 0 => {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Some(_) => {}
                 }
-                RedirectionMode::Append => {
-                    opts.append(true);
+            }
+        }
+        for (&fd, file) in &self.extra_fds {
+            // 关闭从父进程继承来的 fd；带 CLOEXEC 的是 shell 内部的，不能动
+            if file.is_none() {
+                unsafe {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags >= 0 && flags & libc::FD_CLOEXEC == 0 {
+                        libc::close(fd);
+                    }
                 }
             }
+        }
+        Ok(self.extra_fds)
+    }
 
-            if let RedirectionTarget::File(path) = &r.target {
-                let file = opts.open(path)?;
+    // 复制 fd 当前指向的目标，N>&M 和 N<&M 都用它
+    fn duplicate_fd(&self, fd: i32) -> io::Result<File> {
+        match fd {
+            0 => self.stdin.duplicate(),
+            1 => self.stdout.duplicate(1),
+            2 => self.stderr.duplicate(2),
+            _ => match self.extra_fds.get(&fd) {
+                Some(Some(f)) => f.try_clone(),
+                Some(None) => Err(bad_fd(fd)),
+                // 表里没有就看 shell 自己有没有这个 fd
+                // 带 CLOEXEC 的是 shell 内部用的 (比如终端事件)，不算数，只认会被子进程继承的
+                None => unsafe {
+                    let flags = libc::fcntl(fd, libc::F_GETFD);
+                    if flags < 0 || flags & libc::FD_CLOEXEC != 0 {
+                        return Err(bad_fd(fd));
+                    }
+                    let dup = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 3);
+                    if dup < 0 {
+                        Err(bad_fd(fd))
+                    } else {
+                        Ok(File::from_raw_fd(dup))
+                    }
+                },
+            },
+        }
+    }
+    // 核心逻辑：根据重定向列表，修改上下文
+    // 这一步是把 "Configuration" 变成 "Runtime Resources"
+    // 必须按书写顺序处理：>out 2>&1 和 2>&1 >out 的结果不一样
+    // noclobber 打开时 > 不能覆盖已经存在的普通文件
+    pub fn apply_redirections(
+        &mut self,
+        redirections: &[Redirection],
+        noclobber: bool,
+    ) -> io::Result<()> {
+        for r in redirections {
+            // 1. 先得到新的目标，None 表示关闭
+            let target = match &r.target {
+                RedirectionTarget::File(path) => {
+                    let mut opts = OpenOptions::new();
+                    match r.mode {
+                        RedirectionMode::Overwrite if noclobber => {
+                            match path.metadata() {
+                                Ok(meta) if meta.is_file() => {
+                                    return Err(io::Error::new(
+                                        io::ErrorKind::AlreadyExists,
+                                        format!(
+                                            "{}: cannot overwrite existing file",
+                                            path.display()
+                                        ),
+                                    ));
+                                }
+                                // /dev/null 之类的特殊文件照常写，只是不截断
+                                Ok(_) => opts.write(true),
+                                // 不存在就新建，create_new 保证这期间没有别人抢先建好
+                                Err(_) => opts.write(true).create_new(true),
+                            };
+                        }
+                        RedirectionMode::Overwrite | RedirectionMode::Clobber => {
+                            opts.write(true).create(true).truncate(true);
+                        }
+                        RedirectionMode::Append => {
+                            opts.create(true).append(true);
+                        }
+                        RedirectionMode::Read => {
+                            opts.read(true);
+                        }
+                        RedirectionMode::ReadWrite => {
+                            opts.read(true).write(true).create(true);
+                        }
+                    }
+                    Some(opts.open(path).map_err(|e| path_error(path, e))?)
+                }
+                RedirectionTarget::Text(text) => Some(text_pipe(text.clone())?),
+                RedirectionTarget::Fd(fd) => Some(self.duplicate_fd(*fd)?),
+                RedirectionTarget::Close => None,
+            };
 
-                // 2. 替换流
-                match r.source_fd {
-                    1 => self.stdout = OutputStream::File(file),
-                    2 => self.stderr = OutputStream::File(file),
-                    _ => {} // 暂不支持其他 fd
+            // 2. 替换流
+            match (r.source_fd, target) {
+                (0, Some(f)) => self.stdin = InputStream::File(f),
+                (0, None) => self.stdin = InputStream::Closed,
+                (1, Some(f)) => self.stdout = OutputStream::File(f),
+                (1, None) => self.stdout = OutputStream::Closed,
+                (2, Some(f)) => self.stderr = OutputStream::File(f),
+                (2, None) => self.stderr = OutputStream::Closed,
+                (fd, target) => {
+                    self.extra_fds.insert(fd, target);
                 }
             }
         }
@@ -21,20 +21,19 @@ impl Trie {
         word.chars().try_fold(self, |node, c| node.children.get(&c))
     }
 
+    #[allow(dead_code)]
     fn search(&self, word: &str) -> bool {
-        self.get(word).map_or(false, |node| node.is_leaf)
+        self.get(word).is_some_and(|node| node.is_leaf)
     }
 
+    #[allow(dead_code)]
     fn starts_with(&self, prefix: &str) -> bool {
         self.get(prefix).is_some()
     }
     pub fn search_prefix(&self, prefix: &str) -> Option<Vec<String>> {
         // 1. 快速定位到前缀所在的节点
         // 如果前缀本身都不存在，直接返回空 Vec，避免后续分配
-        let start_node = match self.get(prefix) {
-            Some(node) => node,
-            None => return None,
-        };
+        let start_node = self.get(prefix)?;
         // 2. 准备结果容器
         let mut results = Vec::new();
 
@@ -0,0 +1,124 @@
+// 内建命令的错误处理
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{run, stdout};
+
+#[test]
+fn type_with_an_empty_name_reports_not_found() {
+    let output = run("type ''; echo $?");
+    assert_eq!(stdout(&output), "1\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("type: : not found"), "{}", stderr);
+}
+
+#[test]
+fn failed_exec_exits_a_non_interactive_shell() {
+    let output = run("exec msksh-no-such-command; echo after");
+    assert_eq!(output.status.code(), Some(127));
+    assert_eq!(stdout(&output), "");
+
+    let output = run("exec /; echo after");
+    assert_eq!(output.status.code(), Some(126));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn source_reads_large_multi_line_bodies_in_linear_time() {
+    // 每读一行都重新解析整段输入时，这里要跑好几分钟
+    let mut script = String::from("f() {\n");
+    for _ in 0..5000 {
+        script.push_str("  x=$((x + 1))\n");
+    }
+    script.push_str("}\nf; echo $x\ncat <<EOF | wc -l\n");
+    for i in 0..5000 {
+        script.push_str(&format!("line {}\n", i));
+    }
+    script.push_str("EOF\n");
+    let path = std::env::temp_dir().join(format!("msksh-large-{}.sh", std::process::id()));
+    std::fs::write(&path, script).unwrap();
+
+    let start = Instant::now();
+    let output = run(&format!(". {}", path.display()));
+    let elapsed = start.elapsed();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        stdout(&output).split_whitespace().collect::<Vec<_>>(),
+        ["5000", "5000"]
+    );
+    assert!(elapsed < Duration::from_secs(10), "took {:?}", elapsed);
+}
+
+#[test]
+fn failed_cd_returns_non_zero() {
+    let output = run("cd /msksh-no-such-dir || echo fail; cd /msksh-no-such-dir && echo ok");
+    assert_eq!(stdout(&output), "fail\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("msksh: line 1: cd: /msksh-no-such-dir: No such file or directory"),
+        "{}",
+        stderr
+    );
+
+    let output = run("set -e; cd /msksh-no-such-dir; echo after");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn builtin_errors_follow_stderr_redirections() {
+    let output = run(
+        "set -Q 2>/dev/null; fg 2>/dev/null; cd /msksh-no-such-dir 2>/dev/null; \
+         msksh-no-such-command 2>/dev/null; echo status=$?",
+    );
+    assert_eq!(stdout(&output), "status=127\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+    let output = run("msksh-no-such-command");
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "msksh: line 1: msksh-no-such-command: command not found\n"
+    );
+
+    let output = run("fg 2>&1; echo status=$?");
+    assert_eq!(
+        stdout(&output),
+        "msksh: line 1: fg: current: no such job\nstatus=1\n"
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+    let path = std::env::temp_dir().join(format!("msksh-stderr-{}", std::process::id()));
+    let output = run(&format!("unset -Q 2>{0}; cat {0}", path.display()));
+    let _ = std::fs::remove_file(&path);
+    assert!(
+        stdout(&output).contains("unset: -Q: invalid option"),
+        "{}",
+        stdout(&output)
+    );
+}
+
+#[test]
+fn test_errors_follow_stderr_redirections() {
+    let output = run(
+        "[ 1 -eq ] 2>/dev/null; echo $?; test 1 -lt x 2>/dev/null; echo $?; \
+         [ 1 2>/dev/null; echo $?; [[ 1 -eq 1+ ]] 2>/dev/null; echo $?",
+    );
+    assert_eq!(stdout(&output), "2\n2\n2\n2\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+    let output = run("[ 1 -eq ] 2>&1");
+    assert!(stdout(&output).contains("[: "), "{}", stdout(&output));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
+
+#[test]
+fn cd_updates_and_exports_pwd_and_oldpwd() {
+    let output = run("cd /; cd /dev; echo $PWD $OLDPWD; printenv PWD OLDPWD; cd -; pwd");
+    assert_eq!(stdout(&output), "/dev /\n/dev\n/\n/\n/\n");
+
+    let output = run("unset OLDPWD; cd - 2>&1; echo $?");
+    assert_eq!(stdout(&output), "msksh: line 1: cd: OLDPWD not set\n1\n");
+}
@@ -0,0 +1,14 @@
+use std::process::{Command, Output};
+
+// 用 -c 执行一段脚本
+pub fn run(script: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .arg("-c")
+        .arg(script)
+        .output()
+        .expect("failed to run msksh")
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
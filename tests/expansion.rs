@@ -0,0 +1,28 @@
+// 参数展开出错时 shell 的行为
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+
+use common::{run, stdout};
+
+#[test]
+fn unset_parameter_error_exits_a_script() {
+    let path = std::env::temp_dir().join(format!("msksh-param-{}.sh", std::process::id()));
+    fs::write(&path, "echo ${y:?boom}\necho after\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+        .arg(&path)
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("line 1: y: boom"), "{}", stderr);
+
+    // -c 也一样
+    let output = run("echo ${y:?boom}; echo after");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+}
@@ -0,0 +1,60 @@
+// 文件名展开和 shopt 选项
+
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use common::{run, stdout};
+
+// 每个测试用自己的目录：x.rs .hidden.rs a/y.rs a/b/z.rs
+fn fixture(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("msksh-glob-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("a/b")).unwrap();
+    for file in ["x.rs", ".hidden.rs", "a/y.rs", "a/b/z.rs"] {
+        fs::write(dir.join(file), "").unwrap();
+    }
+    dir
+}
+
+fn run_in(dir: &PathBuf, script: &str) -> std::process::Output {
+    let output = run(&format!("cd {}; {}", dir.display(), script));
+    fs::remove_dir_all(dir).unwrap();
+    output
+}
+
+#[test]
+fn dotglob_includes_hidden_files() {
+    let output = run_in(
+        &fixture("dotglob"),
+        "echo *.rs; shopt -s dotglob; echo *.rs; echo .*.rs",
+    );
+    assert_eq!(stdout(&output), "x.rs\n.hidden.rs x.rs\n.hidden.rs\n");
+}
+
+#[test]
+fn globstar_matches_any_depth() {
+    let output = run_in(
+        &fixture("globstar"),
+        "echo **/*.rs; shopt -s globstar; echo **/*.rs; echo **; echo **/",
+    );
+    assert_eq!(
+        stdout(&output),
+        "a/y.rs\na/b/z.rs a/y.rs x.rs\na a/b a/b/z.rs a/y.rs x.rs\na/ a/b/\n"
+    );
+}
+
+#[test]
+fn unmatched_patterns_with_nullglob_and_failglob() {
+    let output = run_in(
+        &fixture("nullglob"),
+        "echo '<' *.none '>'; shopt -s nullglob; echo '<' *.none '>'; echo '<' a/*/*.none '>'",
+    );
+    assert_eq!(stdout(&output), "< *.none >\n< >\n< >\n");
+
+    let output = run_in(&fixture("failglob"), "shopt -s failglob; echo *.none");
+    assert_eq!(stdout(&output), "");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no match: *.none"), "{}", stderr);
+}
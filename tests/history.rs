@@ -0,0 +1,69 @@
+// 历史文件的读写
+
+mod common;
+
+use std::fs;
+
+use common::{run, stdout};
+
+#[test]
+fn legacy_entries_are_read_back_verbatim() {
+    let dir = std::env::temp_dir().join(format!("msksh-history-legacy-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let saved = dir.join("saved");
+    let copy = dir.join("copy");
+    fs::write(&saved, "printf \"a\\n\"\necho a\\\\b\n\n  ls -l  \n").unwrap();
+
+    let script = format!(
+        "history -r {}; history; history -w {}",
+        saved.display(),
+        copy.display()
+    );
+    let output = run(&script);
+    assert_eq!(
+        stdout(&output),
+        "    1  printf \"a\\n\"\n    2  echo a\\\\b\n    3  ls -l\n"
+    );
+    assert_eq!(
+        fs::read_to_string(&copy).unwrap(),
+        "printf \"a\\n\"\necho a\\\\b\nls -l\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn multi_line_entries_round_trip_through_history_file() {
+    let dir = std::env::temp_dir().join(format!("msksh-history-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let saved = dir.join("saved");
+    let copy = dir.join("copy");
+    fs::write(
+        &saved,
+        "echo first\n#1700000000\necho \"a\nb\"\nprintf 'x\\ny'\n#1700000001\nfor i in 1; do\n  echo $i\ndone\n#1700000002\necho last\n",
+    )
+    .unwrap();
+
+    let script = format!(
+        "history -r {}; history; history -w {}",
+        saved.display(),
+        copy.display()
+    );
+    let output = run(&script);
+    assert_eq!(
+        stdout(&output),
+        "    1  echo first\n    2  echo \"a\nb\"\n    3  printf 'x\\ny'\n    4  for i in 1; do\n  echo $i\ndone\n    5  echo last\n"
+    );
+    // 多行的命令写回去时带着新的时间戳，单行的命令原样写回
+    let written = fs::read_to_string(&copy).unwrap();
+    let lines: Vec<&str> = written.lines().collect();
+    assert_eq!(lines[0], "echo first");
+    assert!(lines[1].starts_with('#'));
+    assert_eq!(&lines[2..4], ["echo \"a", "b\""]);
+    assert_eq!(lines[4], "printf 'x\\ny'");
+    assert!(lines[5].starts_with('#'));
+    assert_eq!(
+        &lines[6..],
+        ["for i in 1; do", "  echo $i", "done", "echo last"]
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,152 @@
+// 交互式 shell 里的 Ctrl-C：打断正在执行的命令，但不能杀掉 shell
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 在伪终端里启动一个交互式 shell
+struct Pty {
+    master: File,
+    output: Receiver<u8>,
+    seen: String,
+    child: Child,
+}
+
+impl Pty {
+    fn spawn() -> Pty {
+        let (mut master, mut slave) = (0, 0);
+        let ret = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        };
+        assert_eq!(ret, 0, "openpty failed");
+        let slave = unsafe { OwnedFd::from_raw_fd(slave) };
+        let mut command = Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"));
+        command
+            .arg("--norc")
+            .env_remove("HISTFILE")
+            .stdin(Stdio::from(slave.try_clone().unwrap()))
+            .stdout(Stdio::from(slave.try_clone().unwrap()))
+            .stderr(Stdio::from(slave));
+        // 新建会话并把伪终端设成控制终端，Ctrl-C 才会变成 SIGINT
+        unsafe {
+            command.pre_exec(|| {
+                libc::setsid();
+                libc::ioctl(0, libc::TIOCSCTTY, 0);
+                Ok(())
+            });
+        }
+        let child = command.spawn().unwrap();
+        let master = unsafe { File::from_raw_fd(master) };
+        let mut reader = master.try_clone().unwrap();
+        let (tx, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok(n @ 1..) = reader.read(&mut buf) {
+                for &b in &buf[..n] {
+                    if tx.send(b).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Pty {
+            master,
+            output,
+            seen: String::new(),
+            child,
+        }
+    }
+
+    fn send(&mut self, input: &str) {
+        self.master.write_all(input.as_bytes()).unwrap();
+    }
+
+    // 等到输出里出现 text，超时就失败；返回到 text 为止读到的内容
+    fn expect(&mut self, text: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !self.seen.contains(text) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match self.output.recv_timeout(left) {
+                Ok(b) => self.seen.push(b as char),
+                Err(_) => panic!("timed out waiting for {:?}, got {:?}", text, self.seen),
+            }
+        }
+        std::mem::take(&mut self.seen)
+    }
+
+    // 等 shell 退出，超时就失败
+    fn wait(&mut self) -> i32 {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status.code().unwrap();
+            }
+            assert!(Instant::now() < deadline, "shell did not exit");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for Pty {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn ctrl_c_interrupts_a_loop_of_builtins() {
+    let mut pty = Pty::spawn();
+    pty.expect("$ ");
+    pty.send("while [ 1 ]; do x=1; done; echo not reached\r");
+    // 等循环跑起来再按 Ctrl-C
+    thread::sleep(Duration::from_millis(500));
+    pty.send("\x03");
+    let out = pty.expect("$ ");
+    assert!(!out.contains("\nnot reached"), "{:?}", out);
+    pty.send("echo status=$?\r");
+    pty.expect("status=130\r\n");
+    pty.expect("$ ");
+
+    // 函数里的循环同样被打断，shell 还活着
+    pty.send("f() { until [ ]; do x=1; done; }; f; echo after=$?\r");
+    thread::sleep(Duration::from_millis(500));
+    pty.send("\x03");
+    let out = pty.expect("$ ");
+    assert!(!out.contains("\nafter="), "{:?}", out);
+    pty.send("echo alive\r");
+    pty.expect("alive\r\n");
+    pty.expect("$ ");
+    pty.send("exit\r");
+    assert_eq!(pty.wait(), 0);
+}
+
+#[test]
+fn ctrl_c_newline_goes_to_the_terminal() {
+    let path = std::env::temp_dir().join(format!("msksh-interrupt-{}", std::process::id()));
+    let mut pty = Pty::spawn();
+    pty.expect("$ ");
+    // 标准输出指向文件，^C 之后的换行不能写进去
+    pty.send(&format!("exec >{}\r", path.display()));
+    pty.expect("$ ");
+    pty.send("while [ 1 ]; do x=1; done\r");
+    thread::sleep(Duration::from_millis(500));
+    pty.send("\x03");
+    pty.expect("$ ");
+    pty.send("exit 0\r");
+    assert_eq!(pty.wait(), 0);
+    let content = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(content, "");
+}
@@ -0,0 +1,12 @@
+// 后台作业
+
+mod common;
+
+use common::{run, stdout};
+
+#[test]
+fn background_jobs_are_not_announced_without_a_terminal() {
+    let output = run("echo hi & wait; echo done");
+    assert_eq!(stdout(&output), "hi\ndone\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}
@@ -0,0 +1,76 @@
+// 命令替换和管道里的命令在子 shell 里执行，不能影响当前 shell
+
+mod common;
+
+use common::{run, stdout};
+
+#[test]
+fn exit_in_command_substitution_only_ends_the_subshell() {
+    let output = run("x=$(exit 3); echo \"after $?\"");
+    assert_eq!(stdout(&output), "after 3\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn cd_in_command_substitution_keeps_the_working_directory() {
+    let output = run("cd /tmp; y=$(cd /; pwd); pwd; echo $y");
+    assert_eq!(stdout(&output), "/tmp\n/\n");
+}
+
+#[test]
+fn assignment_in_command_substitution_is_not_visible_outside() {
+    let output = run("a=1; b=$(a=2; echo $a); echo $a $b");
+    assert_eq!(stdout(&output), "1 2\n");
+}
+
+#[test]
+fn builtin_stages_of_a_pipeline_run_concurrently() {
+    // 输出远大于管道缓冲区，前后两条都是复合命令
+    let output = run("for i in $(seq 30000); do echo $i; done | { wc -l; }");
+    assert_eq!(stdout(&output).trim(), "30000");
+}
+
+#[test]
+fn assignments_in_a_pipeline_stay_in_the_subshell() {
+    let output = run("echo hi | x=5; echo \"[$x]\"; f() { g=1; echo f; }; f | cat; echo \"[$g]\"");
+    assert_eq!(stdout(&output), "[]\nf\n[]\n");
+}
+
+#[test]
+fn command_of_only_a_substitution_keeps_its_status() {
+    let output = run("$(exit 4); echo $?; `exit 3`; echo $?; $(true) $(exit 5); echo $?");
+    assert_eq!(stdout(&output), "4\n3\n5\n");
+    let output = run("false; $x; echo $?");
+    assert_eq!(stdout(&output), "0\n");
+}
+
+#[test]
+fn unterminated_substitution_is_not_run() {
+    for script in ["echo $(echo ran", "echo `echo ran"] {
+        let output = run(script);
+        assert_eq!(stdout(&output), "");
+        assert_eq!(output.status.code(), Some(2));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("unexpected EOF while looking for matching"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn command_not_found_is_not_captured_by_substitution() {
+    let output = run("x=$(msksh-no-such-command); echo \"[$x] $?\"");
+    assert_eq!(stdout(&output), "[] 127\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("msksh-no-such-command: command not found"),
+        "{}",
+        stderr
+    );
+
+    let output = run("x=$(msksh-no-such-command 2>/dev/null); echo \"[$x]\"");
+    assert_eq!(stdout(&output), "[]\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+}